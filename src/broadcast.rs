@@ -0,0 +1,357 @@
+pub mod context;
+
+use std::{rc::Rc, cell::RefCell, collections::HashSet, io, path::PathBuf};
+
+use chrono::{DateTime, Utc};
+use mockall::automock;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tracing::warn;
+use uuid::Uuid;
+
+use crate::{storage::{check_json, load_json, save_json}, registration::{Registration, RegistrationManager}, scheduler::{JobKind, ScheduledJob, Schedules}, state_machine::{StateData, template::{Template, TemplateError}}};
+
+/// Chooses which registered chats receive a broadcast. The default filter takes them all.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BroadcastFilter {
+    pub registered_since: Option<DateTime<Utc>>,
+}
+impl BroadcastFilter {
+    fn accepts(&self, registration: &Registration) -> bool {
+        self.registered_since.is_none_or(|since| registration.created_on >= since)
+    }
+}
+
+/// Delivery counters of a broadcast, updated as its queued messages are sent.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BroadcastReport {
+    pub id: String,
+    pub created_on: DateTime<Utc>,
+    pub queued: usize,
+    pub sent: usize,
+    pub failed: usize,
+    /// Matching registrations without a chat to send to.
+    pub skipped: usize,
+}
+
+#[derive(Debug)]
+pub enum BroadcastError {
+    InvalidTemplate(TemplateError),
+    /// The messages couldn't be queued.
+    Storage(io::Error),
+}
+
+#[automock]
+pub trait Broadcasts {
+    fn add(&mut self, report: BroadcastReport) -> io::Result<()>;
+    fn remove(&mut self, broadcast_id: &str) -> io::Result<bool>;
+    fn record_delivery(&mut self, broadcast_id: &str, delivered: bool) -> io::Result<()>;
+    /// Latest broadcasts first.
+    fn recent(&self, limit: usize) -> Vec<BroadcastReport>;
+    /// Whether the backing storage can be written, for readiness checks.
+    fn check(&self) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+pub struct BroadcastsInMemory {
+    reports: Vec<BroadcastReport>,
+}
+impl BroadcastsInMemory {
+    pub fn new() -> Self {
+        Self {
+            reports: Vec::new(),
+        }
+    }
+}
+impl Broadcasts for BroadcastsInMemory {
+    fn add(&mut self, report: BroadcastReport) -> io::Result<()> {
+        self.reports.push(report);
+        Ok(())
+    }
+
+    fn remove(&mut self, broadcast_id: &str) -> io::Result<bool> {
+        Ok(remove(&mut self.reports, broadcast_id))
+    }
+
+    fn record_delivery(&mut self, broadcast_id: &str, delivered: bool) -> io::Result<()> {
+        record_delivery(&mut self.reports, broadcast_id, delivered);
+        Ok(())
+    }
+
+    fn recent(&self, limit: usize) -> Vec<BroadcastReport> {
+        recent(&self.reports, limit)
+    }
+}
+
+/// Keeps every broadcast report in a single JSON file, rewritten on each change, so the
+/// delivery counters survive restarts.
+pub struct BroadcastsJsonFile {
+    path: PathBuf,
+    reports: Vec<BroadcastReport>,
+    save_error: Option<String>,
+}
+impl BroadcastsJsonFile {
+    pub fn new(path: &str) -> io::Result<Self> {
+        let path = PathBuf::from(path);
+        let reports = load_json(&path)?;
+        Ok(Self {
+            path,
+            reports,
+            save_error: None,
+        })
+    }
+
+    fn save(&mut self) -> io::Result<()> {
+        let saved = save_json(&self.path, &self.reports);
+        self.save_error = saved.as_ref().err().map(|error| error.to_string());
+        saved
+    }
+}
+impl Broadcasts for BroadcastsJsonFile {
+    fn add(&mut self, report: BroadcastReport) -> io::Result<()> {
+        self.reports.push(report);
+        self.save()
+    }
+
+    fn remove(&mut self, broadcast_id: &str) -> io::Result<bool> {
+        let removed = remove(&mut self.reports, broadcast_id);
+        if removed {
+            self.save()?;
+        }
+        Ok(removed)
+    }
+
+    fn record_delivery(&mut self, broadcast_id: &str, delivered: bool) -> io::Result<()> {
+        if record_delivery(&mut self.reports, broadcast_id, delivered) {
+            self.save()?;
+        }
+        Ok(())
+    }
+
+    fn recent(&self, limit: usize) -> Vec<BroadcastReport> {
+        recent(&self.reports, limit)
+    }
+
+    fn check(&self) -> Result<(), String> {
+        check_json(&self.path, self.save_error.as_ref())
+    }
+}
+
+/// Counts a delivery of `broadcast_id`, returning whether the broadcast is known.
+fn record_delivery(reports: &mut [BroadcastReport], broadcast_id: &str, delivered: bool) -> bool {
+    match reports.iter_mut().find(|r| r.id == broadcast_id) {
+        Some(report) if delivered => report.sent += 1,
+        Some(report) => report.failed += 1,
+        None => return false,
+    }
+    true
+}
+
+fn remove(reports: &mut Vec<BroadcastReport>, broadcast_id: &str) -> bool {
+    let count = reports.len();
+    reports.retain(|r| r.id != broadcast_id);
+    reports.len() < count
+}
+
+fn recent(reports: &[BroadcastReport], limit: usize) -> Vec<BroadcastReport> {
+    reports.iter().rev().take(limit).cloned().collect()
+}
+
+#[automock]
+pub trait BroadcastManager {
+    /// Renders `template` for each registration accepted by `filter` (with `name` and `phone`
+    /// variables) and queues one message per chat.
+    fn broadcast(&mut self, template: &str, filter: &BroadcastFilter) -> Result<BroadcastReport, BroadcastError>;
+    fn recent_reports(&self, limit: usize) -> Vec<BroadcastReport>;
+}
+
+pub struct BroadcastManagerImpl {
+    registration_manager: Rc<RefCell<dyn RegistrationManager>>,
+    schedules: Rc<RefCell<dyn Schedules>>,
+    broadcasts: Rc<RefCell<dyn Broadcasts>>,
+}
+impl BroadcastManagerImpl {
+    pub fn new(
+            registration_manager: Rc<RefCell<dyn RegistrationManager>>,
+            schedules: Rc<RefCell<dyn Schedules>>,
+            broadcasts: Rc<RefCell<dyn Broadcasts>>,
+        ) -> Self {
+        Self {
+            registration_manager,
+            schedules,
+            broadcasts,
+        }
+    }
+}
+impl BroadcastManager for BroadcastManagerImpl {
+    fn broadcast(&mut self, template: &str, filter: &BroadcastFilter) -> Result<BroadcastReport, BroadcastError> {
+        let template = Template::parse(template).map_err(BroadcastError::InvalidTemplate)?;
+        let registrations: Vec<Registration> = self.registration_manager.borrow().get_all_registrations().into_iter()
+            .filter(|r| filter.accepts(r))
+            .collect();
+
+        let mut messages = Vec::new();
+        let mut chat_ids = HashSet::new();
+        let mut skipped = 0;
+        for registration in &registrations {
+            let chat_id = match &registration.chat_id {
+                Some(chat_id) => chat_id,
+                None => {
+                    skipped += 1;
+                    continue;
+                }
+            };
+            if chat_ids.insert(chat_id) {
+                let mut data = StateData::new();
+                data.insert(String::from("name"), Value::from(registration.name.as_str()));
+                data.insert(String::from("phone"), Value::from(registration.phone.as_str()));
+                let text = template.render(&data).map_err(BroadcastError::InvalidTemplate)?;
+                messages.push((chat_id, text));
+            }
+        }
+
+        let now = Utc::now();
+        let report = BroadcastReport {
+            id: Uuid::new_v4().to_string(),
+            created_on: now,
+            queued: messages.len(),
+            sent: 0,
+            failed: 0,
+            skipped,
+        };
+        // The report is saved first, so every queued message has one to record its delivery.
+        self.broadcasts.borrow_mut().add(report.clone()).map_err(BroadcastError::Storage)?;
+        let jobs = messages.into_iter()
+            .map(|(chat_id, text)| ScheduledJob::new(chat_id, now, JobKind::Broadcast { broadcast_id: report.id.clone(), text }))
+            .collect();
+        if let Err(error) = self.schedules.borrow_mut().add_all(jobs) {
+            // Nothing was queued, so a retry won't send the broadcast twice.
+            if let Err(error) = self.broadcasts.borrow_mut().remove(&report.id) {
+                warn!(broadcast_id = %report.id, %error, "failed to remove report of unqueued broadcast");
+            }
+            return Err(BroadcastError::Storage(error));
+        }
+        Ok(report)
+    }
+
+    fn recent_reports(&self, limit: usize) -> Vec<BroadcastReport> {
+        self.broadcasts.borrow().recent(limit)
+    }
+}
+
+#[cfg(test)]
+mod broadcast_tests {
+    use crate::{registration::MockRegistrationManager, scheduler::{MockSchedules, SchedulesInMemory}};
+
+    use super::*;
+
+    fn build_manager(registrations: Vec<Registration>, schedules: Rc<RefCell<SchedulesInMemory>>) -> BroadcastManagerImpl {
+        let mut registration_manager = MockRegistrationManager::new();
+        registration_manager.expect_get_all_registrations().return_once(move || registrations);
+        BroadcastManagerImpl::new(
+            Rc::new(RefCell::new(registration_manager)),
+            schedules,
+            Rc::new(RefCell::new(BroadcastsInMemory::new())),
+        )
+    }
+
+    #[test]
+    fn broadcast_should_queue_rendered_message_per_registered_chat() -> Result<(), BroadcastError> {
+        let schedules = Rc::new(RefCell::new(SchedulesInMemory::new()));
+        let mut manager = build_manager(vec![
+            Registration::new("Fulano", "+5541123").with_chat_id("1"),
+            Registration::new("Fulano Again", "+5541123").with_chat_id("1"),
+            Registration::new("Beltrano", "+5542223").with_chat_id("2"),
+            Registration::new("Terminal", "+5543333"),
+        ], schedules.clone());
+
+        let report = manager.broadcast("Olá {{name}}!", &BroadcastFilter::default())?;
+        let jobs = schedules.borrow_mut().take_due(Utc::now()).unwrap();
+
+        assert_eq!((2, 1), (report.queued, report.skipped));
+        assert_eq!(vec![
+            JobKind::Broadcast { broadcast_id: report.id.clone(), text: "Olá Fulano!".to_string() },
+            JobKind::Broadcast { broadcast_id: report.id.clone(), text: "Olá Beltrano!".to_string() },
+        ], jobs.into_iter().map(|j| j.kind).collect::<Vec<_>>());
+        assert_eq!(vec![report], manager.recent_reports(10));
+        Ok(())
+    }
+
+    #[test]
+    fn broadcast_should_filter_registrations() -> Result<(), BroadcastError> {
+        let schedules = Rc::new(RefCell::new(SchedulesInMemory::new()));
+        let mut old_registration = Registration::new("Fulano", "+5541123").with_chat_id("1");
+        old_registration.created_on = Utc::now() - chrono::Duration::days(10);
+        let mut manager = build_manager(vec![
+            old_registration,
+            Registration::new("Beltrano", "+5542223").with_chat_id("2"),
+        ], schedules.clone());
+
+        let filter = BroadcastFilter { registered_since: Some(Utc::now() - chrono::Duration::days(1)) };
+        let report = manager.broadcast("Olá {{name}}!", &filter)?;
+        let jobs = schedules.borrow_mut().take_due(Utc::now()).unwrap();
+
+        assert_eq!(1, report.queued);
+        assert_eq!("2", jobs[0].chat_id);
+        Ok(())
+    }
+
+    #[test]
+    fn broadcast_should_queue_nothing_when_report_is_not_saved() {
+        let mut registration_manager = MockRegistrationManager::new();
+        registration_manager.expect_get_all_registrations().return_once(|| vec![Registration::new("Fulano", "+5541123").with_chat_id("1")]);
+        let mut broadcasts = MockBroadcasts::new();
+        broadcasts.expect_add().return_once(|_| Err(io::Error::new(io::ErrorKind::StorageFull, "disk full")));
+        let mut schedules = MockSchedules::new();
+        schedules.expect_add_all().never();
+        let mut manager = BroadcastManagerImpl::new(Rc::new(RefCell::new(registration_manager)), Rc::new(RefCell::new(schedules)), Rc::new(RefCell::new(broadcasts)));
+
+        let result = manager.broadcast("Olá {{name}}!", &BroadcastFilter::default());
+
+        assert!(matches!(result, Err(BroadcastError::Storage(_))));
+    }
+
+    #[test]
+    fn broadcast_should_drop_report_when_messages_are_not_queued() {
+        let mut registration_manager = MockRegistrationManager::new();
+        registration_manager.expect_get_all_registrations().return_once(|| vec![Registration::new("Fulano", "+5541123").with_chat_id("1")]);
+        let mut schedules = MockSchedules::new();
+        schedules.expect_add_all().return_once(|_| Err(io::Error::new(io::ErrorKind::StorageFull, "disk full")));
+        let broadcasts = Rc::new(RefCell::new(BroadcastsInMemory::new()));
+        let mut manager = BroadcastManagerImpl::new(Rc::new(RefCell::new(registration_manager)), Rc::new(RefCell::new(schedules)), broadcasts.clone());
+
+        let result = manager.broadcast("Olá {{name}}!", &BroadcastFilter::default());
+
+        assert!(matches!(result, Err(BroadcastError::Storage(_))));
+        assert!(broadcasts.borrow().recent(10).is_empty());
+    }
+
+    #[test]
+    fn broadcasts_should_count_deliveries() {
+        let mut broadcasts = BroadcastsInMemory::new();
+        broadcasts.add(BroadcastReport { id: "b1".to_string(), created_on: Utc::now(), queued: 3, sent: 0, failed: 0, skipped: 0 }).unwrap();
+
+        broadcasts.record_delivery("b1", true).unwrap();
+        broadcasts.record_delivery("b1", true).unwrap();
+        broadcasts.record_delivery("b1", false).unwrap();
+
+        let report = &broadcasts.recent(1)[0];
+        assert_eq!((2, 1), (report.sent, report.failed));
+    }
+
+    #[test]
+    fn broadcasts_json_file_should_load_saved_reports() {
+        let path = std::env::temp_dir().join(format!("chat-broadcasts-{}.json", uuid::Uuid::new_v4()));
+        let path = path.to_str().unwrap();
+        let mut broadcasts = BroadcastsJsonFile::new(path).unwrap();
+        broadcasts.add(BroadcastReport { id: "b1".to_string(), created_on: Utc::now(), queued: 2, sent: 0, failed: 0, skipped: 0 }).unwrap();
+        broadcasts.record_delivery("b1", true).unwrap();
+
+        let loaded = BroadcastsJsonFile::new(path).unwrap().recent(10);
+        std::fs::remove_file(path).unwrap();
+
+        assert_eq!(broadcasts.recent(10), loaded);
+        assert_eq!(1, loaded[0].sent);
+    }
+}
@@ -0,0 +1,29 @@
+use std::{rc::Rc, cell::RefCell};
+
+use crate::{config::{Config, ConfigError}, registration::RegistrationManager, scheduler::Schedules};
+
+use super::*;
+
+pub struct BroadcastContext {
+    pub broadcasts: Rc<RefCell<dyn Broadcasts>>,
+    pub broadcast_manager: Rc<RefCell<dyn BroadcastManager>>,
+}
+impl BroadcastContext {
+    pub fn build(config: &Config, registration_manager: Rc<RefCell<dyn RegistrationManager>>, schedules: Rc<RefCell<dyn Schedules>>) -> Result<Self, ConfigError> {
+        let broadcasts = Self::build_broadcasts(config)?;
+        let broadcast_manager = Rc::new(RefCell::new(BroadcastManagerImpl::new(registration_manager, schedules, broadcasts.clone())));
+
+        Ok(Self {
+            broadcasts,
+            broadcast_manager,
+        })
+    }
+
+    fn build_broadcasts(config: &Config) -> Result<Rc<RefCell<dyn Broadcasts>>, ConfigError> {
+        Ok(match &config.storage.broadcasts_file {
+            Some(path) => Rc::new(RefCell::new(BroadcastsJsonFile::new(path)
+                .map_err(|error| ConfigError::storage(path, error))?)),
+            None => Rc::new(RefCell::new(BroadcastsInMemory::new())),
+        })
+    }
+}
@@ -1,55 +1,264 @@
 use std::cell::RefCell;
-use std::collections::HashMap;
-use std::sync::Arc;
+use std::fmt;
+use std::rc::Rc;
+use std::time::Duration;
 
-use crate::messages_gateway::StateMachineBuilder;
+use chrono::{NaiveDate, Utc};
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::broadcast::{BroadcastFilter, BroadcastManager};
+use crate::i18n::{Catalog, Catalogs};
+use crate::messages_gateway::{ChatInfo, Role, StateMachineBuilder};
+use crate::telegram::ChatType;
 use crate::state_machine::form_states::*;
 
-use super::{registration::RegistrationManager};
-use super::{state_machine::*, state_machine::transitions::*, state_machine::state_output::*};
+use super::{registration::{RegisteredUser, RegistrationManager, RegistrationManagerError}};
+use super::{state_machine::*, state_machine::transitions::*, state_machine::transition_rules::*, state_machine::state_output::*, state_machine::template::*};
 
 const INITIAL_STATE_NAME: &str = "start";
 const MENU_STATE_NAME: &str = "menu";
 const REGISTER_FIELD_PREFIX: &str = "register-";
 const REGISTER_FIELD_INITIAL_STATE: &str = "register-name";
 const REGISTER_FIELD_FINISHED_STATE: &str = "register-finished";
-const MENU_MESSAGE: &str = "1: Novo registro\n2: Lista de registros";
-const REGISTER_NAME_QUESTION: &str = "Qual o nome?";
-const REGISTER_PHONE_QUESTION: &str = "Qual o telefone?";
-const INVALID_MENU_MESSAGE: &str = "Menu inválido!";
+const REGISTER_STATES: [&str; 3] = ["register-name", "register-phone", REGISTER_FIELD_FINISHED_STATE];
+const REGISTER_TIMEOUT: Duration = Duration::from_secs(30 * 60);
+const REGISTER_REMINDER_DELAY: Duration = Duration::from_secs(10 * 60);
+const GREETING_MESSAGE: &str = "greeting";
+const RESTART_MESSAGE: &str = "restart";
+const MENU_MESSAGE: &str = "menu";
+const MENU_ADMIN_MESSAGE: &str = "menu-admin";
+const MENU_NEW_REGISTER_LABEL: &str = "menu-new-register";
+const MENU_NEW_REGISTER_KEYWORDS: &str = "menu-new-register-keywords";
+const MENU_LIST_REGISTERS_LABEL: &str = "menu-list-registers";
+const MENU_LIST_REGISTERS_KEYWORDS: &str = "menu-list-registers-keywords";
+const INVALID_MENU_MESSAGE: &str = "invalid-menu";
+const VALID_OPTIONS_MESSAGE: &str = "valid-options";
+const REGISTER_NAME_QUESTION: &str = "register-name-question";
+const REGISTER_PHONE_QUESTION: &str = "register-phone-question";
+const REGISTER_CONFIRMATION_MESSAGE: &str = "register-confirmation";
+const CONFIRM_YES_KEYWORDS: &str = "confirm-yes";
+const CONFIRM_NO_KEYWORDS: &str = "confirm-no";
+const CONFIRM_CANCEL_KEYWORDS: &str = "confirm-cancel";
+const REGISTER_TIMEOUT_MESSAGE: &str = "register-timeout";
+const REGISTER_REMINDER_MESSAGE: &str = "register-reminder";
+const START_COMMAND: &str = "start";
+const MENU_COMMAND: &str = "menu";
+const CANCEL_COMMAND: &str = "cancel";
+const HELP_COMMAND: &str = "help";
+const STATS_COMMAND: &str = "stats";
+const EXPORT_COMMAND: &str = "export";
+const DELETE_COMMAND: &str = "delete";
+const STATS_MESSAGE: &str = "admin-stats";
+const DELETE_MESSAGE: &str = "admin-delete";
+const BROADCAST_COMMAND: &str = "broadcast";
+const BROADCAST_REPORTS_COMMAND: &str = "broadcasts";
+const BROADCAST_QUEUED_MESSAGE: &str = "broadcast-queued";
+const BROADCAST_REPORTS_MESSAGE: &str = "broadcast-reports";
+const BROADCAST_REPORTS_LIMIT: usize = 5;
+
+#[derive(Deserialize)]
+struct RegistrationDraft {
+    #[serde(rename = "register-name")]
+    name: String,
+    #[serde(rename = "register-phone")]
+    phone: String,
+}
+impl RegistrationDraft {
+    fn from_data(data: &StateData) -> Result<Self, StateMachineErrors> {
+        serde_json::from_value(Value::Object(data.clone()))
+            .map_err(|e| StateMachineErrors::ActionFailed(e.to_string()))
+    }
+}
 
 pub struct ChatbotBuilder {
-    registration_manager: Arc<RefCell<dyn RegistrationManager>>,
+    registration_manager: Rc<RefCell<dyn RegistrationManager>>,
+    catalogs: Catalogs,
+    broadcast_manager: Option<Rc<RefCell<dyn BroadcastManager>>>,
 }
 impl StateMachineBuilder for ChatbotBuilder {
-    fn build(&self, state_data: HashMap<String, String>) -> StateMachine {
-        let mut state_machine = StateMachine::new(state_data);
-        self.build_initial_state(&mut state_machine);
-        self.build_menu_state(&mut state_machine);
-        self.build_register_form(&mut state_machine);
-        state_machine
+    /// Catalog templates are checked with [`ChatbotBuilder::try_build`] before the bot starts.
+    fn build(&self, state_data: StateData, chat: &ChatInfo) -> StateMachine {
+        self.try_build(state_data, chat).expect("catalog templates are validated at startup")
     }
 }
 impl ChatbotBuilder {
-    pub fn new(registration_manager: Arc<RefCell<dyn RegistrationManager>>) -> Self {
+    pub fn new(registration_manager: Rc<RefCell<dyn RegistrationManager>>) -> Self {
         Self {
             registration_manager,
+            catalogs: Catalogs::embedded(),
+            broadcast_manager: None,
+        }
+    }
+
+    pub fn with_catalogs(mut self, catalogs: Catalogs) -> Self {
+        self.catalogs = catalogs;
+        self
+    }
+
+    /// Enables the `/broadcast` and `/broadcasts` commands for admins.
+    pub fn with_broadcasts(mut self, broadcast_manager: Rc<RefCell<dyn BroadcastManager>>) -> Self {
+        self.broadcast_manager = Some(broadcast_manager);
+        self
+    }
+
+    /// Commands available from any state with their localized descriptions, as registered
+    /// in Telegram's command menu.
+    pub fn commands(&self, locale: &str) -> Vec<(String, String)> {
+        let catalog = self.catalogs.get(locale);
+        [START_COMMAND, MENU_COMMAND, CANCEL_COMMAND, HELP_COMMAND].iter()
+            .map(|command| (String::from(*command), catalog.text(&format!("command-{}", command))))
+            .collect()
+    }
+
+    pub fn locales(&self) -> Vec<&str> {
+        self.catalogs.locales()
+    }
+
+    /// Builds the chat's flows, failing when a catalog text used as a template doesn't parse.
+    pub fn try_build(&self, state_data: StateData, chat: &ChatInfo) -> Result<StateMachine, InvalidTemplate> {
+        let catalog = self.catalogs.get(&chat.locale);
+        let mut state_machine = StateMachine::new(state_data);
+        state_machine.set_wrong_transition_message(&catalog.text(VALID_OPTIONS_MESSAGE));
+        self.build_initial_state(&mut state_machine, catalog)?;
+        self.build_menu_state(&mut state_machine, catalog, is_admin_chat(chat));
+        self.build_register_form(&mut state_machine, catalog, chat)?;
+        self.build_global_commands(&mut state_machine, catalog);
+        if is_admin_chat(chat) {
+            self.build_admin_commands(&mut state_machine, catalog)?;
+            self.build_broadcast_commands(&mut state_machine, catalog)?;
         }
+        Ok(state_machine)
+    }
+
+    /// `/start` goes back to the initial state, so the next message greets as in a new conversation.
+    fn build_global_commands(&self, state_machine: &mut StateMachine, catalog: &Catalog) {
+        state_machine.add_global_transition(
+            Transition::new(INITIAL_STATE_NAME, CommandTransitionRule::new(START_COMMAND))
+                .with_action(ClearDataTransitionAction::new())
+                .with_output(FixedTransitionOutput::new(&catalog.text(RESTART_MESSAGE)))
+        );
+        state_machine.add_global_transition(Transition::new(MENU_STATE_NAME, CommandTransitionRule::new(MENU_COMMAND)));
+        state_machine.add_global_transition(
+            Transition::new(MENU_STATE_NAME, CommandTransitionRule::new(CANCEL_COMMAND)).with_action(ClearDataTransitionAction::new())
+        );
+        state_machine.set_help_rule(CommandTransitionRule::new(HELP_COMMAND));
+    }
+
+    /// Admin commands answer in place, so an admin filling a form doesn't lose it.
+    fn build_admin_commands(&self, state_machine: &mut StateMachine, catalog: &Catalog) -> Result<(), InvalidTemplate> {
+        let registration_manager_arc = self.registration_manager.clone();
+        state_machine.add_global_command(Command::new(CommandTransitionRule::new(STATS_COMMAND))
+            .with_action(FnTransitionAction::new(move |data, _action| {
+                let registrations = registration_manager_arc.borrow().get_all_registrations();
+                let last_day = registrations.iter()
+                    .filter(|r| r.created_on >= Utc::now() - chrono::Duration::days(1))
+                    .count();
+                data.insert(String::from("stats"), serde_json::json!({ "total": registrations.len(), "last-day": last_day }));
+                Ok(())
+            }))
+            .with_output(template_output(catalog, STATS_MESSAGE)?)
+        );
+
+        let registration_manager_arc = self.registration_manager.clone();
+        state_machine.add_global_command(Command::new(CommandTransitionRule::new(EXPORT_COMMAND))
+            .with_output(FnTransitionOutput::new(move |_data, _action| {
+                let mut lines = vec![String::from("id;name;phone;chat_id;created_on")];
+                for r in registration_manager_arc.borrow().get_all_registrations() {
+                    lines.push(format!("{};{};{};{};{}", r.id, r.name, r.phone, r.chat_id.unwrap_or_default(), r.created_on.to_rfc3339()));
+                }
+                Some(lines.join("\n"))
+            }))
+        );
+
+        let registration_manager_arc = self.registration_manager.clone();
+        let delete_rule = AndTransitionRule::new(
+            CommandTransitionRule::new(DELETE_COMMAND),
+            FnTransitionRule::new(has_argument),
+        );
+        state_machine.add_global_command(Command::new(delete_rule)
+            .with_action(FnTransitionAction::new(move |data, action| {
+                let id = action.split_whitespace().nth(1).unwrap_or_default();
+                let deleted = match registration_manager_arc.borrow_mut().delete(id) {
+                    Ok(()) => true,
+                    Err(RegistrationManagerError::RegistrationNotFound) => false,
+                    Err(error) => return Err(StateMachineErrors::ActionFailed(format!("{:?}", error))),
+                };
+                data.insert(String::from("deleted"), Value::Bool(deleted));
+                Ok(())
+            }))
+            .with_output(template_output(catalog, DELETE_MESSAGE)?)
+        );
+        Ok(())
+    }
+
+    fn build_broadcast_commands(&self, state_machine: &mut StateMachine, catalog: &Catalog) -> Result<(), InvalidTemplate> {
+        let broadcast_manager = match &self.broadcast_manager {
+            Some(broadcast_manager) => broadcast_manager,
+            None => return Ok(()),
+        };
+
+        let broadcast_manager_arc = broadcast_manager.clone();
+        let broadcast_rule = AndTransitionRule::new(
+            CommandTransitionRule::new(BROADCAST_COMMAND),
+            FnTransitionRule::new(has_argument),
+        );
+        state_machine.add_global_command(Command::new(broadcast_rule)
+            .with_action(FnTransitionAction::new(move |data, action| {
+                let (filter, template) = parse_broadcast(action)?;
+                let report = broadcast_manager_arc.borrow_mut().broadcast(template, &filter)
+                    .map_err(|e| StateMachineErrors::ActionFailed(format!("{:?}", e)))?;
+                data.insert(String::from("broadcast"), to_output_value(report)?);
+                Ok(())
+            }))
+            .with_output(template_output(catalog, BROADCAST_QUEUED_MESSAGE)?)
+        );
+
+        let broadcast_manager_arc = broadcast_manager.clone();
+        state_machine.add_global_command(Command::new(CommandTransitionRule::new(BROADCAST_REPORTS_COMMAND))
+            .with_action(FnTransitionAction::new(move |data, _action| {
+                let reports = broadcast_manager_arc.borrow().recent_reports(BROADCAST_REPORTS_LIMIT);
+                data.insert(String::from("broadcasts"), to_output_value(reports)?);
+                Ok(())
+            }))
+            .with_output(template_output(catalog, BROADCAST_REPORTS_MESSAGE)?)
+        );
+        Ok(())
     }
 
-    fn build_initial_state(&self, state_machine: &mut StateMachine) {
+    /// Greets by first name when the sender is known from the message context.
+    fn build_initial_state(&self, state_machine: &mut StateMachine, catalog: &Catalog) -> Result<(), InvalidTemplate> {
         let mut initial_state = State::new(INITIAL_STATE_NAME);
-        initial_state.add_transition(MENU_STATE_NAME, DefaultTransitionRule::new());
+        initial_state.add_transition_with_output(MENU_STATE_NAME, DefaultTransitionRule::new(), template_output(catalog, GREETING_MESSAGE)?);
         state_machine.add_state(initial_state);
         state_machine.set_initial_state_name(INITIAL_STATE_NAME).unwrap();
+        Ok(())
     }
 
-    fn build_menu_state(&self, state_machine: &mut StateMachine) {
+    /// Listing registrations exposes every user's data, so only admins get that entry.
+    fn build_menu_state(&self, state_machine: &mut StateMachine, catalog: &Catalog, admin: bool) {
         let mut menu_state = State::new(MENU_STATE_NAME);
-        menu_state.set_output(FixedStateOutput::new(MENU_MESSAGE));
-        menu_state.add_transition(REGISTER_FIELD_INITIAL_STATE, EqTransitionRule::new("1"));
+        menu_state.add_transition(REGISTER_FIELD_INITIAL_STATE, OrTransitionRule::new(
+            EqTransitionRule::new("1").with_label(&catalog.text(MENU_NEW_REGISTER_LABEL)),
+            IntentTransitionRule::new(&catalog.keywords(MENU_NEW_REGISTER_KEYWORDS)),
+        ));
+        if admin {
+            menu_state.set_output(FixedStateOutput::new(&catalog.text(MENU_ADMIN_MESSAGE)));
+            self.build_list_registrations(&mut menu_state, catalog);
+        } else {
+            menu_state.set_output(FixedStateOutput::new(&catalog.text(MENU_MESSAGE)));
+        }
+        menu_state.add_transition_with_output(MENU_STATE_NAME, DefaultTransitionRule::new(), FixedTransitionOutput::new(&catalog.text(INVALID_MENU_MESSAGE)));
+        state_machine.add_state(menu_state);
+    }
+
+    fn build_list_registrations(&self, menu_state: &mut State, catalog: &Catalog) {
         let registration_manager_arc = self.registration_manager.clone();
-        menu_state.add_transition_with_output(MENU_STATE_NAME, EqTransitionRule::new("2"), FnTransitionOutput::new(
+        let list_rule = OrTransitionRule::new(
+            EqTransitionRule::new("2").with_label(&catalog.text(MENU_LIST_REGISTERS_LABEL)),
+            IntentTransitionRule::new(&catalog.keywords(MENU_LIST_REGISTERS_KEYWORDS)),
+        );
+        menu_state.add_transition_with_output(MENU_STATE_NAME, list_rule, FnTransitionOutput::new(
             move |_data, _action| {
                 let registration_manager = registration_manager_arc.borrow();
                 let registers = registration_manager.get_all_registrations();                
@@ -60,74 +269,208 @@ impl ChatbotBuilder {
                 Some(names.join("\n"))
             }
         ));        
-        menu_state.add_transition_with_output(MENU_STATE_NAME, DefaultTransitionRule::new(), FixedTransitionOutput::new(INVALID_MENU_MESSAGE));
-        state_machine.add_state(menu_state);
     }
 
-    fn build_register_form(&self, state_machine: &mut StateMachine) {
+    fn build_register_form(&self, state_machine: &mut StateMachine, catalog: &Catalog, chat: &ChatInfo) -> Result<(), InvalidTemplate> {
         let mut form_states = FormStates::new("register-");
-        form_states.add_field("name", REGISTER_NAME_QUESTION, FieldType::String, FieldOption::Required);
-        form_states.add_field("phone", REGISTER_PHONE_QUESTION, FieldType::String, FieldOption::Required);        
+        form_states.add_field("name", &catalog.text(REGISTER_NAME_QUESTION), FieldType::String, FieldOption::Required);
+        form_states.add_field("phone", &catalog.text(REGISTER_PHONE_QUESTION), FieldType::String, FieldOption::Required);        
         form_states.apply_states(REGISTER_FIELD_FINISHED_STATE, state_machine);
 
         let mut register_finished = State::new(REGISTER_FIELD_FINISHED_STATE);
-        register_finished.set_output(FnStateOutput::new(|data| {
-            let mut output = String::new();
-            output.push_str("Nome: "); 
-            output.push_str(&data.get("register-name").unwrap());
-            output.push_str("\n");
-            output.push_str("Telefone: ");
-            output.push_str(&data.get("register-phone").unwrap());
-            output.push_str("\n\n");
-            output.push_str("Confirmar? (sim, não ou cancelar)");
-            Some(output)
-        }));
-        register_finished.add_transition(MENU_STATE_NAME, EqTransitionRule::new("cancelar"));
-        register_finished.add_transition(REGISTER_FIELD_INITIAL_STATE, EqTransitionRule::new("não"));
+        let confirmation = TemplateStateOutput::new(&catalog.text(REGISTER_CONFIRMATION_MESSAGE))
+            .map_err(|error| InvalidTemplate::new(catalog, REGISTER_CONFIRMATION_MESSAGE, error))?;
+        register_finished.set_output(confirmation);
+        register_finished.add_transition(MENU_STATE_NAME, SynonymsTransitionRule::new(&catalog.keywords(CONFIRM_CANCEL_KEYWORDS)));
+        register_finished.add_transition(REGISTER_FIELD_INITIAL_STATE, SynonymsTransitionRule::new(&catalog.keywords(CONFIRM_NO_KEYWORDS)));
         let registration_manager_arc = self.registration_manager.clone();
-        register_finished.add_transition(MENU_STATE_NAME, FnTransitionRule::new(
-            move |data, action| {
-                if "sim" == action {
-                    let mut registration_manager = registration_manager_arc.borrow_mut();
-                    registration_manager.add(&data.get("register-name").unwrap(), &data.get("register-phone").unwrap()).unwrap();
-                    true            
-                } else {
-                    false
-                }
+        let chat_id = chat.chat_id.clone();
+        register_finished.add_transition_with_action(MENU_STATE_NAME, SynonymsTransitionRule::new(&catalog.keywords(CONFIRM_YES_KEYWORDS)), FnTransitionAction::new(
+            move |data, _action| {
+                let draft = RegistrationDraft::from_data(data)?;
+                let context = data.get(CONTEXT_KEY);
+                let user = context
+                    .and_then(|context| context.get("user"))
+                    .and_then(|user| serde_json::from_value::<RegisteredUser>(user.clone()).ok());
+                // A confirmation received again, e.g. after its session failed to save, keeps its registration.
+                let message_key = context
+                    .and_then(|context| context.get("message_id"))
+                    .and_then(|message_id| message_id.as_i64())
+                    .map(|message_id| format!("{}:{}", chat_id, message_id));
+                let mut registration_manager = registration_manager_arc.borrow_mut();
+                registration_manager.add(&draft.name, &draft.phone, Some(chat_id.clone()), user, message_key)
+                    .map_err(|e| StateMachineErrors::ActionFailed(format!("{:?}", e)))
             }
         ));
         state_machine.add_state(register_finished);
+
+        for state_name in REGISTER_STATES {
+            let timeout = Timeout::new(REGISTER_TIMEOUT, MENU_STATE_NAME)
+                .with_action(ClearDataTransitionAction::new())
+                .with_output(FixedTransitionOutput::new(&catalog.text(REGISTER_TIMEOUT_MESSAGE)));
+            state_machine.set_state_timeout(state_name, timeout).unwrap();
+            let reminder = FixedStateOutput::new(&catalog.text(REGISTER_REMINDER_MESSAGE));
+            state_machine.add_state_reminder(state_name, REGISTER_REMINDER_DELAY, reminder).unwrap();
+        }
+        Ok(())
+    }
+}
+
+/// A catalog text that doesn't parse as the template it's used as.
+#[derive(Debug, PartialEq)]
+pub struct InvalidTemplate {
+    pub locale: String,
+    pub id: String,
+    pub error: TemplateError,
+}
+impl InvalidTemplate {
+    fn new(catalog: &Catalog, id: &str, error: TemplateError) -> Self {
+        Self {
+            locale: String::from(catalog.locale()),
+            id: String::from(id),
+            error,
+        }
+    }
+}
+impl fmt::Display for InvalidTemplate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid {} template {}: {}", self.locale, self.id, self.error)
+    }
+}
+
+fn template_output(catalog: &Catalog, id: &str) -> Result<TemplateTransitionOutput, InvalidTemplate> {
+    TemplateTransitionOutput::new(&catalog.text(id)).map_err(|error| InvalidTemplate::new(catalog, id, error))
+}
+
+/// Whether a command like `/delete r1` came with an argument.
+fn has_argument(_data: &StateData, action: &str) -> bool {
+    action.split_whitespace().nth(1).is_some()
+}
+
+/// Converts a command result into data its output template can render.
+fn to_output_value(value: impl serde::Serialize) -> Result<Value, StateMachineErrors> {
+    serde_json::to_value(value).map_err(|e| StateMachineErrors::ActionFailed(e.to_string()))
+}
+
+/// Admin commands answer with every user's data, so they are only offered to admins in a
+/// private chat, where nobody else reads the answers.
+fn is_admin_chat(chat: &ChatInfo) -> bool {
+    chat.role == Role::Admin && chat.chat_type == ChatType::Private
+}
+
+/// Splits `/broadcast [since:YYYY-MM-DD] template` into its filter and template.
+fn parse_broadcast(action: &str) -> Result<(BroadcastFilter, &str), StateMachineErrors> {
+    let arguments = action.trim().split_once(char::is_whitespace).map(|(_, a)| a.trim_start()).unwrap_or_default();
+    let (since, template) = match arguments.strip_prefix("since:") {
+        Some(rest) => rest.split_once(char::is_whitespace)
+            .map(|(since, template)| (Some(since), template.trim_start()))
+            .unwrap_or((Some(rest), "")),
+        None => (None, arguments),
+    };
+    let registered_since = match since {
+        Some(since) => {
+            let date = NaiveDate::parse_from_str(since, "%Y-%m-%d")
+                .map_err(|_| StateMachineErrors::ActionFailed(format!("invalid broadcast date {}", since)))?;
+            Some(date.and_hms_opt(0, 0, 0).unwrap().and_utc())
+        },
+        None => None,
+    };
+    if template.is_empty() {
+        return Err(StateMachineErrors::ActionFailed(String::from("empty broadcast message")));
     }
+    Ok((BroadcastFilter { registered_since }, template))
 }
 
 #[cfg(test)]
 mod chatbot_tests {
-    use crate::registration::{MockRegistrationManager, Registration};
+    use crate::{broadcast::{BroadcastReport, MockBroadcastManager}, registration::{MockRegistrationManager, Registration}, i18n::DEFAULT_LOCALE};
 
     use super::*;
+    use serde_json::json;
+
+    fn text(id: &str) -> String {
+        Catalogs::embedded().get(DEFAULT_LOCALE).text(id)
+    }
+
+    fn chat(locale: &str) -> ChatInfo {
+        ChatInfo::new("111000", locale)
+    }
+
+    fn admin_chat(locale: &str) -> ChatInfo {
+        chat(locale).with_role(Role::Admin)
+    }
+
+    #[test]
+    fn chatbot_state_machine_should_be_valid() {
+        let registration_manager = MockRegistrationManager::new();
+        let chatbot_builder = ChatbotBuilder::new(Rc::new(RefCell::new(registration_manager)));
+
+        for locale in chatbot_builder.locales() {
+            for chat in [chat(locale), admin_chat(locale)] {
+                assert_eq!(Ok(()), chatbot_builder.build(StateData::new(), &chat).validate(), "{:?}", chat);
+            }
+        }
+    }
+
+    #[test]
+    fn chatbot_should_report_invalid_catalog_templates() {
+        let registration_manager = MockRegistrationManager::new();
+        let source = include_str!("../locales/pt-BR.toml").replace("{{/if}}!", "!");
+        let catalogs = Catalogs::new(vec![Catalog::parse(DEFAULT_LOCALE, &source).unwrap()]);
+        let chatbot_builder = ChatbotBuilder::new(Rc::new(RefCell::new(registration_manager))).with_catalogs(catalogs);
+
+        let error = match chatbot_builder.try_build(StateData::new(), &chat(DEFAULT_LOCALE)) {
+            Err(error) => error,
+            Ok(_) => panic!("invalid greeting template was accepted"),
+        };
+
+        assert_eq!((DEFAULT_LOCALE, GREETING_MESSAGE), (error.locale.as_str(), error.id.as_str()));
+        assert!(matches!(error.error, TemplateError::Syntax(_)));
+    }
 
     #[test]
     fn chatbot_should_have_initial_message() -> Result<(), StateMachineErrors> {
         let registration_manager = MockRegistrationManager::new();
-        let chatbot_builder = ChatbotBuilder::new(Arc::new(RefCell::new(registration_manager)));
-        let mut chatbot = chatbot_builder.build(HashMap::new());        
+        let chatbot_builder = ChatbotBuilder::new(Rc::new(RefCell::new(registration_manager)));
+        let mut chatbot = chatbot_builder.build(StateData::new(), &chat(DEFAULT_LOCALE));        
 
         let response = chatbot.transition_state("1")?;
 
-        assert_eq!(MENU_MESSAGE, response.1.unwrap());
+        assert_eq!(text(MENU_MESSAGE), response.1.unwrap());
+        Ok(())
+    }
+
+    #[test]
+    fn chatbot_should_greet_by_first_name_and_store_sender() -> Result<(), StateMachineErrors> {
+        let mut registration_manager = MockRegistrationManager::new();
+        registration_manager.expect_add()
+            .withf(|_name, _phone, _chat_id, user, message_key| user.as_ref().map(|u| (u.id, u.first_name.as_str())) == Some((222000, "Fulano"))
+                && message_key.as_deref() == Some("111000:7"))
+            .return_once(|_,_,_,_,_| Ok(()));
+        let chatbot_builder = ChatbotBuilder::new(Rc::new(RefCell::new(registration_manager)));
+        let mut chatbot = chatbot_builder.build(StateData::new(), &chat(DEFAULT_LOCALE));
+        chatbot.set_context(json!({"message_id": 7, "user": {"id": 222000, "first_name": "Fulano", "username": null, "last_name": null}, "chat": {"id": 111000, "type": "private"}}));
+
+        let greeting = chatbot.transition_state("olá")?;
+        chatbot.transition_state("1")?;
+        chatbot.transition_state("Fulano de Tal")?;
+        chatbot.transition_state("123123")?;
+        chatbot.transition_state("sim")?;
+
+        assert_eq!("Olá, Fulano!", greeting.0.unwrap());
         Ok(())
     }
 
     #[test]
     fn chatbot_should_ask_register_name() -> Result<(), StateMachineErrors> {        
         let registration_manager = MockRegistrationManager::new();
-        let chatbot_builder = ChatbotBuilder::new(Arc::new(RefCell::new(registration_manager)));
-        let mut chatbot = chatbot_builder.build(HashMap::new());        
+        let chatbot_builder = ChatbotBuilder::new(Rc::new(RefCell::new(registration_manager)));
+        let mut chatbot = chatbot_builder.build(StateData::new(), &chat(DEFAULT_LOCALE));        
         
         chatbot.transition_state("olá")?;
         let response = chatbot.transition_state("1")?;
 
-        assert_eq!(REGISTER_NAME_QUESTION, response.1.unwrap());
+        assert_eq!(text(REGISTER_NAME_QUESTION), response.1.unwrap());
         Ok(())
     }
 
@@ -135,10 +478,10 @@ mod chatbot_tests {
     fn chatbot_should_back_to_menu_after_name_registered() -> Result<(), StateMachineErrors> {
         let mut registration_manager = MockRegistrationManager::new();
         registration_manager.expect_add()
-            .withf(|name, phone| name == "José Ricardo" && phone == "123321")
-            .return_once(|_,_| Ok(()));
-        let chatbot_builder = ChatbotBuilder::new(Arc::new(RefCell::new(registration_manager)));
-        let mut chatbot = chatbot_builder.build(HashMap::new());        
+            .withf(|name, phone, chat_id, _user, _message_key| name == "José Ricardo" && phone == "123321" && chat_id.as_deref() == Some("111000"))
+            .return_once(|_,_,_,_,_| Ok(()));
+        let chatbot_builder = ChatbotBuilder::new(Rc::new(RefCell::new(registration_manager)));
+        let mut chatbot = chatbot_builder.build(StateData::new(), &chat(DEFAULT_LOCALE));        
         
         chatbot.transition_state("olá")?;
         chatbot.transition_state("1")?;
@@ -147,7 +490,43 @@ mod chatbot_tests {
         chatbot.transition_state("sim")?;
         let response = chatbot.transition_state("José Ricardo")?;
 
-        assert_eq!(MENU_MESSAGE, response.1.unwrap());
+        assert_eq!(text(MENU_MESSAGE), response.1.unwrap());
+        Ok(())
+    }
+
+    #[test]
+    fn chatbot_should_show_registration_summary() -> Result<(), StateMachineErrors> {
+        let registration_manager = MockRegistrationManager::new();
+        let chatbot_builder = ChatbotBuilder::new(Rc::new(RefCell::new(registration_manager)));
+        let mut chatbot = chatbot_builder.build(StateData::new(), &chat(DEFAULT_LOCALE));
+
+        chatbot.transition_state("olá")?;
+        chatbot.transition_state("1")?;
+        chatbot.transition_state("Fulano")?;
+        let response = chatbot.transition_state("123123")?;
+
+        assert_eq!("Nome: Fulano\nTelefone: 123123\n\nConfirmar? (sim, não ou cancelar)", response.1.unwrap());
+        Ok(())
+    }
+
+    #[test]
+    fn chatbot_should_accept_confirmation_variations() -> Result<(), StateMachineErrors> {
+        for confirmation in ["Sim", "SIM", "s", "sim "] {
+            let mut registration_manager = MockRegistrationManager::new();
+            registration_manager.expect_add()
+                .times(1)
+                .return_once(|_,_,_,_,_| Ok(()));
+            let chatbot_builder = ChatbotBuilder::new(Rc::new(RefCell::new(registration_manager)));
+            let mut chatbot = chatbot_builder.build(StateData::new(), &chat(DEFAULT_LOCALE));
+
+            chatbot.transition_state("olá")?;
+            chatbot.transition_state("1")?;
+            chatbot.transition_state("Fulano")?;
+            chatbot.transition_state("123123")?;
+            let response = chatbot.transition_state(confirmation)?;
+
+            assert_eq!(text(MENU_MESSAGE), response.1.unwrap());
+        }
         Ok(())
     }
 
@@ -155,12 +534,12 @@ mod chatbot_tests {
     fn chatbot_should_show_register_list_after_back_to_menu() -> Result<(), StateMachineErrors> {
         let mut registration_manager = MockRegistrationManager::new();
         registration_manager.expect_add()
-            .withf(|name, _phone| name == "Fulano")
-            .return_once(|_,_| Ok(()));        
+            .withf(|name, _phone, _chat_id, _user, _message_key| name == "Fulano")
+            .return_once(|_,_,_,_,_| Ok(()));        
         registration_manager.expect_get_all_registrations()            
             .return_once(move || Vec::from([Registration::new("Fulano", "+5541123")]));
-        let chatbot_builder = ChatbotBuilder::new(Arc::new(RefCell::new(registration_manager)));
-        let mut chatbot = chatbot_builder.build(HashMap::new());        
+        let chatbot_builder = ChatbotBuilder::new(Rc::new(RefCell::new(registration_manager)));
+        let mut chatbot = chatbot_builder.build(StateData::new(), &admin_chat(DEFAULT_LOCALE));        
         
         chatbot.transition_state("olá")?;
         chatbot.transition_state("1")?;
@@ -177,9 +556,9 @@ mod chatbot_tests {
     fn chatbot_should_show_empty_register_list() -> Result<(), StateMachineErrors> {
         let mut registration_manager = MockRegistrationManager::new();
         registration_manager.expect_get_all_registrations()            
-            .return_once(move || Vec::new());
-        let chatbot_builder = ChatbotBuilder::new(Arc::new(RefCell::new(registration_manager)));
-        let mut chatbot = chatbot_builder.build(HashMap::new());        
+            .return_once(Vec::new);
+        let chatbot_builder = ChatbotBuilder::new(Rc::new(RefCell::new(registration_manager)));
+        let mut chatbot = chatbot_builder.build(StateData::new(), &admin_chat(DEFAULT_LOCALE));        
         
         chatbot.transition_state("olá")?;
         let response = chatbot.transition_state("2")?;
@@ -196,8 +575,8 @@ mod chatbot_tests {
                 Registration::new("Fulano", "+5541123"),
                 Registration::new("Beltrano", "+5542223"),
             ]));
-        let chatbot_builder = ChatbotBuilder::new(Arc::new(RefCell::new(registration_manager)));
-        let mut chatbot = chatbot_builder.build(HashMap::new());        
+        let chatbot_builder = ChatbotBuilder::new(Rc::new(RefCell::new(registration_manager)));
+        let mut chatbot = chatbot_builder.build(StateData::new(), &admin_chat(DEFAULT_LOCALE));        
         
         chatbot.transition_state("olá")?;
         let response = chatbot.transition_state("2")?;
@@ -206,17 +585,323 @@ mod chatbot_tests {
         Ok(())
     }
 
+    #[test]
+    fn chatbot_should_understand_menu_intents() -> Result<(), StateMachineErrors> {
+        for intent in ["novo", "Cadastrar", "cadastar", "quero cadastrar"] {
+            let registration_manager = MockRegistrationManager::new();
+            let chatbot_builder = ChatbotBuilder::new(Rc::new(RefCell::new(registration_manager)));
+            let mut chatbot = chatbot_builder.build(StateData::new(), &chat(DEFAULT_LOCALE));
+
+            chatbot.transition_state("olá")?;
+            let response = chatbot.transition_state(intent)?;
+
+            assert_eq!(text(REGISTER_NAME_QUESTION), response.1.unwrap());
+        }
+        let mut registration_manager = MockRegistrationManager::new();
+        registration_manager.expect_get_all_registrations()
+            .return_once(move || Vec::from([Registration::new("Fulano", "+5541123")]));
+        let chatbot_builder = ChatbotBuilder::new(Rc::new(RefCell::new(registration_manager)));
+        let mut chatbot = chatbot_builder.build(StateData::new(), &admin_chat(DEFAULT_LOCALE));
+
+        chatbot.transition_state("olá")?;
+        let response = chatbot.transition_state("listar")?;
+
+        assert_eq!("Fulano", response.0.unwrap());
+        Ok(())
+    }
+
     #[test]
     fn chatbot_should_show_menu_on_invalid_command() -> Result<(), StateMachineErrors> {
         let registration_manager = MockRegistrationManager::new();
-        let chatbot_builder = ChatbotBuilder::new(Arc::new(RefCell::new(registration_manager)));
-        let mut chatbot = chatbot_builder.build(HashMap::new());        
+        let chatbot_builder = ChatbotBuilder::new(Rc::new(RefCell::new(registration_manager)));
+        let mut chatbot = chatbot_builder.build(StateData::new(), &chat(DEFAULT_LOCALE));        
         
         chatbot.transition_state("olá")?;
         let response = chatbot.transition_state("olá")?;         
 
-        assert_eq!(INVALID_MENU_MESSAGE, response.0.unwrap());
-        assert_eq!(MENU_MESSAGE, response.1.unwrap());
+        assert_eq!(text(INVALID_MENU_MESSAGE), response.0.unwrap());
+        assert_eq!(text(MENU_MESSAGE), response.1.unwrap());
+        Ok(())
+    }
+
+    #[test]
+    fn chatbot_should_list_valid_options_on_invalid_confirmation() -> Result<(), StateMachineErrors> {
+        let registration_manager = MockRegistrationManager::new();
+        let chatbot_builder = ChatbotBuilder::new(Rc::new(RefCell::new(registration_manager)));
+        let mut chatbot = chatbot_builder.build(StateData::new(), &chat(DEFAULT_LOCALE));
+
+        chatbot.transition_state("olá")?;
+        chatbot.transition_state("1")?;
+        chatbot.transition_state("Fulano")?;
+        chatbot.transition_state("123123")?;
+        let result = chatbot.transition_state("talvez");
+
+        assert!(matches!(result, Err(StateMachineErrors::WrongTransition)));
+        assert_eq!("Opções válidas: cancelar, não, sim, /start, /menu, /cancel, /help", chatbot.wrong_transition_help().unwrap());
+        Ok(())
+    }
+
+    #[test]
+    fn chatbot_should_talk_in_chat_locale() -> Result<(), StateMachineErrors> {
+        let mut registration_manager = MockRegistrationManager::new();
+        registration_manager.expect_add()
+            .withf(|name, phone, _chat_id, _user, _message_key| name == "John" && phone == "555")
+            .return_once(|_,_,_,_,_| Ok(()));
+        let chatbot_builder = ChatbotBuilder::new(Rc::new(RefCell::new(registration_manager)));
+        let mut chatbot = chatbot_builder.build(StateData::new(), &chat("en-US"));
+
+        let menu = chatbot.transition_state("hello")?;
+        let name_question = chatbot.transition_state("new")?;
+        chatbot.transition_state("John")?;
+        let confirmation = chatbot.transition_state("555")?;
+        let response = chatbot.transition_state("Yes")?;
+
+        assert_eq!("1: New registration", menu.1.unwrap());
+        assert_eq!("What is the name?", name_question.1.unwrap());
+        assert_eq!("Name: John\nPhone: 555\n\nConfirm? (yes, no or cancel)", confirmation.1.unwrap());
+        assert_eq!("1: New registration", response.1.unwrap());
+        Ok(())
+    }
+
+    #[test]
+    fn chatbot_should_match_spanish_keywords() -> Result<(), StateMachineErrors> {
+        let registration_manager = MockRegistrationManager::new();
+        let chatbot_builder = ChatbotBuilder::new(Rc::new(RefCell::new(registration_manager)));
+        let mut chatbot = chatbot_builder.build(StateData::new(), &chat("es"));
+
+        chatbot.transition_state("hola")?;
+        chatbot.transition_state("nuevo")?;
+        chatbot.transition_state("Juan")?;
+        chatbot.transition_state("555")?;
+        let response = chatbot.transition_state("no")?;
+
+        assert_eq!("¿Cuál es el nombre?", response.1.unwrap());
+        Ok(())
+    }
+
+    #[test]
+    fn chatbot_should_cancel_register_form_from_any_field() -> Result<(), StateMachineErrors> {
+        let registration_manager = MockRegistrationManager::new();
+        let chatbot_builder = ChatbotBuilder::new(Rc::new(RefCell::new(registration_manager)));
+        let mut chatbot = chatbot_builder.build(StateData::new(), &chat(DEFAULT_LOCALE));
+
+        chatbot.transition_state("olá")?;
+        chatbot.transition_state("1")?;
+        chatbot.transition_state("Fulano")?;
+        let response = chatbot.transition_state("/cancel")?;
+
+        assert_eq!(text(MENU_MESSAGE), response.1.unwrap());
+        assert!(chatbot.get_state_data().is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn chatbot_should_restart_with_start_command() -> Result<(), StateMachineErrors> {
+        let registration_manager = MockRegistrationManager::new();
+        let chatbot_builder = ChatbotBuilder::new(Rc::new(RefCell::new(registration_manager)));
+        let mut chatbot = chatbot_builder.build(StateData::new(), &chat(DEFAULT_LOCALE));
+
+        chatbot.transition_state("olá")?;
+        chatbot.transition_state("1")?;
+        chatbot.transition_state("Fulano")?;
+        let restarted = chatbot.transition_state("/start")?;
+
+        assert_eq!((Some(text(RESTART_MESSAGE)), None), restarted);
+        assert_eq!(INITIAL_STATE_NAME, chatbot.get_current_state().unwrap());
+        assert!(chatbot.get_state_data().is_empty());
+
+        let greeting = chatbot.transition_state("oi")?;
+
+        assert_eq!("Olá!", greeting.0.unwrap());
+        assert_eq!(text(MENU_MESSAGE), greeting.1.unwrap());
+        assert_eq!(MENU_STATE_NAME, chatbot.get_current_state().unwrap());
+        Ok(())
+    }
+
+    #[test]
+    fn chatbot_should_show_context_help() -> Result<(), StateMachineErrors> {
+        let registration_manager = MockRegistrationManager::new();
+        let chatbot_builder = ChatbotBuilder::new(Rc::new(RefCell::new(registration_manager)));
+        let mut chatbot = chatbot_builder.build(StateData::new(), &chat(DEFAULT_LOCALE));
+
+        chatbot.transition_state("olá")?;
+        chatbot.transition_state("1")?;
+        let response = chatbot.transition_state("/help")?;
+
+        assert_eq!("Qual o nome?\n\nOpções válidas: /start, /menu, /cancel, /help", response.0.unwrap());
+        assert_eq!(REGISTER_FIELD_INITIAL_STATE, chatbot.get_current_state().unwrap());
+        Ok(())
+    }
+
+    #[test]
+    fn chatbot_should_describe_commands_in_locale() {
+        let registration_manager = MockRegistrationManager::new();
+        let chatbot_builder = ChatbotBuilder::new(Rc::new(RefCell::new(registration_manager)));
+
+        let commands = chatbot_builder.commands("en");
+
+        assert_eq!(("cancel".to_string(), "Cancel the registration in progress".to_string()), commands[2]);
+    }
+
+    #[test]
+    fn chatbot_should_drop_idle_register_form() -> Result<(), StateMachineErrors> {
+        let registration_manager = MockRegistrationManager::new();
+        let chatbot_builder = ChatbotBuilder::new(Rc::new(RefCell::new(registration_manager)));
+        let mut chatbot = chatbot_builder.build(StateData::new(), &chat(DEFAULT_LOCALE));
+
+        chatbot.transition_state("olá")?;
+        chatbot.transition_state("1")?;
+        chatbot.transition_state("Fulano")?;
+        let outputs = chatbot.timeout_state(REGISTER_TIMEOUT)?.unwrap();
+
+        assert_eq!(text(REGISTER_TIMEOUT_MESSAGE), outputs.0.unwrap());
+        assert_eq!(MENU_STATE_NAME, chatbot.get_current_state().unwrap());
+        assert!(chatbot.get_state_data().is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn chatbot_should_remind_register_form_in_progress() -> Result<(), StateMachineErrors> {
+        let registration_manager = MockRegistrationManager::new();
+        let chatbot_builder = ChatbotBuilder::new(Rc::new(RefCell::new(registration_manager)));
+        let mut chatbot = chatbot_builder.build(StateData::new(), &chat(DEFAULT_LOCALE));
+
+        chatbot.transition_state("olá")?;
+        let menu_reminders = chatbot.current_reminders()?;
+        chatbot.transition_state("1")?;
+        let form_reminders = chatbot.current_reminders()?;
+
+        assert!(menu_reminders.is_empty());
+        assert_eq!(vec![(REGISTER_REMINDER_DELAY, text(REGISTER_REMINDER_MESSAGE))], form_reminders);
+        Ok(())
+    }
+
+    #[test]
+    fn chatbot_should_broadcast_only_for_admins() -> Result<(), StateMachineErrors> {
+        let registration_manager = Rc::new(RefCell::new(MockRegistrationManager::new()));
+        let mut broadcast_manager = MockBroadcastManager::new();
+        broadcast_manager.expect_broadcast()
+            .withf(|template, filter| template == "Olá {{name}}" && filter.registered_since.is_some())
+            .times(1)
+            .return_once(|_, _| Ok(BroadcastReport { id: "b1".to_string(), created_on: chrono::Utc::now(), queued: 2, sent: 0, failed: 0, skipped: 1 }));
+        let chatbot_builder = ChatbotBuilder::new(registration_manager)
+            .with_broadcasts(Rc::new(RefCell::new(broadcast_manager)));
+        let mut admin_chatbot = chatbot_builder.build(StateData::new(), &admin_chat(DEFAULT_LOCALE));
+        let mut user_chatbot = chatbot_builder.build(StateData::new(), &chat(DEFAULT_LOCALE));
+
+        let response = admin_chatbot.transition_state("/broadcast since:2026-01-01 Olá {{name}}")?;
+        let user_response = user_chatbot.transition_state("/broadcast Olá {{name}}")?;
+
+        assert_eq!("Mensagem enfileirada para 2 conversas (1 registros sem conversa).", response.0.unwrap());
+        assert!(!admin_chatbot.get_state_data().contains_key("broadcast"));
+        assert_eq!(Some("Olá!".to_string()), user_response.0);
+        assert_eq!(MENU_STATE_NAME, user_chatbot.get_current_state().unwrap());
+        Ok(())
+    }
+
+    #[test]
+    fn chatbot_should_show_broadcast_reports_to_admin() -> Result<(), StateMachineErrors> {
+        let registration_manager = Rc::new(RefCell::new(MockRegistrationManager::new()));
+        let mut broadcast_manager = MockBroadcastManager::new();
+        broadcast_manager.expect_recent_reports()
+            .return_const(vec![BroadcastReport { id: "b1".to_string(), created_on: chrono::Utc::now(), queued: 3, sent: 2, failed: 1, skipped: 0 }]);
+        let chatbot_builder = ChatbotBuilder::new(registration_manager)
+            .with_broadcasts(Rc::new(RefCell::new(broadcast_manager)));
+        let mut chatbot = chatbot_builder.build(StateData::new(), &admin_chat(DEFAULT_LOCALE));
+
+        let response = chatbot.transition_state("/broadcasts")?;
+
+        assert_eq!("b1: 2 de 3 enviadas, 1 falhas\n", response.0.unwrap());
+        Ok(())
+    }
+
+    #[test]
+    fn parse_broadcast_should_read_filter_and_template() {
+        let (filter, template) = parse_broadcast("/broadcast since:2026-01-01 Olá a todos").unwrap();
+        let (no_filter, _) = parse_broadcast("/broadcast Olá").unwrap();
+
+        assert_eq!("Olá a todos", template);
+        assert_eq!("2026-01-01T00:00:00+00:00", filter.registered_since.unwrap().to_rfc3339());
+        assert_eq!(None, no_filter.registered_since);
+        assert!(parse_broadcast("/broadcast since:2026-01-01").is_err());
+    }
+
+    #[test]
+    fn chatbot_should_hide_registration_list_from_users() -> Result<(), StateMachineErrors> {
+        let registration_manager = MockRegistrationManager::new();
+        let chatbot_builder = ChatbotBuilder::new(Rc::new(RefCell::new(registration_manager)));
+        let mut chatbot = chatbot_builder.build(StateData::new(), &chat(DEFAULT_LOCALE));
+
+        let menu = chatbot.transition_state("olá")?;
+        let response = chatbot.transition_state("2")?;
+        let stats = chatbot.transition_state("/stats")?;
+
+        assert_eq!(text(MENU_MESSAGE), menu.1.unwrap());
+        assert_eq!(text(INVALID_MENU_MESSAGE), response.0.unwrap());
+        assert_eq!(text(INVALID_MENU_MESSAGE), stats.0.unwrap());
+        Ok(())
+    }
+
+    #[test]
+    fn chatbot_should_keep_form_in_progress_when_admin_runs_commands() -> Result<(), StateMachineErrors> {
+        let mut registration_manager = MockRegistrationManager::new();
+        registration_manager.expect_get_all_registrations().returning(Vec::new);
+        registration_manager.expect_delete().return_once(|_| Ok(()));
+        let chatbot_builder = ChatbotBuilder::new(Rc::new(RefCell::new(registration_manager)));
+        let mut chatbot = chatbot_builder.build(StateData::new(), &admin_chat(DEFAULT_LOCALE));
+
+        chatbot.transition_state("olá")?;
+        chatbot.transition_state("1")?;
+        chatbot.transition_state("Fulano")?;
+        let stats = chatbot.transition_state("/stats")?;
+        let deleted = chatbot.transition_state("/delete r1")?;
+
+        assert_eq!("Registros: 0\nÚltimas 24 horas: 0", stats.0.unwrap());
+        assert_eq!("Registro removido.", deleted.0.unwrap());
+        assert_eq!("register-phone", chatbot.get_current_state().unwrap());
+        assert_eq!(vec!["register-name"], chatbot.get_state_data().keys().collect::<Vec<_>>());
+        Ok(())
+    }
+
+    #[test]
+    fn chatbot_should_not_run_admin_commands_in_groups() -> Result<(), StateMachineErrors> {
+        let mut registration_manager = MockRegistrationManager::new();
+        registration_manager.expect_get_all_registrations().never();
+        let chatbot_builder = ChatbotBuilder::new(Rc::new(RefCell::new(registration_manager)));
+        let mut chatbot = chatbot_builder.build(StateData::new(), &admin_chat(DEFAULT_LOCALE).with_chat_type(ChatType::Group));
+
+        let menu = chatbot.transition_state("olá")?;
+        let export = chatbot.transition_state("/export")?;
+
+        assert_eq!(text(MENU_MESSAGE), menu.1.unwrap());
+        assert_eq!(text(INVALID_MENU_MESSAGE), export.0.unwrap());
+        Ok(())
+    }
+
+    #[test]
+    fn chatbot_should_run_admin_commands() -> Result<(), StateMachineErrors> {
+        let mut registration_manager = MockRegistrationManager::new();
+        registration_manager.expect_get_all_registrations()
+            .returning(|| vec![Registration::new("Fulano", "+5541123").with_chat_id("1")]);
+        registration_manager.expect_delete()
+            .withf(|id| id == "r1")
+            .return_once(|_| Ok(()));
+        registration_manager.expect_delete()
+            .return_once(|_| Err(RegistrationManagerError::RegistrationNotFound));
+        let chatbot_builder = ChatbotBuilder::new(Rc::new(RefCell::new(registration_manager)));
+        let mut chatbot = chatbot_builder.build(StateData::new(), &admin_chat(DEFAULT_LOCALE));
+
+        let menu = chatbot.transition_state("olá")?;
+        let stats = chatbot.transition_state("/stats")?;
+        let export = chatbot.transition_state("/export")?;
+        let deleted = chatbot.transition_state("/delete r1")?;
+        let not_found = chatbot.transition_state("/delete r2")?;
+
+        assert_eq!(text(MENU_ADMIN_MESSAGE), menu.1.unwrap());
+        assert_eq!("Registros: 1\nÚltimas 24 horas: 1", stats.0.unwrap());
+        assert!(export.0.unwrap().starts_with("id;name;phone;chat_id;created_on\n"));
+        assert_eq!("Registro removido.", deleted.0.unwrap());
+        assert_eq!("Registro não encontrado.", not_found.0.unwrap());
         Ok(())
     }
 }
@@ -0,0 +1,113 @@
+use clap::{Parser, Subcommand, ValueEnum};
+
+/// Registration chatbot for Telegram. Settings come from `CHATBOT_CONFIG` and the environment.
+#[derive(Debug, Parser)]
+#[command(name = "chatbot")]
+pub struct Cli {
+    /// Runs the Telegram bot when omitted.
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, PartialEq, Eq, Subcommand)]
+pub enum Command {
+    /// Runs the bot.
+    #[command(subcommand)]
+    Run(RunMode),
+    /// Prints the state diagram of the chatbot.
+    Graph {
+        #[arg(value_enum, default_value_t = GraphFormat::Dot)]
+        format: GraphFormat,
+    },
+    /// Checks the configuration and the flow definitions.
+    Validate,
+    #[command(subcommand)]
+    Registrations(RegistrationsCommand),
+    #[command(subcommand)]
+    Sessions(SessionsCommand),
+    #[command(subcommand)]
+    Transcripts(TranscriptsCommand),
+}
+
+#[derive(Debug, PartialEq, Eq, Subcommand)]
+pub enum RunMode {
+    /// Answers Telegram messages received by long polling.
+    Telegram,
+    /// Chats on stdin and stdout, for trying out flows.
+    Terminal,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum GraphFormat {
+    Dot,
+    Mermaid,
+}
+
+#[derive(Debug, PartialEq, Eq, Subcommand)]
+pub enum RegistrationsCommand {
+    /// Writes every registration as JSON.
+    Export {
+        /// Written to stdout when omitted.
+        file: Option<String>,
+    },
+    /// Adds the registrations of a JSON export, skipping the ones already present.
+    Import {
+        file: String,
+    },
+}
+
+#[derive(Debug, PartialEq, Eq, Subcommand)]
+pub enum SessionsCommand {
+    /// Lists the saved sessions with their current state.
+    List,
+    /// Forgets every session of a chat, so its users start over.
+    Reset {
+        /// Group chat ids are negative, e.g. `-100123`.
+        #[arg(allow_negative_numbers = true)]
+        chat: String,
+    },
+}
+
+#[derive(Debug, PartialEq, Eq, Subcommand)]
+pub enum TranscriptsCommand {
+    /// Prints the recorded transcript of a chat as JSON lines, oldest first.
+    Show {
+        /// Group chat ids are negative, e.g. `-100123`.
+        #[arg(allow_negative_numbers = true)]
+        chat: String,
+    },
+}
+
+#[cfg(test)]
+mod cli_tests {
+    use super::*;
+
+    #[test]
+    fn cli_should_parse_subcommands() {
+        let parse = |args: &[&str]| Cli::try_parse_from(args).unwrap().command;
+
+        assert_eq!(None, parse(&["chatbot"]));
+        assert_eq!(Some(Command::Run(RunMode::Terminal)), parse(&["chatbot", "run", "terminal"]));
+        assert_eq!(Some(Command::Graph { format: GraphFormat::Dot }), parse(&["chatbot", "graph"]));
+        assert_eq!(Some(Command::Graph { format: GraphFormat::Mermaid }), parse(&["chatbot", "graph", "mermaid"]));
+        assert_eq!(
+            Some(Command::Registrations(RegistrationsCommand::Export { file: None })),
+            parse(&["chatbot", "registrations", "export"])
+        );
+        assert_eq!(
+            Some(Command::Sessions(SessionsCommand::Reset { chat: "-100".to_string() })),
+            parse(&["chatbot", "sessions", "reset", "-100"])
+        );
+        assert_eq!(
+            Some(Command::Transcripts(TranscriptsCommand::Show { chat: "-100".to_string() })),
+            parse(&["chatbot", "transcripts", "show", "-100"])
+        );
+    }
+
+    #[test]
+    fn cli_should_reject_unknown_arguments() {
+        assert!(Cli::try_parse_from(["chatbot", "graph", "svg"]).is_err());
+        assert!(Cli::try_parse_from(["chatbot", "sessions", "reset"]).is_err());
+        assert!(Cli::try_parse_from(["chatbot", "run"]).is_err());
+    }
+}
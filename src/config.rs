@@ -0,0 +1,361 @@
+use std::{env, fmt, fs, net::SocketAddr, path::Path};
+
+use serde::{de::{DeserializeOwned, IntoDeserializer}, Deserialize};
+
+use crate::{i18n::DEFAULT_LOCALE, messages_gateway::session::SessionKeying};
+
+/// Read when `CHATBOT_CONFIG` isn't set, if it exists.
+const DEFAULT_CONFIG_FILE: &str = "chatbot.toml";
+const DEFAULT_API_BASE_URL: &str = "https://api.telegram.org";
+const DEFAULT_DEDUP_TTL_HOURS: u64 = 24;
+const DEFAULT_POLL_TIMEOUT_SECS: u64 = 15;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConfigError {
+    Read { path: String, reason: String },
+    Parse { path: String, reason: String },
+    InvalidEnv { name: String, value: String, reason: String },
+    Missing { key: String, env: String },
+    Invalid(Vec<String>),
+    /// A configured store couldn't be opened, e.g. a corrupt states file.
+    Storage { path: String, reason: String },
+}
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Read { path, reason } => write!(f, "can't read config file {}: {}", path, reason),
+            Self::Parse { path, reason } => write!(f, "invalid config file {}: {}", path, reason),
+            Self::InvalidEnv { name, value, reason } => write!(f, "invalid {}={:?}: {}", name, value, reason),
+            Self::Missing { key, env } => write!(f, "{} is required, set it in the config file or with {}", key, env),
+            Self::Invalid(issues) => write!(f, "invalid config: {}", issues.join("; ")),
+            Self::Storage { path, reason } => write!(f, "can't open storage {}: {}", path, reason),
+        }
+    }
+}
+impl ConfigError {
+    pub fn storage(path: &str, error: impl fmt::Display) -> Self {
+        Self::Storage { path: path.to_string(), reason: error.to_string() }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ReceiverMode {
+    #[default]
+    LongPolling,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    #[default]
+    Compact,
+    Pretty,
+    Json,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TelegramConfig {
+    pub token: Option<String>,
+    pub api_base_url: String,
+    /// Set to only answer group messages addressed to the bot.
+    pub bot_username: Option<String>,
+    pub receiver: ReceiverMode,
+    /// How long each getUpdates long poll waits for updates.
+    pub poll_timeout_secs: u64,
+    pub offset_file: Option<String>,
+}
+impl Default for TelegramConfig {
+    fn default() -> Self {
+        Self {
+            token: None,
+            api_base_url: String::from(DEFAULT_API_BASE_URL),
+            bot_username: None,
+            receiver: ReceiverMode::default(),
+            poll_timeout_secs: DEFAULT_POLL_TIMEOUT_SECS,
+            offset_file: None,
+        }
+    }
+}
+
+/// Files of the persistent backends; the in-memory ones are used when unset.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StorageConfig {
+    pub states_file: Option<String>,
+    pub registrations_file: Option<String>,
+    pub schedules_file: Option<String>,
+    pub dedup_file: Option<String>,
+    pub broadcasts_file: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SessionsConfig {
+    pub ttl_hours: Option<u64>,
+    pub keying: SessionKeying,
+    pub dedup_ttl_hours: u64,
+}
+impl Default for SessionsConfig {
+    fn default() -> Self {
+        Self {
+            ttl_hours: None,
+            keying: SessionKeying::default(),
+            dedup_ttl_hours: DEFAULT_DEDUP_TTL_HOURS,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TranscriptConfig {
+    pub sqlite_file: Option<String>,
+    pub jsonl_file: Option<String>,
+    pub redact_phones: bool,
+}
+impl Default for TranscriptConfig {
+    fn default() -> Self {
+        Self {
+            sqlite_file: None,
+            jsonl_file: None,
+            redact_phones: true,
+        }
+    }
+}
+
+/// Everything configurable, read from a TOML file and overridden by environment variables.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub telegram: TelegramConfig,
+    pub storage: StorageConfig,
+    pub sessions: SessionsConfig,
+    pub transcript: TranscriptConfig,
+    /// Telegram user ids or usernames allowed to run admin commands.
+    pub admins: Vec<String>,
+    pub default_locale: String,
+    pub http_listen_address: Option<String>,
+    pub log_format: LogFormat,
+}
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            telegram: TelegramConfig::default(),
+            storage: StorageConfig::default(),
+            sessions: SessionsConfig::default(),
+            transcript: TranscriptConfig::default(),
+            admins: Vec::new(),
+            default_locale: String::from(DEFAULT_LOCALE),
+            http_listen_address: None,
+            log_format: LogFormat::default(),
+        }
+    }
+}
+impl Config {
+    /// Reads the file named by `CHATBOT_CONFIG` (or `chatbot.toml`, if present) and applies
+    /// the environment overrides.
+    pub fn load() -> Result<Self, ConfigError> {
+        let mut config = match env::var("CHATBOT_CONFIG") {
+            Ok(path) => Self::from_file(&path)?,
+            Err(_) if Path::new(DEFAULT_CONFIG_FILE).exists() => Self::from_file(DEFAULT_CONFIG_FILE)?,
+            Err(_) => Self::default(),
+        };
+        config.apply_env(|name| env::var(name).ok())?;
+        Ok(config)
+    }
+
+    pub fn from_file(path: &str) -> Result<Self, ConfigError> {
+        let content = fs::read_to_string(path)
+            .map_err(|error| ConfigError::Read { path: path.to_string(), reason: error.to_string() })?;
+        toml::from_str(&content)
+            .map_err(|error| ConfigError::Parse { path: path.to_string(), reason: error.to_string() })
+    }
+
+    pub fn apply_env(&mut self, var: impl Fn(&str) -> Option<String>) -> Result<(), ConfigError> {
+        let text = |name: &str, field: &mut Option<String>| {
+            if let Some(value) = var(name) {
+                *field = Some(value);
+            }
+        };
+        text("TELEGRAM_BOT_TOKEN", &mut self.telegram.token);
+        text("TELEGRAM_BOT_USERNAME", &mut self.telegram.bot_username);
+        text("TELEGRAM_OFFSET_FILE", &mut self.telegram.offset_file);
+        text("CHAT_STATES_FILE", &mut self.storage.states_file);
+        text("CHAT_REGISTRATIONS_FILE", &mut self.storage.registrations_file);
+        text("CHAT_SCHEDULES_FILE", &mut self.storage.schedules_file);
+        text("CHAT_DEDUP_FILE", &mut self.storage.dedup_file);
+        text("CHAT_BROADCASTS_FILE", &mut self.storage.broadcasts_file);
+        text("TRANSCRIPT_SQLITE_FILE", &mut self.transcript.sqlite_file);
+        text("TRANSCRIPT_JSONL_FILE", &mut self.transcript.jsonl_file);
+        text("HTTP_LISTEN_ADDRESS", &mut self.http_listen_address);
+        if let Some(value) = var("TELEGRAM_API_BASE_URL") {
+            self.telegram.api_base_url = value;
+        }
+        if let Some(value) = var("DEFAULT_LOCALE") {
+            self.default_locale = value;
+        }
+        if let Some(value) = var("ADMIN_USERS") {
+            self.admins = value.split(',').map(str::trim).filter(|admin| !admin.is_empty()).map(String::from).collect();
+        }
+        if let Some(value) = var("TELEGRAM_RECEIVER") {
+            self.telegram.receiver = parse_env("TELEGRAM_RECEIVER", &value, parse_enum)?;
+        }
+        if let Some(value) = var("CHAT_SESSION_KEYING") {
+            self.sessions.keying = parse_env("CHAT_SESSION_KEYING", &value, parse_enum)?;
+        }
+        if let Some(value) = var("LOG_FORMAT") {
+            self.log_format = parse_env("LOG_FORMAT", &value, parse_enum)?;
+        }
+        if let Some(value) = var("CHAT_SESSION_TTL_HOURS") {
+            self.sessions.ttl_hours = Some(parse_env("CHAT_SESSION_TTL_HOURS", &value, |v| v.parse::<u64>().map_err(|e| e.to_string()))?);
+        }
+        if let Some(value) = var("TELEGRAM_POLL_TIMEOUT_SECS") {
+            self.telegram.poll_timeout_secs = parse_env("TELEGRAM_POLL_TIMEOUT_SECS", &value, |v| v.parse::<u64>().map_err(|e| e.to_string()))?;
+        }
+        if let Some(value) = var("CHAT_DEDUP_TTL_HOURS") {
+            self.sessions.dedup_ttl_hours = parse_env("CHAT_DEDUP_TTL_HOURS", &value, |v| v.parse::<u64>().map_err(|e| e.to_string()))?;
+        }
+        if let Some(value) = var("TRANSCRIPT_REDACT_PHONES") {
+            self.transcript.redact_phones = parse_env("TRANSCRIPT_REDACT_PHONES", &value, |v| v.parse::<bool>().map_err(|e| e.to_string()))?;
+        }
+        Ok(())
+    }
+
+    /// Reports every invalid setting at once, given the locales that have catalogs.
+    pub fn validate(&self, locales: &[&str]) -> Result<(), ConfigError> {
+        let mut issues = Vec::new();
+        if !(self.telegram.api_base_url.starts_with("http://") || self.telegram.api_base_url.starts_with("https://")) {
+            issues.push(format!("telegram.api_base_url must be an http(s) URL, got {:?}", self.telegram.api_base_url));
+        }
+        if !locales.contains(&self.default_locale.as_str()) {
+            issues.push(format!("default_locale {:?} has no catalog, expected one of {}", self.default_locale, locales.join(", ")));
+        }
+        if self.telegram.poll_timeout_secs == 0 {
+            issues.push(String::from("telegram.poll_timeout_secs must be greater than 0"));
+        }
+        if self.sessions.ttl_hours == Some(0) {
+            issues.push(String::from("sessions.ttl_hours must be greater than 0"));
+        }
+        if self.sessions.dedup_ttl_hours == 0 {
+            issues.push(String::from("sessions.dedup_ttl_hours must be greater than 0"));
+        }
+        if self.transcript.sqlite_file.is_some() && self.transcript.jsonl_file.is_some() {
+            issues.push(String::from("transcript.sqlite_file and transcript.jsonl_file can't both be set"));
+        }
+        if let Some(address) = &self.http_listen_address {
+            if address.parse::<SocketAddr>().is_err() {
+                issues.push(format!("http_listen_address must be an ip:port address, got {:?}", address));
+            }
+        }
+        if issues.is_empty() {
+            Ok(())
+        } else {
+            Err(ConfigError::Invalid(issues))
+        }
+    }
+
+    /// The bot token, only needed to talk to Telegram.
+    pub fn telegram_token(&self) -> Result<&str, ConfigError> {
+        self.telegram.token.as_deref()
+            .filter(|token| !token.is_empty())
+            .ok_or_else(|| ConfigError::Missing { key: String::from("telegram.token"), env: String::from("TELEGRAM_BOT_TOKEN") })
+    }
+}
+
+fn parse_env<T>(name: &str, value: &str, parse: impl Fn(&str) -> Result<T, String>) -> Result<T, ConfigError> {
+    parse(value).map_err(|reason| ConfigError::InvalidEnv { name: name.to_string(), value: value.to_string(), reason })
+}
+
+/// Parses an enum value the way it's written in the config file.
+fn parse_enum<T: DeserializeOwned>(value: &str) -> Result<T, String> {
+    T::deserialize(IntoDeserializer::<serde::de::value::Error>::into_deserializer(value.trim()))
+        .map_err(|error| error.to_string())
+}
+
+#[cfg(test)]
+mod config_tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    const LOCALES: [&str; 3] = ["pt-BR", "en", "es"];
+
+    #[test]
+    fn config_should_read_file_and_apply_env_overrides() -> Result<(), ConfigError> {
+        let mut config: Config = toml::from_str(r#"
+            admins = ["@alice"]
+            default_locale = "en"
+
+            [telegram]
+            token = "from-file"
+            bot_username = "cadastro_bot"
+
+            [storage]
+            states_file = "states.json"
+
+            [sessions]
+            keying = "chat"
+            ttl_hours = 48
+        "#).unwrap();
+        let env = HashMap::from([
+            ("TELEGRAM_BOT_TOKEN", "from-env"),
+            ("ADMIN_USERS", "123, @bob"),
+            ("CHAT_SESSION_KEYING", "user-in-chat"),
+        ]);
+
+        config.apply_env(|name| env.get(name).map(|value| value.to_string()))?;
+
+        assert_eq!("from-env", config.telegram_token()?);
+        assert_eq!(Some("cadastro_bot".to_string()), config.telegram.bot_username);
+        assert_eq!(DEFAULT_API_BASE_URL, config.telegram.api_base_url);
+        assert_eq!(vec!["123".to_string(), "@bob".to_string()], config.admins);
+        assert_eq!(SessionKeying::UserInChat, config.sessions.keying);
+        assert_eq!(Some(48), config.sessions.ttl_hours);
+        assert_eq!(Some("states.json".to_string()), config.storage.states_file);
+        assert!(config.transcript.redact_phones);
+        config.validate(&LOCALES)
+    }
+
+    #[test]
+    fn config_should_report_invalid_env_values() {
+        let mut config = Config::default();
+
+        let result = config.apply_env(|name| (name == "CHAT_SESSION_TTL_HOURS").then(|| "two days".to_string()));
+
+        assert!(matches!(result, Err(ConfigError::InvalidEnv { ref name, .. }) if name == "CHAT_SESSION_TTL_HOURS"));
+        assert!(matches!(Config::default().apply_env(|name| (name == "LOG_FORMAT").then(|| "xml".to_string())), Err(ConfigError::InvalidEnv { .. })));
+    }
+
+    #[test]
+    fn config_should_report_every_invalid_setting() {
+        let config = Config {
+            default_locale: "fr".to_string(),
+            sessions: SessionsConfig { dedup_ttl_hours: 0, ..SessionsConfig::default() },
+            http_listen_address: Some("localhost".to_string()),
+            ..Config::default()
+        };
+
+        let result = config.validate(&LOCALES);
+
+        match result {
+            Err(ConfigError::Invalid(issues)) => assert_eq!(3, issues.len()),
+            other => panic!("unexpected {:?}", other),
+        }
+        assert_eq!(
+            "telegram.token is required, set it in the config file or with TELEGRAM_BOT_TOKEN",
+            config.telegram_token().unwrap_err().to_string()
+        );
+    }
+
+    #[test]
+    fn config_should_reject_unknown_keys() {
+        let path = std::env::temp_dir().join(format!("chatbot-{}.toml", uuid::Uuid::new_v4()));
+        fs::write(&path, "[telegram]\ntokn = \"typo\"\n").unwrap();
+
+        let result = Config::from_file(path.to_str().unwrap());
+
+        assert!(matches!(result, Err(ConfigError::Parse { .. })));
+        fs::remove_file(path).unwrap();
+    }
+}
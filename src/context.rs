@@ -1,23 +1,49 @@
+use crate::broadcast::context::BroadcastContext;
+use crate::config::{Config, ConfigError};
+use crate::http_server::context::HttpServerContext;
+use crate::metrics::context::MetricsContext;
+use crate::scheduler::context::SchedulerContext;
 use crate::telegram::context::TelegramContext;
+use crate::transcript::context::TranscriptContext;
 
 use super::messages_gateway::context::MessagesGatewayContext;
 use super::registration::context::RegistrationContext;
 
 pub struct ApplicationContext {
+    pub metrics_context: MetricsContext,
+    pub http_server_context: HttpServerContext,
     pub registration_context: RegistrationContext,
     pub messages_gateway_context: MessagesGatewayContext,
     pub telegram_context: TelegramContext,
+    pub scheduler_context: SchedulerContext,
+    pub broadcast_context: BroadcastContext,
+    pub transcript_context: TranscriptContext,
 }
 impl ApplicationContext {
-    pub fn build() -> Self {
-        let registration_context = RegistrationContext::build();
-        let chatbot_context = MessagesGatewayContext::build();
-        let telegram_context = TelegramContext::build();
+    /// Opens every configured store, failing on the first one that can't be opened.
+    pub fn build(config: &Config) -> Result<Self, ConfigError> {
+        let metrics_context = MetricsContext::build();
+        let http_server_context = HttpServerContext::build(config);
+        let registration_context = RegistrationContext::build(config, metrics_context.metrics.clone())?;
+        let chatbot_context = MessagesGatewayContext::build(config)?;
+        let telegram_context = TelegramContext::build(config, metrics_context.metrics.clone(), http_server_context.health.clone());
+        let scheduler_context = SchedulerContext::build(config)?;
+        let broadcast_context = BroadcastContext::build(
+            config,
+            registration_context.registration_manager.clone(),
+            scheduler_context.schedules.clone(),
+        )?;
+        let transcript_context = TranscriptContext::build(config)?;
 
-        Self {
+        Ok(Self {
+            metrics_context,
+            http_server_context,
             registration_context,
             messages_gateway_context: chatbot_context,
             telegram_context,
-        }
+            scheduler_context,
+            broadcast_context,
+            transcript_context,
+        })
     }
 }
@@ -0,0 +1,225 @@
+use std::{collections::BTreeMap, sync::{atomic::{AtomicUsize, Ordering}, Mutex}};
+
+use chrono::{DateTime, Duration, Utc};
+use serde_json::{json, Value};
+
+use crate::telegram::POLL_REQUEST_MARGIN;
+
+/// Long polls in a row the poller may miss before it counts as stuck or dead.
+const MISSED_POLLS: u32 = 3;
+/// Consecutive failed sends after which the outbound queue is considered unhealthy.
+const DEFAULT_MAX_SEND_FAILURES: usize = 5;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HealthCheck {
+    pub ok: bool,
+    pub detail: Value,
+}
+impl HealthCheck {
+    fn new(ok: bool, detail: Value) -> Self {
+        Self {
+            ok,
+            detail,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HealthReport {
+    pub ok: bool,
+    pub checks: Vec<(String, HealthCheck)>,
+}
+impl HealthReport {
+    fn new(checks: Vec<(&str, HealthCheck)>) -> Self {
+        Self {
+            ok: checks.iter().all(|(_, check)| check.ok),
+            checks: checks.into_iter().map(|(name, check)| (name.to_string(), check)).collect(),
+        }
+    }
+
+    pub fn to_json(&self) -> Value {
+        let checks: serde_json::Map<String, Value> = self.checks.iter()
+            .map(|(name, check)| (name.clone(), json!({ "ok": check.ok, "detail": check.detail })))
+            .collect();
+        json!({ "status": if self.ok { "ok" } else { "fail" }, "checks": checks })
+    }
+}
+
+/// What the poller, the receiving loop and the storages last reported, shared with the HTTP
+/// server thread. Liveness only needs the threads to be running; readiness also needs storage
+/// and the outbound queue to work.
+pub struct Health {
+    started_at: DateTime<Utc>,
+    stale_after: Duration,
+    max_send_failures: usize,
+    last_poll: Mutex<Option<DateTime<Utc>>>,
+    last_tick: Mutex<Option<DateTime<Utc>>>,
+    /// Last result reported by each storage, by name.
+    storage: Mutex<BTreeMap<String, Result<(), String>>>,
+    send_failures: AtomicUsize,
+}
+impl Health {
+    /// Staleness follows `poll_timeout`, since an idle long poll only returns once it expires.
+    pub fn new(started_at: DateTime<Utc>, poll_timeout: std::time::Duration) -> Self {
+        Self {
+            started_at,
+            stale_after: Duration::from_std((poll_timeout + POLL_REQUEST_MARGIN) * MISSED_POLLS).unwrap_or(Duration::MAX),
+            max_send_failures: DEFAULT_MAX_SEND_FAILURES,
+            last_poll: Mutex::new(None),
+            last_tick: Mutex::new(None),
+            storage: Mutex::new(BTreeMap::new()),
+            send_failures: AtomicUsize::new(0),
+        }
+    }
+
+    /// The poller got updates from Telegram at `now`.
+    pub fn polled(&self, now: DateTime<Utc>) {
+        *self.last_poll.lock().unwrap() = Some(now);
+    }
+
+    /// The receiving loop, which handles messages and scheduled jobs, ran at `now`.
+    pub fn ticked(&self, now: DateTime<Utc>) {
+        *self.last_tick.lock().unwrap() = Some(now);
+    }
+
+    /// The `store` storage was checked or written, with `result`. Readiness fails while any
+    /// storage's last result is an error.
+    pub fn record_storage(&self, store: &str, result: Result<(), String>) {
+        self.storage.lock().unwrap().insert(store.to_string(), result);
+    }
+
+    pub fn message_sent(&self, delivered: bool) {
+        if delivered {
+            self.send_failures.store(0, Ordering::Relaxed);
+        } else {
+            self.send_failures.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn liveness(&self, now: DateTime<Utc>) -> HealthReport {
+        HealthReport::new(vec![
+            ("poller", self.recent_check(*self.last_poll.lock().unwrap(), now)),
+            ("receiver", self.recent_check(*self.last_tick.lock().unwrap(), now)),
+        ])
+    }
+
+    pub fn readiness(&self, now: DateTime<Utc>) -> HealthReport {
+        let last_poll = *self.last_poll.lock().unwrap();
+        let poller = match last_poll {
+            Some(_) => self.recent_check(last_poll, now),
+            None => HealthCheck::new(false, json!("no successful getUpdates yet")),
+        };
+        let storage = self.storage_check();
+        let send_failures = self.send_failures.load(Ordering::Relaxed);
+        let outbound = HealthCheck::new(send_failures < self.max_send_failures, json!({ "consecutive_failures": send_failures }));
+        HealthReport::new(vec![
+            ("poller", poller),
+            ("receiver", self.recent_check(*self.last_tick.lock().unwrap(), now)),
+            ("storage", storage),
+            ("outbound", outbound),
+        ])
+    }
+
+    fn storage_check(&self) -> HealthCheck {
+        let storage = self.storage.lock().unwrap();
+        if storage.is_empty() {
+            return HealthCheck::new(false, json!("not checked yet"));
+        }
+        let errors: serde_json::Map<String, Value> = storage.iter()
+            .filter_map(|(store, result)| result.as_ref().err().map(|error| (store.clone(), json!(error))))
+            .collect();
+        if errors.is_empty() {
+            HealthCheck::new(true, json!("available"))
+        } else {
+            HealthCheck::new(false, Value::Object(errors))
+        }
+    }
+
+    /// Ok if `last` is recent, or if nothing was reported yet but the process only just started.
+    fn recent_check(&self, last: Option<DateTime<Utc>>, now: DateTime<Utc>) -> HealthCheck {
+        let since = last.unwrap_or(self.started_at);
+        HealthCheck::new(now - since <= self.stale_after, json!({ "last_success": last.map(|last| last.to_rfc3339()) }))
+    }
+}
+
+#[cfg(test)]
+mod health_tests {
+    use super::*;
+
+    const POLL_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(15);
+
+    #[test]
+    fn health_should_fail_liveness_when_poller_stops() {
+        let now = Utc::now();
+        let health = Health::new(now - Duration::minutes(10), POLL_TIMEOUT);
+        health.ticked(now);
+
+        assert!(!health.liveness(now).ok);
+
+        health.polled(now - Duration::seconds(20));
+
+        assert!(health.liveness(now).ok);
+        assert!(!health.liveness(now + Duration::minutes(5)).ok);
+    }
+
+    #[test]
+    fn health_should_allow_for_longer_poll_timeouts() {
+        let now = Utc::now();
+        let long_poll = Health::new(now, std::time::Duration::from_secs(50));
+        let short_poll = Health::new(now, POLL_TIMEOUT);
+        for health in [&long_poll, &short_poll] {
+            health.ticked(now);
+            health.polled(now - Duration::seconds(120));
+        }
+
+        assert!(long_poll.liveness(now).ok);
+        assert!(!short_poll.liveness(now).ok);
+    }
+
+    #[test]
+    fn health_should_be_ready_with_storage_and_outbound_working() {
+        let now = Utc::now();
+        let health = Health::new(now, POLL_TIMEOUT);
+        health.ticked(now);
+
+        assert!(health.liveness(now).ok);
+        assert!(!health.readiness(now).ok);
+
+        health.polled(now);
+        health.record_storage("states", Ok(()));
+
+        assert!(health.readiness(now).ok);
+
+        for _ in 0..DEFAULT_MAX_SEND_FAILURES {
+            health.message_sent(false);
+        }
+        let report = health.readiness(now);
+
+        assert!(!report.ok);
+        assert_eq!("fail", report.to_json()["status"]);
+        assert_eq!(false, report.to_json()["checks"]["outbound"]["ok"]);
+
+        health.message_sent(true);
+
+        assert!(health.readiness(now).ok);
+    }
+
+    #[test]
+    fn health_should_not_be_ready_while_a_storage_write_is_failing() {
+        let now = Utc::now();
+        let health = Health::new(now, POLL_TIMEOUT);
+        health.polled(now);
+        health.ticked(now);
+        health.record_storage("states", Ok(()));
+        health.record_storage("dedup", Err("disk full".to_string()));
+
+        let report = health.readiness(now);
+
+        assert!(!report.ok);
+        assert_eq!("disk full", report.to_json()["checks"]["storage"]["detail"]["dedup"]);
+
+        health.record_storage("dedup", Ok(()));
+
+        assert!(health.readiness(now).ok);
+    }
+}
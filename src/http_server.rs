@@ -0,0 +1,139 @@
+pub mod context;
+
+use std::{io, sync::Arc, thread};
+
+use chrono::Utc;
+use tiny_http::{Header, Response, Server};
+use tracing::{info, warn};
+
+use crate::{health::{Health, HealthReport}, metrics::Metrics};
+
+const TEXT_CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+const JSON_CONTENT_TYPE: &str = "application/json";
+
+/// What an [`HttpServer`] answers to a request path.
+#[derive(Debug, PartialEq, Eq)]
+pub struct HttpResponse {
+    pub status: u16,
+    pub content_type: &'static str,
+    pub body: String,
+}
+impl HttpResponse {
+    fn text(status: u16, body: &str) -> Self {
+        Self {
+            status,
+            content_type: TEXT_CONTENT_TYPE,
+            body: body.to_string(),
+        }
+    }
+
+    /// 200 when every check passed, 503 otherwise, so orchestrators can act on the status alone.
+    fn health(report: HealthReport) -> Self {
+        Self {
+            status: if report.ok { 200 } else { 503 },
+            content_type: JSON_CONTENT_TYPE,
+            body: report.to_json().to_string(),
+        }
+    }
+}
+
+/// Local HTTP endpoints for operating the bot, served from their own thread.
+pub struct HttpServer {
+    listen_address: String,
+    metrics: Option<Arc<Metrics>>,
+    health: Option<Arc<Health>>,
+}
+impl HttpServer {
+    pub fn new(listen_address: &str) -> Self {
+        Self {
+            listen_address: listen_address.to_string(),
+            metrics: None,
+            health: None,
+        }
+    }
+
+    /// Serves the liveness of `health` on `/healthz` and its readiness on `/readyz`.
+    pub fn with_health(mut self, health: Arc<Health>) -> Self {
+        self.health = Some(health);
+        self
+    }
+
+    /// Serves `metrics` on `/metrics`.
+    pub fn with_metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    /// Binds the listen address and serves requests from a new thread. Failing to bind is an
+    /// error, since orchestrators can't tell a bot without endpoints from a dead one.
+    pub fn start(self) -> io::Result<()> {
+        let server = Server::http(&self.listen_address).map_err(io::Error::other)?;
+        info!(address = %self.listen_address, "http server listening");
+        thread::spawn(move || {
+            for request in server.incoming_requests() {
+                let response = self.respond(request.url());
+                let header = Header::from_bytes("Content-Type", response.content_type).unwrap();
+                let result = request.respond(Response::from_string(response.body)
+                    .with_status_code(response.status)
+                    .with_header(header));
+                if let Err(error) = result {
+                    warn!(%error, "failed to answer http request");
+                }
+            }
+        });
+        Ok(())
+    }
+
+    pub fn respond(&self, path: &str) -> HttpResponse {
+        match (path, &self.metrics, &self.health) {
+            ("/metrics", Some(metrics), _) => HttpResponse::text(200, &metrics.render()),
+            ("/healthz", _, Some(health)) => HttpResponse::health(health.liveness(Utc::now())),
+            ("/readyz", _, Some(health)) => HttpResponse::health(health.readiness(Utc::now())),
+            _ => HttpResponse::text(404, "not found\n"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod http_server_tests {
+    use super::*;
+
+    #[test]
+    fn http_server_should_serve_metrics() {
+        let metrics = Arc::new(Metrics::new());
+        metrics.updates_received.inc();
+        let server = HttpServer::new("127.0.0.1:0").with_metrics(metrics);
+
+        let response = server.respond("/metrics");
+
+        assert_eq!(200, response.status);
+        assert!(response.body.contains("chatbot_updates_received_total 1"));
+        assert_eq!(404, server.respond("/other").status);
+    }
+
+    #[test]
+    fn http_server_should_fail_to_start_when_address_is_taken() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+
+        assert!(HttpServer::new(&address).start().is_err());
+    }
+
+    #[test]
+    fn http_server_should_report_health_with_status_code() {
+        let health = Arc::new(Health::new(Utc::now(), std::time::Duration::from_secs(15)));
+        health.ticked(Utc::now());
+        let server = HttpServer::new("127.0.0.1:0").with_health(health.clone());
+
+        assert_eq!(200, server.respond("/healthz").status);
+        assert_eq!(503, server.respond("/readyz").status);
+
+        health.polled(Utc::now());
+        health.record_storage("states", Ok(()));
+        let response = server.respond("/readyz");
+
+        assert_eq!(200, response.status);
+        assert_eq!(JSON_CONTENT_TYPE, response.content_type);
+        assert!(response.body.contains("\"status\":\"ok\""));
+    }
+}
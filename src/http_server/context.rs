@@ -0,0 +1,21 @@
+use std::{sync::Arc, time::Duration};
+
+use chrono::Utc;
+
+use crate::{config::Config, health::Health};
+
+pub struct HttpServerContext {
+    pub listen_address: Option<String>,
+    pub health: Arc<Health>,
+}
+impl HttpServerContext {
+    pub fn build(config: &Config) -> Self {
+        let listen_address = config.http_listen_address.clone();
+        let health = Arc::new(Health::new(Utc::now(), Duration::from_secs(config.telegram.poll_timeout_secs)));
+
+        Self {
+            listen_address,
+            health,
+        }
+    }
+}
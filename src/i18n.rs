@@ -0,0 +1,132 @@
+use std::collections::HashMap;
+
+use serde::Deserialize;
+
+pub const DEFAULT_LOCALE: &str = "pt-BR";
+
+const EMBEDDED_CATALOGS: [(&str, &str); 3] = [
+    ("pt-BR", include_str!("../locales/pt-BR.toml")),
+    ("en", include_str!("../locales/en.toml")),
+    ("es", include_str!("../locales/es.toml")),
+];
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Entry {
+    Text(String),
+    Keywords(Vec<String>),
+}
+
+/// Messages of one locale, keyed by message id. Entries are either texts or keyword lists
+/// used to match user input.
+pub struct Catalog {
+    locale: String,
+    entries: HashMap<String, Entry>,
+}
+impl Catalog {
+    pub fn parse(locale: &str, source: &str) -> Result<Self, String> {
+        let entries = toml::from_str(source).map_err(|e| format!("invalid catalog {}: {}", locale, e))?;
+        Ok(Self {
+            locale: String::from(locale),
+            entries,
+        })
+    }
+
+    pub fn locale(&self) -> &str {
+        &self.locale
+    }
+
+    /// Text for `id`, or the id itself when the catalog doesn't have it.
+    pub fn text(&self, id: &str) -> String {
+        match self.entries.get(id) {
+            Some(Entry::Text(text)) => String::from(text),
+            _ => String::from(id),
+        }
+    }
+
+    pub fn keywords(&self, id: &str) -> Vec<&str> {
+        match self.entries.get(id) {
+            Some(Entry::Keywords(keywords)) => keywords.iter().map(String::as_str).collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    fn ids(&self) -> Vec<&String> {
+        let mut ids: Vec<&String> = self.entries.keys().collect();
+        ids.sort();
+        ids
+    }
+}
+
+pub struct Catalogs {
+    catalogs: Vec<Catalog>,
+}
+impl Catalogs {
+    pub fn new(catalogs: Vec<Catalog>) -> Self {
+        Self {
+            catalogs,
+        }
+    }
+
+    pub fn embedded() -> Self {
+        let catalogs = EMBEDDED_CATALOGS.iter()
+            .map(|(locale, source)| Catalog::parse(locale, source).unwrap())
+            .collect();
+        Self::new(catalogs)
+    }
+
+    /// Finds the best catalog for a locale such as Telegram's `language_code`: an exact match,
+    /// then the same language (`pt` or `pt-PT` use `pt-BR`), then the default locale.
+    pub fn get(&self, locale: &str) -> &Catalog {
+        let language = locale.split(['-', '_']).next().unwrap_or_default();
+        self.catalogs.iter().find(|c| c.locale.eq_ignore_ascii_case(locale))
+            .or_else(|| self.catalogs.iter().find(|c| c.locale.split('-').next().unwrap_or_default().eq_ignore_ascii_case(language)))
+            .or_else(|| self.catalogs.iter().find(|c| c.locale == DEFAULT_LOCALE))
+            .unwrap_or(&self.catalogs[0])
+    }
+
+    pub fn locales(&self) -> Vec<&str> {
+        self.catalogs.iter().map(Catalog::locale).collect()
+    }
+
+    /// Locale of the catalog chosen by [`Catalogs::get`].
+    pub fn resolve_locale(&self, locale: &str) -> String {
+        String::from(self.get(locale).locale())
+    }
+}
+
+#[cfg(test)]
+mod i18n_tests {
+    use super::*;
+
+    #[test]
+    fn catalogs_should_resolve_locales() {
+        let catalogs = Catalogs::embedded();
+
+        assert_eq!("pt-BR", catalogs.get("pt-br").locale());
+        assert_eq!("pt-BR", catalogs.get("pt").locale());
+        assert_eq!("en", catalogs.get("en-US").locale());
+        assert_eq!("es", catalogs.get("es").locale());
+        assert_eq!("pt-BR", catalogs.get("de").locale());
+    }
+
+    #[test]
+    fn catalog_should_return_texts_and_keywords() {
+        let catalog = Catalog::parse("en", "hello = \"Hello!\"\nyes = [\"yes\", \"y\"]").unwrap();
+
+        assert_eq!("Hello!", catalog.text("hello"));
+        assert_eq!("missing", catalog.text("missing"));
+        assert_eq!(vec!["yes", "y"], catalog.keywords("yes"));
+        assert!(catalog.keywords("hello").is_empty());
+    }
+
+    #[test]
+    fn embedded_catalogs_should_have_the_same_ids() {
+        let catalogs = Catalogs::embedded();
+        let default_ids = catalogs.get(DEFAULT_LOCALE).ids();
+
+        for catalog in &catalogs.catalogs {
+            assert_eq!(default_ids, catalog.ids(), "catalog {}", catalog.locale());
+        }
+    }
+}
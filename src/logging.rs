@@ -0,0 +1,18 @@
+use tracing_subscriber::EnvFilter;
+
+use crate::config::LogFormat;
+
+const DEFAULT_FILTER: &str = "info";
+
+/// Logs to stderr in the configured `format`, filtered by `RUST_LOG` (e.g. `chatbot=debug`).
+pub fn init(format: LogFormat) {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(DEFAULT_FILTER));
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(std::io::stderr);
+    match format {
+        LogFormat::Json => builder.json().init(),
+        LogFormat::Pretty => builder.pretty().init(),
+        LogFormat::Compact => builder.init(),
+    }
+}
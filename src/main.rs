@@ -1,49 +1,294 @@
 #![allow(dead_code)]
 use chatbot::ChatbotBuilder;
+use clap::Parser;
+use cli::{Cli, Command, GraphFormat, RegistrationsCommand, RunMode, SessionsCommand, TranscriptsCommand};
+use broadcast::context::BroadcastContext;
 use context::ApplicationContext;
-use messages_gateway::{MessagesGateway, StateMachineBuilder};
-use telegram::TelegramReceiver;
-use std::{io::{self, BufRead, Error}, sync::Arc, collections::HashMap};
+use messages_gateway::{ChatInfo, MessagesGateway, Role, StateMachineBuilder, context::MessagesGatewayContext};
+use metrics::context::MetricsContext;
+use registration::{Registration, RegistrationManagerError, context::RegistrationContext};
+use scheduler::context::SchedulerContext;
+use transcript::context::TranscriptContext;
+use telegram::{TelegramReceiver, TelegramSender, BotCommand};
+use state_machine::StateData;
+use i18n::Catalogs;
+use config::{Config, ConfigError, StorageConfig};
+use http_server::HttpServer;
+use std::{fs, io::{self, BufRead, Error}, rc::Rc, sync::{atomic::AtomicBool, Arc}, process};
 
 mod telegram;
 mod state_machine;
 mod chatbot;
 mod registration;
 mod context;
+mod config;
+mod cli;
 mod messages_gateway;
+mod i18n;
+mod scheduler;
+mod broadcast;
+mod transcript;
+mod logging;
+mod metrics;
+mod http_server;
+mod health;
+mod storage;
 mod test;
 
+/// Chat id of the single local session run outside Telegram.
+const TERMINAL_CHAT_ID: &str = "terminal";
+
 fn main() {
-    run_telegram_bot();
-    // run_terminal_bot().unwrap();
+    let cli = Cli::parse();
+    let config = match load_config() {
+        Ok(config) => config,
+        Err(error) => exit_with_error(&error.to_string()),
+    };
+    logging::init(config.log_format);
+    match cli.command.unwrap_or(Command::Run(RunMode::Telegram)) {
+        Command::Run(RunMode::Telegram) => run_telegram_bot(&config, &or_exit(ApplicationContext::build(&config))),
+        Command::Run(RunMode::Terminal) => {
+            if let Err(error) = run_terminal_bot(&config, &or_exit(ApplicationContext::build(&config))) {
+                exit_with_error(&error.to_string());
+            }
+        }
+        Command::Graph { format } => print_graph(&config, format),
+        Command::Validate => {
+            validate_chatbot(&new_flows_builder(&config));
+            println!("configuration and flows are valid");
+        }
+        Command::Registrations(command) => {
+            let registration_context = or_exit(RegistrationContext::build(&config, MetricsContext::build().metrics));
+            match command {
+                RegistrationsCommand::Export { file } => export_registrations(&registration_context, file.as_deref()),
+                RegistrationsCommand::Import { file } => import_registrations(&registration_context, &file),
+            }
+        }
+        Command::Sessions(command) => {
+            let messages_gateway_context = or_exit(MessagesGatewayContext::build(&config));
+            match command {
+                SessionsCommand::List => list_sessions(&messages_gateway_context),
+                SessionsCommand::Reset { chat } => reset_sessions(&messages_gateway_context, &chat),
+            }
+        }
+        Command::Transcripts(TranscriptsCommand::Show { chat }) => show_transcript(&or_exit(TranscriptContext::build(&config)), &chat),
+    }
+}
+
+fn or_exit<T>(result: Result<T, ConfigError>) -> T {
+    result.unwrap_or_else(|error| exit_with_error(&error.to_string()))
+}
+
+fn exit_with_error(error: &str) -> ! {
+    eprintln!("{}", error);
+    process::exit(1);
+}
+
+fn load_config() -> Result<Config, ConfigError> {
+    let config = Config::load()?;
+    config.validate(&Catalogs::embedded().locales())?;
+    Ok(config)
+}
+
+fn new_chatbot_builder(application_context: &ApplicationContext) -> ChatbotBuilder {
+    ChatbotBuilder::new(application_context.registration_context.registration_manager.clone())
+        .with_broadcasts(application_context.broadcast_context.broadcast_manager.clone())
+}
+
+/// The flows don't depend on stored data, so they're built on in-memory stores, leaving the
+/// configured files untouched when only graphing or validating them.
+fn new_flows_builder(config: &Config) -> ChatbotBuilder {
+    let config = Config { storage: StorageConfig::default(), ..config.clone() };
+    let registration_context = or_exit(RegistrationContext::build(&config, MetricsContext::build().metrics));
+    let scheduler_context = or_exit(SchedulerContext::build(&config));
+    let broadcast_context = or_exit(BroadcastContext::build(&config, registration_context.registration_manager.clone(), scheduler_context.schedules));
+    ChatbotBuilder::new(registration_context.registration_manager)
+        .with_broadcasts(broadcast_context.broadcast_manager)
+}
+
+fn print_graph(config: &Config, format: GraphFormat) {
+    let chatbot = new_flows_builder(config).build(StateData::new(), &ChatInfo::new(TERMINAL_CHAT_ID, &config.default_locale));
+    match format {
+        GraphFormat::Dot => print!("{}", chatbot.to_dot()),
+        GraphFormat::Mermaid => print!("{}", chatbot.to_mermaid()),
+    }
+}
+
+fn export_registrations(registration_context: &RegistrationContext, file: Option<&str>) {
+    let registrations = registration_context.registration_manager.borrow().get_all_registrations();
+    let json = serde_json::to_string_pretty(&registrations).unwrap();
+    match file {
+        Some(file) => {
+            if let Err(error) = fs::write(file, json) {
+                exit_with_error(&format!("can't write {}: {}", file, error));
+            }
+            println!("exported {} registrations to {}", registrations.len(), file);
+        }
+        None => println!("{}", json),
+    }
+}
+
+fn import_registrations(registration_context: &RegistrationContext, file: &str) {
+    let registrations: Vec<Registration> = match fs::read_to_string(file).map_err(|e| e.to_string())
+        .and_then(|content| serde_json::from_str(&content).map_err(|e| e.to_string())) {
+        Ok(registrations) => registrations,
+        Err(error) => exit_with_error(&format!("can't import {}: {}", file, error)),
+    };
+    let total = registrations.len();
+    let mut registration_manager = registration_context.registration_manager.borrow_mut();
+    let mut imported = 0;
+    for registration in registrations {
+        match registration_manager.import(registration) {
+            Ok(()) => imported += 1,
+            Err(RegistrationManagerError::DuplicatedRegistration) => (),
+            Err(error) => exit_with_error(&format!("can't import {}: {:?}", file, error)),
+        }
+    }
+    println!("imported {} registrations, skipped {} already present", imported, total - imported);
+}
+
+fn list_sessions(messages_gateway_context: &MessagesGatewayContext) {
+    for (session_key, chat_state) in messages_gateway_context.states.borrow().all() {
+        let last_activity = chat_state.last_activity.map(|date| date.to_rfc3339()).unwrap_or_default();
+        let locale = chat_state.locale.unwrap_or_default();
+        println!("{}\t{}\t{}\t{}", session_key, chat_state.current_state, locale, last_activity);
+    }
+}
+
+fn reset_sessions(messages_gateway_context: &MessagesGatewayContext, chat_id: &str) {
+    let removed = messages_gateway_context.states.borrow_mut().remove_chat(chat_id)
+        .unwrap_or_else(|error| exit_with_error(&format!("can't reset sessions in chat {}: {}", chat_id, error)));
+    if removed == 0 {
+        exit_with_error(&format!("no sessions in chat {}", chat_id));
+    }
+    println!("reset {} sessions in chat {}", removed, chat_id);
+}
+
+fn show_transcript(transcript_context: &TranscriptContext, chat_id: &str) {
+    let transcripts = match &transcript_context.transcripts {
+        Some(transcripts) => transcripts,
+        None => exit_with_error("no transcript file is configured"),
+    };
+    let entries = transcripts.borrow().chat_transcript(chat_id)
+        .unwrap_or_else(|error| exit_with_error(&format!("can't read transcript of chat {}: {:?}", chat_id, error)));
+    for entry in entries {
+        println!("{}", serde_json::to_string(&entry).unwrap());
+    }
+}
+
+/// Checks the state machine of every locale, as seen by users and by admins.
+fn validate_chatbot(chatbot_builder: &ChatbotBuilder) {
+    let mut valid = true;
+    for locale in chatbot_builder.locales() {
+        for role in [Role::User, Role::Admin] {
+            let chat = ChatInfo::new("", locale).with_role(role);
+            let issues: Vec<String> = match chatbot_builder.try_build(StateData::new(), &chat) {
+                Ok(chatbot) => chatbot.validate().err().unwrap_or_default().iter().map(ToString::to_string).collect(),
+                Err(error) => vec![error.to_string()],
+            };
+            for issue in &issues {
+                tracing::error!(%issue, locale, ?role, "invalid chatbot");
+            }
+            valid &= issues.is_empty();
+        }
+    }
+    if !valid {
+        process::exit(1);
+    }
+}
+
+/// Best effort: the bot works without Telegram's command menu, so failures are only logged.
+fn register_bot_commands(config: &Config, chatbot_builder: &ChatbotBuilder, telegram_sender: &dyn TelegramSender) {
+    let to_bot_commands = |locale: &str| chatbot_builder.commands(locale).into_iter()
+        .map(|(command, description)| BotCommand { command, description })
+        .collect();
+    if let Err(error) = telegram_sender.set_my_commands(to_bot_commands(&config.default_locale), None) {
+        tracing::warn!(?error, "failed to set bot commands");
+    }
+    for locale in chatbot_builder.locales() {
+        let language_code = locale.split('-').next().unwrap_or_default().to_string();
+        if let Err(error) = telegram_sender.set_my_commands(to_bot_commands(locale), Some(language_code)) {
+            tracing::warn!(locale, ?error, "failed to set bot commands");
+        }
+    }
 }
 
-fn run_telegram_bot() {
-    let application_context = ApplicationContext::build();
+fn run_telegram_bot(config: &Config, application_context: &ApplicationContext) {
+    if let Err(error) = config.telegram_token() {
+        exit_with_error(&error.to_string());
+    }
+    let chatbot_builder = new_chatbot_builder(application_context);
+    validate_chatbot(&chatbot_builder);
+    register_bot_commands(config, &chatbot_builder, application_context.telegram_context.telegram_sender.as_ref());
 
-    let message_gateway = Arc::new(MessagesGateway::new(
+    let mut message_gateway = MessagesGateway::new(
         application_context.messages_gateway_context.states.clone(),
         application_context.telegram_context.telegram_sender.clone(),
-        Box::new(ChatbotBuilder::new(
-            application_context.registration_context.registration_manager.clone())
-        ),
-    ));
+        Box::new(chatbot_builder),
+    )
+    .with_schedules(application_context.scheduler_context.schedules.clone())
+    .with_broadcasts(application_context.broadcast_context.broadcasts.clone())
+    .with_registrations(application_context.registration_context.registration_manager.clone())
+    .with_admins(application_context.messages_gateway_context.admins.clone())
+    .with_session_keying(application_context.messages_gateway_context.session_keying)
+    .with_default_locale(&application_context.messages_gateway_context.default_locale)
+    .with_processed_messages(application_context.messages_gateway_context.processed_messages.clone());
+    if let Some(bot_username) = &application_context.messages_gateway_context.bot_username {
+        message_gateway = message_gateway.with_bot_username(bot_username);
+    }
+    if let Some(transcripts) = &application_context.transcript_context.transcripts {
+        message_gateway = message_gateway.with_transcripts(transcripts.clone());
+    }
+    message_gateway = message_gateway
+        .with_metrics(application_context.metrics_context.metrics.clone())
+        .with_health(application_context.http_server_context.health.clone());
+    if let Some(session_ttl) = application_context.messages_gateway_context.session_ttl {
+        message_gateway = message_gateway.with_session_ttl(session_ttl);
+    }
+    let message_gateway = Rc::new(message_gateway);
+
+    if let Some(listen_address) = &application_context.http_server_context.listen_address {
+        let started = HttpServer::new(listen_address)
+            .with_metrics(application_context.metrics_context.metrics.clone())
+            .with_health(application_context.http_server_context.health.clone())
+            .start();
+        if let Err(error) = started {
+            exit_with_error(&format!("can't listen on {}: {}", listen_address, error));
+        }
+    }
 
-    let mut receiver = application_context.telegram_context.new_telegram_receiver();
+    let mut receiver = match application_context.telegram_context.new_telegram_receiver(shutdown_signal()) {
+        Ok(receiver) => receiver,
+        Err(error) => exit_with_error(&error.to_string()),
+    };
     receiver.add_message_arrived_listener(message_gateway.clone());
     receiver.start_receive();
 }
 
-fn run_terminal_bot() -> Result<(), Error> {
-    let application_context = ApplicationContext::build();
-    let chatbot_builder = ChatbotBuilder::new(
-        application_context.registration_context.registration_manager.clone()
-    );
-    let mut chatbot = chatbot_builder.build(HashMap::new());
+/// Set by SIGINT or SIGTERM; a second signal exits right away.
+fn shutdown_signal() -> Arc<AtomicBool> {
+    let shutdown = Arc::new(AtomicBool::new(false));
+    for signal in [signal_hook::consts::SIGINT, signal_hook::consts::SIGTERM] {
+        signal_hook::flag::register_conditional_shutdown(signal, 1, shutdown.clone()).unwrap();
+        signal_hook::flag::register(signal, shutdown.clone()).unwrap();
+    }
+    shutdown
+}
+
+fn run_terminal_bot(config: &Config, application_context: &ApplicationContext) -> Result<(), Error> {
+    let chatbot_builder = new_chatbot_builder(application_context);
+    validate_chatbot(&chatbot_builder);
+    let mut chatbot = chatbot_builder.build(StateData::new(), &ChatInfo::new(TERMINAL_CHAT_ID, &config.default_locale));
     let stdin = io::stdin();    
     for line_result in stdin.lock().lines() {
         let line = line_result?;
-        let (transition_output, state_output) = chatbot.transition_state(&line).unwrap();
+        let (transition_output, state_output) = match chatbot.transition_state(&line) {
+            Ok(outputs) => outputs,
+            Err(error) => {
+                eprintln!("{:?}", error);
+                continue;
+            }
+        };
         if let Some(s) = transition_output {
             println!("{}", &s);
         }
@@ -1,42 +1,162 @@
 mod chat_state;
+pub mod admins;
 pub mod context;
+pub mod session;
+pub mod dedup;
 
-use std::{sync::Arc, cell::RefCell, collections::HashMap};
+use std::{io, rc::Rc, sync::Arc, cell::{Cell, RefCell}, time::Duration};
+use chrono::{DateTime, Utc};
 use mockall::automock;
-use crate::{telegram::{TelegramMessageArrived, TelegramListener, TelegramSender, SendTelegramMessage}, state_machine::StateMachine};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tracing::{error, field, info, info_span, warn};
+use crate::{health::Health, metrics::Metrics, transcript::{Transcripts, TranscriptEntry, TranscriptEvent}, broadcast::Broadcasts, registration::RegistrationManager, i18n::DEFAULT_LOCALE, scheduler::{Schedules, ScheduledJob, JobKind}, telegram::{ChatType, TelegramMessageArrived, TelegramListener, TelegramSender, SendTelegramMessage, TelegramError}, state_machine::{StateMachine, StateData, StateMachineErrors}};
 
-use self::chat_state::{States, ChatState};
+use self::{dedup::ProcessedMessages, admins::AdminAllowList, chat_state::{States, ChatState}, session::{AddressFilter, SessionKeying, session_chat_id, session_user_id}};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    User,
+    Admin,
+}
+
+/// What a [`StateMachineBuilder`] knows about the chat it builds the state machine for.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChatInfo {
+    pub chat_id: String,
+    pub locale: String,
+    pub role: Role,
+    pub chat_type: ChatType,
+}
+impl ChatInfo {
+    pub fn new(chat_id: &str, locale: &str) -> Self {
+        Self {
+            chat_id: chat_id.to_string(),
+            locale: locale.to_string(),
+            role: Role::User,
+            chat_type: ChatType::Private,
+        }
+    }
+
+    pub fn with_role(mut self, role: Role) -> Self {
+        self.role = role;
+        self
+    }
+
+    pub fn with_chat_type(mut self, chat_type: ChatType) -> Self {
+        self.chat_type = chat_type;
+        self
+    }
+}
 
 #[automock]
 pub trait StateMachineBuilder {
-    fn build(&self, state_data: HashMap<String, String>) -> StateMachine;
+    fn build(&self, state_data: StateData, chat: &ChatInfo) -> StateMachine;
 }
 
 enum Message {
     Telegram(TelegramMessageArrived),
 }
 impl Message {
-    fn text(&self) -> String {
+    /// The text meant for the bot, or `None` when the filter finds the bot wasn't addressed.
+    fn text(&self, address_filter: Option<&AddressFilter>) -> Option<String> {
+        match (self, address_filter) {
+            (Self::Telegram(message), Some(address_filter)) => address_filter.addressed_text(message),
+            (Self::Telegram(message), None) => Some(message.text.to_string()),
+        }
+    }
+
+    fn session_key(&self, session_keying: SessionKeying) -> String {
         match self {
-            Self::Telegram(message) => message.text.to_string(),
+            Self::Telegram(message) => session_keying.session_key(message),
         }
     }
 
     fn chat_id(&self) -> String {
         match self {
-            Self::Telegram(message) => message.chat_id.to_string(),
+            Self::Telegram(message) => message.chat.id.to_string(),
+        }
+    }
+
+    fn chat_type(&self) -> ChatType {
+        match self {
+            Self::Telegram(message) => message.chat.chat_type,
+        }
+    }
+
+    fn language_code(&self) -> Option<String> {
+        match self {
+            Self::Telegram(message) => message.from.as_ref().and_then(|user| user.language_code.clone()),
+        }
+    }
+
+    fn user_id(&self) -> Option<i64> {
+        match self {
+            Self::Telegram(message) => message.from.as_ref().map(|user| user.id),
+        }
+    }
+
+    /// Identifies the message among redeliveries; Telegram message ids are unique per chat.
+    fn dedup_key(&self) -> String {
+        match self {
+            Self::Telegram(message) => format!("{}:{}", message.chat.id, message.message_id),
+        }
+    }
+
+    fn username(&self) -> Option<&str> {
+        match self {
+            Self::Telegram(message) => message.from.as_ref().and_then(|user| user.username.as_deref()),
+        }
+    }
+
+    /// Read-only context for the state machine, with the sender as `user` and the chat as `chat`.
+    fn context(&self) -> Value {
+        match self {
+            Self::Telegram(message) => json!({
+                "message_id": message.message_id,
+                "user": message.from,
+                "chat": message.chat,
+                "date": message.date.to_rfc3339(),
+            }),
         }
     }
 }
 
+/// Action recorded in transcripts for transitions made by state timeouts.
+const TIMEOUT_ACTION: &str = "timeout";
+
+/// How often inactive sessions are purged, checked as messages arrive.
+const PURGE_INTERVAL_MINUTES: i64 = 60;
+
+/// Storage names reported to the readiness check.
+const STATES_STORAGE: &str = "states";
+const SCHEDULES_STORAGE: &str = "schedules";
+const DEDUP_STORAGE: &str = "dedup";
+const BROADCASTS_STORAGE: &str = "broadcasts";
+const REGISTRATIONS_STORAGE: &str = "registrations";
+
 pub struct MessagesGateway {
-    states: Arc<RefCell<dyn States>>,
+    states: Rc<RefCell<dyn States>>,
     telegram_sender: Arc<dyn TelegramSender>,
     state_machine_builder: Box<dyn StateMachineBuilder>,
+    session_ttl: Option<Duration>,
+    last_purge: Cell<Option<DateTime<Utc>>>,
+    schedules: Option<Rc<RefCell<dyn Schedules>>>,
+    broadcasts: Option<Rc<RefCell<dyn Broadcasts>>>,
+    registration_manager: Option<Rc<RefCell<dyn RegistrationManager>>>,
+    admins: AdminAllowList,
+    session_keying: SessionKeying,
+    address_filter: Option<AddressFilter>,
+    transcripts: Option<Rc<RefCell<dyn Transcripts>>>,
+    metrics: Option<Arc<Metrics>>,
+    health: Option<Arc<Health>>,
+    processed_messages: Option<Rc<RefCell<dyn ProcessedMessages>>>,
+    default_locale: String,
 }
 impl MessagesGateway {
     pub fn new(
-            states: Arc<RefCell<dyn States>>,
+            states: Rc<RefCell<dyn States>>,
             telegram_sender: Arc<dyn TelegramSender>,
             state_machine_builder: Box<dyn StateMachineBuilder>,
         ) -> Self {
@@ -44,61 +164,476 @@ impl MessagesGateway {
             states,
             telegram_sender,
             state_machine_builder,
+            session_ttl: None,
+            last_purge: Cell::new(None),
+            schedules: None,
+            broadcasts: None,
+            registration_manager: None,
+            admins: AdminAllowList::new(),
+            session_keying: SessionKeying::default(),
+            address_filter: None,
+            transcripts: None,
+            metrics: None,
+            health: None,
+            processed_messages: None,
+            default_locale: String::from(DEFAULT_LOCALE),
         }
     }
 
-    fn message_arrived(&self, message: Message) {
-        let chat_id = message.chat_id();
-        let state = self.states.borrow_mut().get(&chat_id);        
+    /// Locale of chats whose messages don't carry a language code.
+    pub fn with_default_locale(mut self, default_locale: &str) -> Self {
+        self.default_locale = String::from(default_locale);
+        self
+    }
+
+    /// Ignores messages already recorded in `processed_messages`, e.g. redelivered updates.
+    pub fn with_processed_messages(mut self, processed_messages: Rc<RefCell<dyn ProcessedMessages>>) -> Self {
+        self.processed_messages = Some(processed_messages);
+        self
+    }
+
+    /// Reports the receiving loop, storage checks and sending results to `health` on each tick.
+    pub fn with_health(mut self, health: Arc<Health>) -> Self {
+        self.health = Some(health);
+        self
+    }
+
+    /// Checks the storages and reports the receiving loop alive.
+    pub fn check_health(&self, now: DateTime<Utc>) {
+        let health = match &self.health {
+            Some(health) => health,
+            None => return,
+        };
+        health.record_storage(STATES_STORAGE, self.states.borrow().check());
+        if let Some(schedules) = &self.schedules {
+            health.record_storage(SCHEDULES_STORAGE, schedules.borrow().check());
+        }
+        if let Some(broadcasts) = &self.broadcasts {
+            health.record_storage(BROADCASTS_STORAGE, broadcasts.borrow().check());
+        }
+        if let Some(registration_manager) = &self.registration_manager {
+            health.record_storage(REGISTRATIONS_STORAGE, registration_manager.borrow().check());
+        }
+        health.ticked(now);
+    }
+
+    /// Reports the result of writing to `store`, so a failing storage fails readiness.
+    fn record_storage<T>(&self, store: &str, result: &io::Result<T>) {
+        if let Some(health) = &self.health {
+            health.record_storage(store, result.as_ref().map(|_| ()).map_err(|error| error.to_string()));
+        }
+    }
+
+    pub fn with_metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    /// Records inbound messages, transitions and outbound messages of each session.
+    pub fn with_transcripts(mut self, transcripts: Rc<RefCell<dyn Transcripts>>) -> Self {
+        self.transcripts = Some(transcripts);
+        self
+    }
+
+    /// How messages are grouped into conversations, e.g. one per user in group chats.
+    pub fn with_session_keying(mut self, session_keying: SessionKeying) -> Self {
+        self.session_keying = session_keying;
+        self
+    }
+
+    /// Ignores group messages that don't mention or reply to `bot_username` and aren't commands.
+    pub fn with_bot_username(mut self, bot_username: &str) -> Self {
+        self.address_filter = Some(AddressFilter::new(bot_username));
+        self
+    }
+
+    /// Enables the reminders and timeouts of states, scheduling them as chats enter each state.
+    pub fn with_schedules(mut self, schedules: Rc<RefCell<dyn Schedules>>) -> Self {
+        self.schedules = Some(schedules);
+        self
+    }
+
+    /// Users building their chats with [`Role::Admin`].
+    pub fn with_admins(mut self, admins: AdminAllowList) -> Self {
+        self.admins = admins;
+        self
+    }
+
+    /// Records the delivery of scheduled broadcast messages in `broadcasts`.
+    pub fn with_broadcasts(mut self, broadcasts: Rc<RefCell<dyn Broadcasts>>) -> Self {
+        self.broadcasts = Some(broadcasts);
+        self
+    }
+
+    /// Includes the registrations storage in the health checks.
+    pub fn with_registrations(mut self, registration_manager: Rc<RefCell<dyn RegistrationManager>>) -> Self {
+        self.registration_manager = Some(registration_manager);
+        self
+    }
+
+    /// Schedules a message to the chat regardless of its state, e.g. a follow-up.
+    pub fn schedule_message(&self, chat_id: &str, due_at: DateTime<Utc>, text: &str) {
+        if let Some(schedules) = &self.schedules {
+            let added = schedules.borrow_mut().add(ScheduledJob::new(chat_id, due_at, JobKind::Message { text: text.to_string() }));
+            self.record_storage(SCHEDULES_STORAGE, &added);
+            if let Err(error) = added {
+                error!(%chat_id, %error, "failed to schedule message");
+            }
+        }
+    }
+
+    /// Runs the scheduled jobs due at `now`, returning how many were taken.
+    pub fn run_due_jobs(&self, now: DateTime<Utc>) -> usize {
+        let jobs = match &self.schedules {
+            Some(schedules) => schedules.borrow_mut().take_due(now),
+            None => return 0,
+        };
+        self.record_storage(SCHEDULES_STORAGE, &jobs);
+        let jobs = match jobs {
+            Ok(jobs) => jobs,
+            Err(error) => {
+                error!(%error, "failed to take due jobs");
+                return 0;
+            },
+        };
+        let count = jobs.len();
+        for job in jobs {
+            self.run_job(job, now);
+        }
+        count
+    }
+
+    /// Forgets chats inactive for longer than `session_ttl`, so they start over.
+    pub fn with_session_ttl(mut self, session_ttl: Duration) -> Self {
+        self.session_ttl = Some(session_ttl);
+        self
+    }
+
+    /// Removes sessions inactive for longer than the session TTL, if one is set.
+    pub fn purge_inactive_sessions(&self, now: DateTime<Utc>) -> usize {
+        let session_ttl = match self.session_ttl.and_then(|ttl| chrono::Duration::from_std(ttl).ok()) {
+            Some(ttl) => ttl,
+            None => return 0,
+        };
+        self.last_purge.set(Some(now));
+        let purged = self.states.borrow_mut().purge_inactive(now - session_ttl);
+        self.record_storage(STATES_STORAGE, &purged);
+        let purged = purged.unwrap_or_else(|error| {
+            error!(%error, "failed to purge inactive sessions");
+            0
+        });
+        self.update_active_sessions();
+        purged
+    }
+
+    /// Returns whether the message was handled, counting messages already processed before.
+    fn message_arrived(&self, message: Message) -> bool {
+        if let Some(metrics) = &self.metrics {
+            metrics.updates_received.inc();
+        }
+        let processed_messages = match &self.processed_messages {
+            Some(processed_messages) => processed_messages,
+            None => return self.handle_message(&message),
+        };
+        let key = message.dedup_key();
+        if processed_messages.borrow().is_processed(&key, Utc::now()) {
+            info!(%key, "ignoring message already processed");
+            return true;
+        }
+        if !self.handle_message(&message) {
+            return false;
+        }
+        let marked = processed_messages.borrow_mut().mark_processed(&key, Utc::now());
+        self.record_storage(DEDUP_STORAGE, &marked);
+        if let Err(error) = marked {
+            error!(%key, %error, "failed to record processed message");
+        }
+        true
+    }
+
+    /// Runs the message through the session's state machine, returning whether it was fully
+    /// handled: the new state saved and every answer sent.
+    fn handle_message(&self, message: &Message) -> bool {
+        let text = match message.text(self.address_filter.as_ref()) {
+            Some(text) => text,
+            None => return true,
+        };
+        let session_key = message.session_key(self.session_keying);
+        let span = info_span!("message", session = %session_key, state_before = field::Empty, state_after = field::Empty);
+        let _entered = span.enter();
+        self.record(&session_key, TranscriptEvent::Inbound { text: text.clone() });
+        let now = Utc::now();
+        if self.last_purge.get().is_none_or(|last_purge| now - last_purge >= chrono::Duration::minutes(PURGE_INTERVAL_MINUTES)) {
+            self.purge_inactive_sessions(now);
+        }
+
+        let state = self.states.borrow_mut().get(&session_key);        
+        let locale = state.as_ref().and_then(|s| s.locale.clone())
+            .or_else(|| message.language_code())
+            .unwrap_or_else(|| self.default_locale.clone());
+        let chat = ChatInfo::new(&message.chat_id(), &locale)
+            .with_role(self.admins.role_of(message.user_id(), message.username()))
+            .with_chat_type(message.chat_type());
+        let mut delivered = true;
         
         let mut state_machine = if let Some(s) = state {            
-            let mut state_machine = self.state_machine_builder.build(s.data.clone());
-            state_machine.set_current_state(&s.current_state).unwrap();
+            let mut state_machine = self.restore_state_machine(&s, &chat);
+            state_machine.set_context(message.context());
+            if let Some(last_activity) = s.last_activity {
+                let idle = (now - last_activity).to_std().unwrap_or_default();
+                let from = state_machine.get_current_state().unwrap();
+                if let Ok(Some((transition_output, state_output))) = state_machine.timeout_state(idle) {
+                    self.record_transition(&session_key, from, &state_machine, TIMEOUT_ACTION, [transition_output.clone(), state_output]);
+                    if let Some(text) = transition_output {
+                        delivered &= self.answer_message(message, &text);
+                    }
+                }
+            }
             state_machine
         } else {
-            let state_machine = self.state_machine_builder.build(HashMap::new());            
+            let mut state_machine = self.state_machine_builder.build(StateData::new(), &chat);
+            state_machine.set_context(message.context());
             state_machine
         };
 
-        if let Ok(output) = state_machine.transition_state(&message.text()) {
-            let (transition_output, state_output) = output;
-            if let Some(text) = transition_output {
-                self.answer_message(&message, &text);
-            }
-            if let Some(text) = state_output {
-                self.answer_message(&message, &text);
+        let from = state_machine.get_current_state().unwrap();
+        span.record("state_before", from.as_str());
+        match state_machine.transition_state(&text) {
+            Ok((transition_output, state_output)) => {
+                span.record("state_after", state_machine.get_current_state().unwrap().as_str());
+                info!("transition");
+                if let Some(metrics) = &self.metrics {
+                    metrics.transition(&from, &state_machine.get_current_state().unwrap());
+                }
+                self.record_transition(&session_key, from, &state_machine, &text, [transition_output.clone(), state_output.clone()]);
+                if let Some(text) = transition_output {
+                    delivered &= self.answer_message(message, &text);
+                }
+                if let Some(text) = state_output {
+                    delivered &= self.answer_message(message, &text);
+                }
+            },
+            Err(StateMachineErrors::WrongTransition) => {
+                if let Some(metrics) = &self.metrics {
+                    metrics.state_machine_error(&StateMachineErrors::WrongTransition);
+                }
+                if let Some(text) = state_machine.wrong_transition_help() {
+                    delivered &= self.answer_message(message, &text);
+                }
+            },
+            Err(error) => {
+                if let Some(metrics) = &self.metrics {
+                    metrics.state_machine_error(&error);
+                }
+                error!(session = %session_key, ?error, "failed to handle message");
+                return false;
+            },
+        }
+
+        let saved = self.states.borrow_mut().change_state(&session_key, ChatState {
+            current_state: state_machine.get_current_state().unwrap(),
+            data: state_machine.get_state_data().clone(),
+            locale: Some(locale),
+            last_activity: Some(now),
+            role: Some(chat.role),
+            chat_type: Some(chat.chat_type),
+        });
+        self.record_storage(STATES_STORAGE, &saved);
+        if let Err(error) = &saved {
+            error!(session = %session_key, %error, "failed to save session state");
+        }
+        self.update_active_sessions();
+        self.schedule_state_jobs(&session_key, &state_machine, now);
+        saved.is_ok() && delivered
+    }
+
+    /// Restores the saved session, or starts it over when the flow no longer has its state,
+    /// e.g. after the state was renamed or removed.
+    fn restore_state_machine(&self, chat_state: &ChatState, chat: &ChatInfo) -> StateMachine {
+        let mut state_machine = self.state_machine_builder.build(chat_state.data.clone(), chat);
+        if let Err(error) = state_machine.set_current_state(&chat_state.current_state) {
+            warn!(state = %chat_state.current_state, ?error, "saved state no longer exists, resetting session");
+            return self.state_machine_builder.build(StateData::new(), chat);
+        }
+        state_machine
+    }
+
+    /// Replaces the session's state-bound jobs, reporting storage failures instead of failing the message.
+    fn schedule_state_jobs(&self, chat_id: &str, state_machine: &StateMachine, now: DateTime<Utc>) {
+        let schedules = match &self.schedules {
+            Some(schedules) => schedules,
+            None => return,
+        };
+        let scheduled = replace_state_jobs(&mut *schedules.borrow_mut(), chat_id, state_machine, now);
+        self.record_storage(SCHEDULES_STORAGE, &scheduled);
+        if let Err(error) = scheduled {
+            error!(session = %chat_id, %error, "failed to schedule state jobs");
+        }
+    }
+
+    fn run_job(&self, job: ScheduledJob, now: DateTime<Utc>) {
+        let chat_state = self.states.borrow().get(&job.chat_id);
+        if let Some(state) = &job.state {
+            if chat_state.as_ref().is_none_or(|chat_state| &chat_state.current_state != state) {
+                return;
             }
+        }
 
-            self.states.borrow_mut().change_state(&chat_id, ChatState {                
-                current_state: state_machine.get_current_state().unwrap(),
-                data: state_machine.get_state_data().clone(),
-            })
+        match job.kind {
+            JobKind::Message { text } => {
+                if let Err(error) = self.send_to_chat(&job.chat_id, &text) {
+                    warn!(chat_id = %job.chat_id, ?error, "failed to send scheduled message");
+                }
+            },
+            JobKind::Broadcast { broadcast_id, text } => {
+                let delivered = self.send_to_chat(&job.chat_id, &text);
+                if let Err(error) = &delivered {
+                    warn!(broadcast_id = %broadcast_id, chat_id = %job.chat_id, ?error, "failed to send broadcast");
+                }
+                if let Some(broadcasts) = &self.broadcasts {
+                    let recorded = broadcasts.borrow_mut().record_delivery(&broadcast_id, delivered.is_ok());
+                    self.record_storage(BROADCASTS_STORAGE, &recorded);
+                    if let Err(error) = recorded {
+                        error!(broadcast_id = %broadcast_id, %error, "failed to record broadcast delivery");
+                    }
+                }
+            },
+            JobKind::Timeout => {
+                let chat_state = match chat_state {
+                    Some(chat_state) => chat_state,
+                    None => return,
+                };
+                let locale = chat_state.locale.clone().unwrap_or_else(|| self.default_locale.clone());
+                let chat = ChatInfo::new(session_chat_id(&job.chat_id), &locale)
+                    .with_role(chat_state.role.unwrap_or(Role::User))
+                    .with_chat_type(chat_state.chat_type.unwrap_or(ChatType::Private));
+                let mut state_machine = self.restore_state_machine(&chat_state, &chat);
+                let idle = chat_state.last_activity
+                    .map(|last_activity| (now - last_activity).to_std().unwrap_or_default())
+                    .unwrap_or(Duration::MAX);
+                let from = state_machine.get_current_state().unwrap();
+                if let Ok(Some((transition_output, state_output))) = state_machine.timeout_state(idle) {
+                    self.record_transition(&job.chat_id, from, &state_machine, TIMEOUT_ACTION, [transition_output.clone(), state_output.clone()]);
+                    for text in [transition_output, state_output].into_iter().flatten() {
+                        if let Err(error) = self.send_to_chat(&job.chat_id, &text) {
+                            warn!(chat_id = %job.chat_id, ?error, "failed to send timeout message");
+                        }
+                    }
+                    let saved = self.states.borrow_mut().change_state(&job.chat_id, ChatState {
+                        current_state: state_machine.get_current_state().unwrap(),
+                        data: state_machine.get_state_data().clone(),
+                        ..chat_state
+                    });
+                    self.record_storage(STATES_STORAGE, &saved);
+                    if let Err(error) = saved {
+                        error!(session = %job.chat_id, %error, "failed to save session state");
+                    }
+                    self.schedule_state_jobs(&job.chat_id, &state_machine, now);
+                }
+            },
+        }
+    }
+
+    fn update_active_sessions(&self) {
+        if let Some(metrics) = &self.metrics {
+            metrics.active_sessions.set(self.states.borrow().count() as i64);
+        }
+    }
+
+    fn sent(&self, result: Result<(), TelegramError>) -> Result<(), TelegramError> {
+        if let Some(metrics) = &self.metrics {
+            metrics.message_sent(result.is_ok());
+        }
+        if let Some(health) = &self.health {
+            health.message_sent(result.is_ok());
+        }
+        result
+    }
+
+    fn record(&self, session_key: &str, event: TranscriptEvent) {
+        if let Some(transcripts) = &self.transcripts {
+            let mut entry = TranscriptEntry::new(session_chat_id(session_key), event);
+            if let Some(user_id) = session_user_id(session_key) {
+                entry = entry.with_user_id(user_id);
+            }
+            if let Err(error) = transcripts.borrow_mut().record(entry) {
+                warn!(session = %session_key, ?error, "failed to record transcript");
+            }
         }
     }
 
-    fn answer_message(&self, arrived_message: &Message, text: &str) {
+    fn record_transition(&self, session_key: &str, from: String, state_machine: &StateMachine, action: &str, outputs: [Option<String>; 2]) {
+        self.record(session_key, TranscriptEvent::Transition {
+            from,
+            to: state_machine.get_current_state().unwrap(),
+            action: action.to_string(),
+            outputs: outputs.into_iter().flatten().collect(),
+        });
+    }
+
+    /// Sends to the chat of `session_key`, which is a plain chat id for broadcasts.
+    fn send_to_chat(&self, session_key: &str, text: &str) -> Result<(), TelegramError> {
+        self.record(session_key, TranscriptEvent::Outbound { text: text.to_string() });
+        let chat_id = session_chat_id(session_key);
+        let chat_id = chat_id.parse::<i64>()
+            .map_err(|_| TelegramError::Request(format!("invalid chat id {}", chat_id)))?;
+        self.sent(self.telegram_sender.send_message(SendTelegramMessage {
+            chat_id,
+            text: text.to_string(),
+        }))
+    }
+
+    /// Replies in the chat of `arrived_message`, returning whether the reply was sent.
+    fn answer_message(&self, arrived_message: &Message, text: &str) -> bool {
         match arrived_message {
             Message::Telegram(telegram_arrived_message) => {
+                self.record(&arrived_message.session_key(self.session_keying), TranscriptEvent::Outbound { text: text.to_string() });
                 let new_message = SendTelegramMessage {
-                    chat_id: telegram_arrived_message.chat_id,
+                    chat_id: telegram_arrived_message.chat.id,
                     text: text.to_string(),
                 };
-                self.telegram_sender.send_message(new_message);
+                let sent = self.sent(self.telegram_sender.send_message(new_message));
+                if let Err(error) = &sent {
+                    warn!(chat_id = telegram_arrived_message.chat.id, ?error, "failed to answer chat");
+                }
+                sent.is_ok()
             }
         }
     }
 }
+/// Replaces the session's state-bound jobs with the reminders and timeout of its current state.
+fn replace_state_jobs(schedules: &mut dyn Schedules, chat_id: &str, state_machine: &StateMachine, now: DateTime<Utc>) -> io::Result<()> {
+    let state_name = state_machine.get_current_state().unwrap();
+    schedules.cancel_state_jobs(chat_id)?;
+    for (after, text) in state_machine.current_reminders().unwrap_or_default() {
+        let job = ScheduledJob::new(chat_id, now + chrono::Duration::from_std(after).unwrap(), JobKind::Message { text });
+        schedules.add(job.for_state(&state_name))?;
+    }
+    if let Some(after) = state_machine.current_timeout() {
+        let job = ScheduledJob::new(chat_id, now + chrono::Duration::from_std(after).unwrap(), JobKind::Timeout);
+        schedules.add(job.for_state(&state_name))?;
+    }
+    Ok(())
+}
+
 impl TelegramListener for MessagesGateway {
-    fn message_arrived(&self, message: TelegramMessageArrived) {
-        MessagesGateway::message_arrived(self, Message::Telegram(message));
+    fn message_arrived(&self, message: TelegramMessageArrived) -> bool {
+        MessagesGateway::message_arrived(self, Message::Telegram(message))
+    }
+
+    fn tick(&self) {
+        let now = Utc::now();
+        self.run_due_jobs(now);
+        self.check_health(now);
     }
 }
 
 #[cfg(test)]
 mod messages_gateway_tests {
-    use std::{cell::RefCell, sync::Arc, collections::HashMap};
-    use crate::{telegram::MockTelegramSender, state_machine::{State, transitions::{EqTransitionRule, DefaultTransitionRule, FixedTransitionOutput}, state_output::FixedStateOutput}};
-    use super::{*, chat_state::MockStates};
+    use std::{cell::RefCell, sync::Arc};
+    use crate::{telegram::MockTelegramSender, state_machine::{State, Timeout, template::TemplateTransitionOutput, transitions::{EqTransitionRule, DefaultTransitionRule, FixedTransitionOutput}, state_output::FixedStateOutput}};
+    use crate::{health::Health, metrics::Metrics, transcript::{MockTranscripts, TranscriptError, TranscriptsInMemory}, chatbot::ChatbotBuilder, registration::MockRegistrationManager, broadcast::MockBroadcasts, scheduler::MockSchedules, telegram::{TelegramError, TelegramUser, TelegramChat, ChatType}};
+    use super::{*, chat_state::{MockStates, StatesInMemory}, dedup::ProcessedMessagesInMemory};
 
     struct TestScope {
         mock_states: MockStates,
@@ -116,14 +651,42 @@ mod messages_gateway_tests {
 
         fn build_object(self) -> MessagesGateway {
             MessagesGateway::new(
-                Arc::new(RefCell::new(self.mock_states)), 
+                Rc::new(RefCell::new(self.mock_states)), 
                 Arc::new(self.mock_telegram_sender),
                 Box::new(self.state_machine_builder),
             )
         }
     }
 
-    fn build_state_machine(state_data: HashMap<String, String>) -> StateMachine {
+    fn telegram_message(text: &str) -> TelegramMessageArrived {
+        TelegramMessageArrived {
+            message_id: 111000,
+            date: Utc::now(),
+            from: Some(TelegramUser {
+                id: 222000,
+                is_bot: false,
+                first_name: "User".to_string(),
+                last_name: None,
+                username: Some("userName".to_string()),
+                language_code: None,
+            }),
+            chat: TelegramChat { id: 111000, chat_type: ChatType::Private, title: None },
+            text: text.to_string(),
+            reply_to: None,
+        }
+    }
+
+    fn group_message(user_id: i64, first_name: &str, text: &str) -> TelegramMessageArrived {
+        let mut message = telegram_message(text);
+        let user = message.from.as_mut().unwrap();
+        user.id = user_id;
+        user.first_name = first_name.to_string();
+        user.username = None;
+        message.chat = TelegramChat { id: -100123, chat_type: ChatType::Group, title: Some("Turma".to_string()) };
+        message
+    }
+
+    fn build_state_machine(state_data: StateData, _chat: &ChatInfo) -> StateMachine {
         let mut state_machine: StateMachine = StateMachine::new(state_data);
         let name_1 = "state-1";
         let name_2 = "state-2";
@@ -148,14 +711,9 @@ mod messages_gateway_tests {
         scope.mock_states.expect_get()            
             .withf(|chat_id| chat_id == "111000")
             .return_once(move |_| None);        
-        let telegram_message = TelegramMessageArrived {
-            from: Some("userName".to_string()),
-            message_id: 111000,
-            chat_id: 111000,
-            text: "1".to_string(),
-        };
-        scope.mock_telegram_sender.expect_send_message().return_const(());
-        scope.mock_states.expect_change_state().return_const(());
+        let telegram_message = telegram_message("1");
+        scope.mock_telegram_sender.expect_send_message().return_const(Ok(()));
+        scope.mock_states.expect_change_state().returning(|_, _| Ok(()));
         let message_gateway = scope.build_object();
 
         <dyn TelegramListener>::message_arrived(&message_gateway, telegram_message);
@@ -166,16 +724,11 @@ mod messages_gateway_tests {
         let mut scope = TestScope::new();
         scope.state_machine_builder.expect_build().return_once(build_state_machine);
         scope.mock_states.expect_get().return_once(move |_| None);
-        let telegram_message = TelegramMessageArrived {
-            from: Some("userName".to_string()),
-            message_id: 111000,
-            chat_id: 111000,
-            text: "1".to_string(),
-        };
-        scope.mock_telegram_sender.expect_send_message().return_const(());
+        let telegram_message = telegram_message("1");
+        scope.mock_telegram_sender.expect_send_message().return_const(Ok(()));
         scope.mock_states.expect_change_state()
             .withf(|chat_id, state| chat_id == "111000" && state.current_state == "state-2")
-            .return_const(());
+            .returning(|_, _| Ok(()));
         let message_gateway = scope.build_object();
 
         <dyn TelegramListener>::message_arrived(&message_gateway, telegram_message);
@@ -186,16 +739,11 @@ mod messages_gateway_tests {
         let mut scope = TestScope::new();
         scope.state_machine_builder.expect_build().return_once(build_state_machine);
         scope.mock_states.expect_get().return_once(move |_| None);        
-        let telegram_message = TelegramMessageArrived {
-            from: Some("userName".to_string()),
-            message_id: 111000,
-            chat_id: 111000,
-            text: "1".to_string(),
-        };
+        let telegram_message = telegram_message("1");
         scope.mock_telegram_sender.expect_send_message()
             .withf(|message| message.chat_id == 111000 && message.text == "this is state 2!")
-            .return_const(());
-        scope.mock_states.expect_change_state().return_const(());
+            .return_const(Ok(()));
+        scope.mock_states.expect_change_state().returning(|_, _| Ok(()));
         let message_gateway = scope.build_object();
 
         <dyn TelegramListener>::message_arrived(&message_gateway, telegram_message);
@@ -206,23 +754,49 @@ mod messages_gateway_tests {
         let mut scope = TestScope::new();
         let chat_state = ChatState {
             current_state: String::from("state-2"),
-            data: HashMap::new(),
+            data: StateData::new(),
+            locale: None,
+            last_activity: None,
+            role: None,
+            chat_type: None,
         };
         scope.mock_states.expect_get().return_once(move |_| Some(chat_state));        
         scope.state_machine_builder.expect_build().return_once(build_state_machine);
-        let telegram_message = TelegramMessageArrived {
-            from: Some("userName".to_string()),
-            message_id: 111000,
-            chat_id: 111000,
-            text: "1".to_string(),
-        };
+        let telegram_message = telegram_message("1");
         scope.mock_telegram_sender.expect_send_message()
             .withf(|message| message.chat_id == 111000 && message.text == "invalid option")
-            .return_const(());
+            .return_const(Ok(()));
         scope.mock_telegram_sender.expect_send_message()
             .withf(|message| message.chat_id == 111000 && message.text == "this is state 2!")
-            .return_const(());
-            scope.mock_states.expect_change_state().return_const(());
+            .return_const(Ok(()));
+            scope.mock_states.expect_change_state().returning(|_, _| Ok(()));
+        let message_gateway = scope.build_object();
+
+        <dyn TelegramListener>::message_arrived(&message_gateway, telegram_message);
+    }    
+
+    #[test]
+    fn message_gateway_should_send_valid_options_on_wrong_transition() {
+        let mut scope = TestScope::new();
+        scope.state_machine_builder.expect_build().return_once(|state_data, _chat| {
+            let mut state_machine = StateMachine::new(state_data);
+            let mut state = State::new("state-1");
+            state.add_transition("state-1", EqTransitionRule::new("1"));
+            state.add_transition("state-1", EqTransitionRule::new("2").with_label("two"));
+            state_machine.add_state(state);
+            state_machine.set_initial_state_name("state-1").unwrap();
+            state_machine
+        });
+        scope.mock_states.expect_get().return_once(move |_| None);
+        let telegram_message = telegram_message("3");
+        scope.mock_telegram_sender.expect_send_message()
+            .withf(|message| message.chat_id == 111000 && message.text == "valid options are: 1, 2 (two)")
+            .times(1)
+            .return_const(Ok(()));
+        scope.mock_states.expect_change_state()
+            .withf(|_chat_id, state| state.current_state == "state-1" && state.last_activity.is_some())
+            .times(1)
+            .returning(|_, _| Ok(()));
         let message_gateway = scope.build_object();
 
         <dyn TelegramListener>::message_arrived(&message_gateway, telegram_message);
@@ -233,24 +807,514 @@ mod messages_gateway_tests {
         let mut scope = TestScope::new();
         let chat_state = ChatState {
             current_state: String::from("state-2"),
-            data: HashMap::new(),
+            data: StateData::new(),
+            locale: None,
+            last_activity: None,
+            role: None,
+            chat_type: None,
         };
         scope.state_machine_builder.expect_build().return_once(build_state_machine);
         scope.mock_states.expect_get()
             .withf(|chat_id| chat_id == "111000")
             .return_once(move |_| Some(chat_state));        
-        let telegram_message = TelegramMessageArrived {
-            from: Some("userName".to_string()),
-            message_id: 111000,
-            chat_id: 111000,
-            text: "2".to_string(),
-        };
-        scope.mock_telegram_sender.expect_send_message().return_const(());
+        let telegram_message = telegram_message("2");
+        scope.mock_telegram_sender.expect_send_message().return_const(Ok(()));
         scope.mock_states.expect_change_state()
             .withf(|_chat_id, state| state.current_state == "state-1")
-            .return_const(());
+            .returning(|_, _| Ok(()));
         let message_gateway = scope.build_object();
 
         <dyn TelegramListener>::message_arrived(&message_gateway, telegram_message);
-    }    
+    }
+
+    #[test]
+    fn message_gateway_should_seed_locale_from_language_code() {
+        let mut scope = TestScope::new();
+        scope.state_machine_builder.expect_build()
+            .withf(|_state_data, chat| chat.chat_id == "111000" && chat.locale == "en" && chat.role == Role::User)
+            .return_once(build_state_machine);
+        scope.mock_states.expect_get().return_once(move |_| None);
+        let mut telegram_message = telegram_message("1");
+        telegram_message.from.as_mut().unwrap().language_code = Some("en".to_string());
+        scope.mock_telegram_sender.expect_send_message().return_const(Ok(()));
+        scope.mock_states.expect_change_state()
+            .withf(|_chat_id, state| state.locale == Some("en".to_string()))
+            .returning(|_, _| Ok(()));
+        let message_gateway = scope.build_object();
+
+        <dyn TelegramListener>::message_arrived(&message_gateway, telegram_message);
+    }
+
+    #[test]
+    fn message_gateway_should_keep_chat_locale() {
+        let mut scope = TestScope::new();
+        let chat_state = ChatState {
+            current_state: String::from("state-1"),
+            data: StateData::new(),
+            locale: Some("es".to_string()),
+            last_activity: None,
+            role: None,
+            chat_type: None,
+        };
+        scope.mock_states.expect_get().return_once(move |_| Some(chat_state));
+        scope.state_machine_builder.expect_build()
+            .withf(|_state_data, chat| chat.locale == "es")
+            .return_once(build_state_machine);
+        let mut telegram_message = telegram_message("1");
+        telegram_message.from.as_mut().unwrap().language_code = Some("en".to_string());
+        scope.mock_telegram_sender.expect_send_message().return_const(Ok(()));
+        scope.mock_states.expect_change_state().returning(|_, _| Ok(()));
+        let message_gateway = scope.build_object();
+
+        <dyn TelegramListener>::message_arrived(&message_gateway, telegram_message);
+    }
+
+    #[test]
+    fn message_gateway_should_follow_state_timeout_before_handling_message() {
+        let mut scope = TestScope::new();
+        let chat_state = ChatState {
+            current_state: String::from("state-2"),
+            data: StateData::new(),
+            locale: None,
+            last_activity: Some(Utc::now() - chrono::Duration::hours(1)),
+            role: None,
+            chat_type: None,
+        };
+        scope.mock_states.expect_get().return_once(move |_| Some(chat_state));
+        scope.state_machine_builder.expect_build().return_once(|state_data, chat| {
+            let mut state_machine = build_state_machine(state_data, chat);
+            state_machine.set_state_timeout("state-2", Timeout::new(Duration::from_secs(60), "state-1")
+                .with_output(FixedTransitionOutput::new("still there?"))).unwrap();
+            state_machine
+        });
+        let telegram_message = telegram_message("1");
+        let mut sequence = mockall::Sequence::new();
+        scope.mock_telegram_sender.expect_send_message()
+            .withf(|message| message.text == "still there?")
+            .times(1)
+            .in_sequence(&mut sequence)
+            .return_const(Ok(()));
+        scope.mock_telegram_sender.expect_send_message()
+            .withf(|message| message.text == "this is state 2!")
+            .times(1)
+            .in_sequence(&mut sequence)
+            .return_const(Ok(()));
+        scope.mock_states.expect_change_state()
+            .withf(|_chat_id, state| state.current_state == "state-2" && state.role == Some(Role::User) && state.chat_type == Some(ChatType::Private))
+            .returning(|_, _| Ok(()));
+        let message_gateway = scope.build_object();
+
+        <dyn TelegramListener>::message_arrived(&message_gateway, telegram_message);
+    }
+
+    #[test]
+    fn message_gateway_should_reset_session_whose_state_no_longer_exists() {
+        let mut scope = TestScope::new();
+        let chat_state = ChatState {
+            current_state: String::from("removed-state"),
+            data: StateData::new(),
+            locale: None,
+            last_activity: Some(Utc::now()),
+            role: None,
+            chat_type: None,
+        };
+        scope.mock_states.expect_get().return_once(move |_| Some(chat_state));
+        scope.state_machine_builder.expect_build().times(2).returning(build_state_machine);
+        scope.mock_telegram_sender.expect_send_message()
+            .withf(|message| message.text == "this is state 2!")
+            .times(1)
+            .return_const(Ok(()));
+        scope.mock_states.expect_change_state()
+            .withf(|_chat_id, state| state.current_state == "state-2")
+            .times(1)
+            .returning(|_, _| Ok(()));
+        let message_gateway = scope.build_object();
+
+        <dyn TelegramListener>::message_arrived(&message_gateway, telegram_message("1"));
+    }
+
+    #[test]
+    fn message_gateway_should_purge_sessions_older_than_ttl() {
+        let mut scope = TestScope::new();
+        let now = Utc::now();
+        scope.mock_states.expect_purge_inactive()
+            .withf(move |since| *since == now - chrono::Duration::days(30))
+            .times(1)
+            .returning(|_| Ok(3));
+        let message_gateway = scope.build_object().with_session_ttl(Duration::from_secs(30 * 24 * 60 * 60));
+
+        assert_eq!(3, message_gateway.purge_inactive_sessions(now));
+    }
+
+    #[test]
+    fn message_gateway_should_schedule_reminders_and_timeout_of_new_state() {
+        let mut scope = TestScope::new();
+        scope.mock_states.expect_get().return_once(move |_| None);
+        scope.state_machine_builder.expect_build().return_once(|state_data, chat| {
+            let mut state_machine = build_state_machine(state_data, chat);
+            state_machine.add_state_reminder("state-2", Duration::from_secs(30), FixedStateOutput::new("reminder")).unwrap();
+            state_machine.set_state_timeout("state-2", Timeout::new(Duration::from_secs(60), "state-1")).unwrap();
+            state_machine
+        });
+        scope.mock_telegram_sender.expect_send_message().return_const(Ok(()));
+        scope.mock_states.expect_change_state().returning(|_, _| Ok(()));
+        let mut mock_schedules = MockSchedules::new();
+        mock_schedules.expect_cancel_state_jobs()
+            .withf(|chat_id| chat_id == "111000")
+            .times(1)
+            .returning(|_| Ok(0));
+        mock_schedules.expect_add()
+            .withf(|job| job.state == Some("state-2".to_string()) && job.kind == JobKind::Message { text: "reminder".to_string() })
+            .times(1)
+            .returning(|_| Ok(()));
+        mock_schedules.expect_add()
+            .withf(|job| job.state == Some("state-2".to_string()) && job.kind == JobKind::Timeout)
+            .times(1)
+            .returning(|_| Ok(()));
+        let message_gateway = scope.build_object().with_schedules(Rc::new(RefCell::new(mock_schedules)));
+        let telegram_message = telegram_message("1");
+
+        <dyn TelegramListener>::message_arrived(&message_gateway, telegram_message);
+    }
+
+    #[test]
+    fn message_gateway_should_drop_reminder_when_chat_left_state() {
+        let mut scope = TestScope::new();
+        let chat_state = ChatState {
+            current_state: String::from("state-1"),
+            data: StateData::new(),
+            locale: None,
+            last_activity: Some(Utc::now()),
+            role: None,
+            chat_type: None,
+        };
+        scope.mock_states.expect_get().return_once(move |_| Some(chat_state));
+        scope.mock_telegram_sender.expect_send_message().never();
+        let now = Utc::now();
+        let mut mock_schedules = MockSchedules::new();
+        mock_schedules.expect_take_due().return_once(|now| Ok(vec![
+            ScheduledJob::new("111000", now, JobKind::Message { text: "reminder".to_string() }).for_state("state-2"),
+        ]));
+        let message_gateway = scope.build_object().with_schedules(Rc::new(RefCell::new(mock_schedules)));
+
+        assert_eq!(1, message_gateway.run_due_jobs(now));
+    }
+
+    #[test]
+    fn message_gateway_should_send_due_timeout_outputs() {
+        let mut scope = TestScope::new();
+        let chat_state = ChatState {
+            current_state: String::from("state-2"),
+            data: StateData::new(),
+            locale: Some("en".to_string()),
+            last_activity: Some(Utc::now() - chrono::Duration::minutes(5)),
+            role: Some(Role::Admin),
+            chat_type: Some(ChatType::Group),
+        };
+        scope.mock_states.expect_get().return_once(move |_| Some(chat_state));
+        scope.state_machine_builder.expect_build().return_once(|state_data, chat| {
+            assert_eq!((Role::Admin, ChatType::Group), (chat.role, chat.chat_type));
+            let mut state_machine = build_state_machine(state_data, chat);
+            state_machine.set_state_timeout("state-2", Timeout::new(Duration::from_secs(60), "state-2")
+                .with_output(FixedTransitionOutput::new("still there?"))).unwrap();
+            state_machine
+        });
+        let mut sequence = mockall::Sequence::new();
+        scope.mock_telegram_sender.expect_send_message()
+            .withf(|message| message.chat_id == 111000 && message.text == "still there?")
+            .times(1)
+            .in_sequence(&mut sequence)
+            .return_const(Ok(()));
+        scope.mock_telegram_sender.expect_send_message()
+            .withf(|message| message.chat_id == 111000 && message.text == "this is state 2!")
+            .times(1)
+            .in_sequence(&mut sequence)
+            .return_const(Ok(()));
+        scope.mock_states.expect_change_state()
+            .withf(|_chat_id, state| state.locale == Some("en".to_string()) && state.role == Some(Role::Admin))
+            .times(1)
+            .returning(|_, _| Ok(()));
+        let now = Utc::now();
+        let mut mock_schedules = MockSchedules::new();
+        mock_schedules.expect_take_due().return_once(|now| Ok(vec![
+            ScheduledJob::new("111000", now, JobKind::Timeout).for_state("state-2"),
+        ]));
+        mock_schedules.expect_cancel_state_jobs().returning(|_| Ok(0));
+        mock_schedules.expect_add().withf(|job| job.kind == JobKind::Timeout).returning(|_| Ok(()));
+        let message_gateway = scope.build_object().with_schedules(Rc::new(RefCell::new(mock_schedules)));
+
+        assert_eq!(1, message_gateway.run_due_jobs(now));
+    }
+
+    #[test]
+    fn message_gateway_should_report_broadcast_deliveries() {
+        let mut scope = TestScope::new();
+        scope.mock_states.expect_get().returning(|_| None);
+        scope.mock_telegram_sender.expect_send_message()
+            .withf(|message| message.chat_id == 1)
+            .return_const(Ok(()));
+        scope.mock_telegram_sender.expect_send_message()
+            .withf(|message| message.chat_id == 2)
+            .return_const(Err(TelegramError::Api("Forbidden: bot was blocked by the user".to_string())));
+        let now = Utc::now();
+        let mut mock_schedules = MockSchedules::new();
+        mock_schedules.expect_take_due().return_once(|now| Ok(vec![
+            ScheduledJob::new("1", now, JobKind::Broadcast { broadcast_id: "b1".to_string(), text: "hi".to_string() }),
+            ScheduledJob::new("2", now, JobKind::Broadcast { broadcast_id: "b1".to_string(), text: "hi".to_string() }),
+        ]));
+        let mut mock_broadcasts = MockBroadcasts::new();
+        mock_broadcasts.expect_record_delivery()
+            .withf(|broadcast_id, delivered| broadcast_id == "b1" && *delivered)
+            .times(1)
+            .returning(|_, _| Ok(()));
+        mock_broadcasts.expect_record_delivery()
+            .withf(|broadcast_id, delivered| broadcast_id == "b1" && !*delivered)
+            .times(1)
+            .returning(|_, _| Ok(()));
+        let message_gateway = scope.build_object()
+            .with_schedules(Rc::new(RefCell::new(mock_schedules)))
+            .with_broadcasts(Rc::new(RefCell::new(mock_broadcasts)));
+
+        assert_eq!(2, message_gateway.run_due_jobs(now));
+    }
+
+    #[test]
+    fn message_gateway_should_build_admin_chats_for_allowed_users() {
+        let mut scope = TestScope::new();
+        scope.mock_states.expect_get().return_once(move |_| None);
+        scope.state_machine_builder.expect_build()
+            .withf(|_state_data, chat| chat.role == Role::Admin)
+            .return_once(build_state_machine);
+        scope.mock_telegram_sender.expect_send_message().return_const(Ok(()));
+        scope.mock_states.expect_change_state().returning(|_, _| Ok(()));
+        let message_gateway = scope.build_object().with_admins(AdminAllowList::parse("@username"));
+        let telegram_message = telegram_message("1");
+
+        <dyn TelegramListener>::message_arrived(&message_gateway, telegram_message);
+    }
+
+    #[test]
+    fn message_gateway_should_give_sender_context_to_state_machine() {
+        let mut scope = TestScope::new();
+        scope.mock_states.expect_get().return_once(move |_| None);
+        scope.state_machine_builder.expect_build().return_once(|state_data, _chat| {
+            let mut state_machine = StateMachine::new(state_data);
+            let mut state = State::new("state-1");
+            state.add_transition_with_output("state-1", DefaultTransitionRule::new(),
+                TemplateTransitionOutput::new("Hi {{context.user.first_name}} ({{context.chat.type}})").unwrap());
+            state_machine.add_state(state);
+            state_machine.set_initial_state_name("state-1").unwrap();
+            state_machine
+        });
+        scope.mock_telegram_sender.expect_send_message()
+            .withf(|message| message.text == "Hi User (private)")
+            .times(1)
+            .return_const(Ok(()));
+        scope.mock_states.expect_change_state()
+            .withf(|_chat_id, state| !state.data.contains_key("context"))
+            .returning(|_, _| Ok(()));
+        let message_gateway = scope.build_object();
+
+        <dyn TelegramListener>::message_arrived(&message_gateway, telegram_message("hi"));
+    }
+
+    #[test]
+    fn message_gateway_should_keep_group_members_conversations_apart() {
+        let mut registration_manager = MockRegistrationManager::new();
+        registration_manager.expect_add()
+            .withf(|name, phone, chat_id, user, _message_key| name == "Ana Silva" && phone == "111" && chat_id.as_deref() == Some("-100123") && user.as_ref().unwrap().id == 222000)
+            .times(1)
+            .return_once(|_, _, _, _, _| Ok(()));
+        registration_manager.expect_add()
+            .withf(|name, phone, _chat_id, user, _message_key| name == "Beto Souza" && phone == "222" && user.as_ref().unwrap().id == 333000)
+            .times(1)
+            .return_once(|_, _, _, _, _| Ok(()));
+        let mut telegram_sender = MockTelegramSender::new();
+        telegram_sender.expect_send_message()
+            .withf(|message| message.chat_id == -100123)
+            .return_const(Ok(()));
+        let states = Rc::new(RefCell::new(StatesInMemory::new()));
+        let message_gateway = MessagesGateway::new(
+            states.clone(),
+            Arc::new(telegram_sender),
+            Box::new(ChatbotBuilder::new(Rc::new(RefCell::new(registration_manager)))),
+        ).with_bot_username("cadastro_bot");
+        let ana = |text: &str| group_message(222000, "Ana", &format!("@cadastro_bot {}", text));
+        let beto = |text: &str| group_message(333000, "Beto", &format!("@cadastro_bot {}", text));
+
+        for message in [ana("oi"), beto("oi"), ana("1"), beto("1"), ana("Ana Silva"), beto("Beto Souza"),
+                group_message(222000, "Ana", "bom dia, pessoal"), ana("111"), beto("222"), beto("sim"), ana("sim")] {
+            <dyn TelegramListener>::message_arrived(&message_gateway, message);
+        }
+
+        assert_eq!("menu", states.borrow_mut().get("-100123:222000").unwrap().current_state);
+        assert_eq!("menu", states.borrow_mut().get("-100123:333000").unwrap().current_state);
+        assert!(states.borrow_mut().get("-100123").is_none());
+    }
+
+    #[test]
+    fn message_gateway_should_record_transcript_of_session() {
+        let mut scope = TestScope::new();
+        scope.state_machine_builder.expect_build().return_once(build_state_machine);
+        scope.mock_states.expect_get().return_once(move |_| None);
+        scope.mock_telegram_sender.expect_send_message().return_const(Ok(()));
+        scope.mock_states.expect_change_state().returning(|_, _| Ok(()));
+        let transcripts = Rc::new(RefCell::new(TranscriptsInMemory::new()));
+        let message_gateway = scope.build_object().with_transcripts(transcripts.clone());
+
+        <dyn TelegramListener>::message_arrived(&message_gateway, telegram_message("1"));
+
+        let events: Vec<TranscriptEvent> = transcripts.borrow().chat_transcript("111000").unwrap().into_iter().map(|entry| entry.event).collect();
+        assert_eq!(vec![
+            TranscriptEvent::Inbound { text: "1".to_string() },
+            TranscriptEvent::Transition { from: "state-1".to_string(), to: "state-2".to_string(), action: "1".to_string(), outputs: vec!["this is state 2!".to_string()] },
+            TranscriptEvent::Outbound { text: "this is state 2!".to_string() },
+        ], events);
+    }
+
+    #[test]
+    fn message_gateway_should_record_group_transcripts_by_chat_and_user() {
+        let mut scope = TestScope::new();
+        scope.state_machine_builder.expect_build().return_once(build_state_machine);
+        scope.mock_states.expect_get().return_once(move |_| None);
+        scope.mock_telegram_sender.expect_send_message().return_const(Ok(()));
+        scope.mock_states.expect_change_state().returning(|_, _| Ok(()));
+        let transcripts = Rc::new(RefCell::new(TranscriptsInMemory::new()));
+        let message_gateway = scope.build_object().with_transcripts(transcripts.clone());
+
+        <dyn TelegramListener>::message_arrived(&message_gateway, group_message(222000, "Fulano", "1"));
+
+        let transcript = transcripts.borrow().chat_transcript("-100123").unwrap();
+        assert_eq!(3, transcript.len());
+        assert!(transcript.iter().all(|entry| entry.user_id == Some("222000".to_string())));
+    }
+
+    #[test]
+    fn message_gateway_should_keep_handling_messages_when_transcripts_fail() {
+        let mut scope = TestScope::new();
+        scope.state_machine_builder.expect_build().return_once(build_state_machine);
+        scope.mock_states.expect_get().return_once(move |_| None);
+        scope.mock_telegram_sender.expect_send_message()
+            .withf(|message| message.text == "this is state 2!")
+            .times(1)
+            .return_const(Ok(()));
+        scope.mock_states.expect_change_state()
+            .withf(|_, state| state.current_state == "state-2")
+            .times(1)
+            .returning(|_, _| Ok(()));
+        let mut transcripts = MockTranscripts::new();
+        transcripts.expect_record().returning(|_| Err(TranscriptError::Io(io::Error::new(io::ErrorKind::StorageFull, "disk full"))));
+        let message_gateway = scope.build_object().with_transcripts(Rc::new(RefCell::new(transcripts)));
+
+        <dyn TelegramListener>::message_arrived(&message_gateway, telegram_message("1"));
+    }
+
+    #[test]
+    fn message_gateway_should_count_updates_transitions_and_messages() {
+        let mut scope = TestScope::new();
+        scope.state_machine_builder.expect_build().return_once(build_state_machine);
+        scope.mock_states.expect_get().return_once(move |_| None);
+        scope.mock_telegram_sender.expect_send_message().return_const(Err(TelegramError::Api("Forbidden".to_string())));
+        scope.mock_states.expect_change_state().returning(|_, _| Ok(()));
+        scope.mock_states.expect_count().return_const(4usize);
+        let metrics = Arc::new(Metrics::new());
+        let message_gateway = scope.build_object().with_metrics(metrics.clone());
+
+        <dyn TelegramListener>::message_arrived(&message_gateway, telegram_message("1"));
+
+        let rendered = metrics.render();
+        assert!(rendered.contains("chatbot_updates_received_total 1"));
+        assert!(rendered.contains("chatbot_transitions_total{from=\"state-1\",to=\"state-2\"} 1"));
+        assert!(rendered.contains("chatbot_messages_sent_total{result=\"failed\"} 1"));
+        assert!(rendered.contains("chatbot_active_sessions 4"));
+    }
+
+    #[test]
+    fn message_gateway_should_report_unavailable_storage_to_health() {
+        let mut scope = TestScope::new();
+        scope.mock_states.expect_check().return_const(Err("states.json: permission denied".to_string()));
+        let mut registration_manager = MockRegistrationManager::new();
+        registration_manager.expect_check().return_const(Err("registrations.json: permission denied".to_string()));
+        let health = Arc::new(Health::new(Utc::now(), Duration::from_secs(15)));
+        health.polled(Utc::now());
+        let message_gateway = scope.build_object()
+            .with_registrations(Rc::new(RefCell::new(registration_manager)))
+            .with_health(health.clone());
+
+        message_gateway.check_health(Utc::now());
+
+        let report = health.readiness(Utc::now());
+        assert!(!report.ok);
+        assert_eq!("states.json: permission denied", report.to_json()["checks"]["storage"]["detail"]["states"]);
+        assert_eq!("registrations.json: permission denied", report.to_json()["checks"]["storage"]["detail"]["registrations"]);
+        assert!(health.liveness(Utc::now()).ok);
+    }
+
+    #[test]
+    fn message_gateway_should_keep_running_and_report_failed_state_saves() {
+        let mut scope = TestScope::new();
+        scope.state_machine_builder.expect_build().return_once(build_state_machine);
+        scope.mock_states.expect_get().return_once(move |_| None);
+        scope.mock_telegram_sender.expect_send_message().times(1).return_const(Ok(()));
+        scope.mock_states.expect_change_state()
+            .returning(|_, _| Err(io::Error::new(io::ErrorKind::StorageFull, "disk full")));
+        let health = Arc::new(Health::new(Utc::now(), Duration::from_secs(15)));
+        let message_gateway = scope.build_object().with_health(health.clone());
+
+        <dyn TelegramListener>::message_arrived(&message_gateway, telegram_message("1"));
+
+        assert_eq!("disk full", health.readiness(Utc::now()).to_json()["checks"]["storage"]["detail"]["states"]);
+    }
+
+    #[test]
+    fn message_gateway_should_handle_redelivery_of_message_that_failed() {
+        let mut scope = TestScope::new();
+        scope.state_machine_builder.expect_build().times(2).returning(build_state_machine);
+        scope.mock_states.expect_get().returning(|_| None);
+        scope.mock_telegram_sender.expect_send_message()
+            .withf(|message| message.text == "this is state 2!")
+            .times(2)
+            .return_const(Ok(()));
+        let mut sequence = mockall::Sequence::new();
+        scope.mock_states.expect_change_state()
+            .times(1)
+            .in_sequence(&mut sequence)
+            .returning(|_, _| Err(io::Error::new(io::ErrorKind::StorageFull, "disk full")));
+        scope.mock_states.expect_change_state()
+            .times(1)
+            .in_sequence(&mut sequence)
+            .returning(|_, _| Ok(()));
+        let message_gateway = scope.build_object()
+            .with_processed_messages(Rc::new(RefCell::new(ProcessedMessagesInMemory::new(Duration::from_secs(60 * 60)))));
+
+        let handled: Vec<bool> = (0..3)
+            .map(|_| <dyn TelegramListener>::message_arrived(&message_gateway, telegram_message("1")))
+            .collect();
+
+        assert_eq!(vec![false, true, true], handled);
+    }
+
+    #[test]
+    fn message_gateway_should_not_register_twice_when_updates_are_redelivered() {
+        let mut registration_manager = MockRegistrationManager::new();
+        registration_manager.expect_add()
+            .times(1)
+            .return_once(|_, _, _, _, _| Ok(()));
+        let mut telegram_sender = MockTelegramSender::new();
+        telegram_sender.expect_send_message()
+            .times(6)
+            .return_const(Ok(()));
+        let message_gateway = MessagesGateway::new(
+            Rc::new(RefCell::new(StatesInMemory::new())),
+            Arc::new(telegram_sender),
+            Box::new(ChatbotBuilder::new(Rc::new(RefCell::new(registration_manager)))),
+        ).with_processed_messages(Rc::new(RefCell::new(ProcessedMessagesInMemory::new(Duration::from_secs(60 * 60)))));
+        let updates: Vec<TelegramMessageArrived> = ["oi", "1", "Ana Silva", "111", "sim"].iter().enumerate()
+            .map(|(message_id, text)| TelegramMessageArrived { message_id: message_id as i64, ..telegram_message(text) })
+            .collect();
+
+        for message in updates.iter().chain(updates.iter()) {
+            <dyn TelegramListener>::message_arrived(&message_gateway, message.clone());
+        }
+    }
 }
@@ -0,0 +1,55 @@
+use super::Role;
+
+/// Telegram users allowed to run admin commands, identified by user id or username.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AdminAllowList {
+    user_ids: Vec<i64>,
+    usernames: Vec<String>,
+}
+impl AdminAllowList {
+    pub fn new() -> Self {
+        Self {
+            user_ids: Vec::new(),
+            usernames: Vec::new(),
+        }
+    }
+
+    /// Reads a comma separated list of user ids and usernames, e.g. `12345, @alice, bob`.
+    pub fn parse(list: &str) -> Self {
+        let mut admins = Self::new();
+        for entry in list.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+            match entry.parse::<i64>() {
+                Ok(user_id) => admins.user_ids.push(user_id),
+                Err(_) => admins.usernames.push(entry.trim_start_matches('@').to_lowercase()),
+            }
+        }
+        admins
+    }
+
+    /// Telegram usernames are case-insensitive, so they're compared ignoring case.
+    pub fn role_of(&self, user_id: Option<i64>, username: Option<&str>) -> Role {
+        let by_id = user_id.is_some_and(|id| self.user_ids.contains(&id));
+        let by_username = username.is_some_and(|name| self.usernames.contains(&name.to_lowercase()));
+        if by_id || by_username {
+            Role::Admin
+        } else {
+            Role::User
+        }
+    }
+}
+
+#[cfg(test)]
+mod admins_tests {
+    use super::*;
+
+    #[test]
+    fn admin_allow_list_should_match_user_ids_and_usernames() {
+        let admins = AdminAllowList::parse("12345, @Alice, bob,");
+
+        assert_eq!(Role::Admin, admins.role_of(Some(12345), None));
+        assert_eq!(Role::Admin, admins.role_of(Some(1), Some("alice")));
+        assert_eq!(Role::Admin, admins.role_of(None, Some("BOB")));
+        assert_eq!(Role::User, admins.role_of(Some(1), Some("carol")));
+        assert_eq!(Role::User, AdminAllowList::new().role_of(Some(12345), Some("alice")));
+    }
+}
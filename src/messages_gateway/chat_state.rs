@@ -1,17 +1,62 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, io, path::PathBuf};
 
+use chrono::{DateTime, Utc};
 use mockall::automock;
+use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Clone)]
+use crate::{state_machine::StateData, storage::{check_json, load_json, save_json}, telegram::ChatType};
+
+use super::{Role, session::session_chat_id};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ChatState {
-    pub data: HashMap<String, String>,
+    pub data: StateData,
     pub current_state: String,
+    #[serde(default)]
+    pub locale: Option<String>,
+    #[serde(default)]
+    pub last_activity: Option<DateTime<Utc>>,
+    /// Role and chat type of the last message, so timeouts rebuild the flow the chat was in.
+    #[serde(default)]
+    pub role: Option<Role>,
+    #[serde(default)]
+    pub chat_type: Option<ChatType>,
+}
+impl ChatState {
+    /// States saved before activity was tracked count as expired.
+    fn is_inactive_since(&self, since: DateTime<Utc>) -> bool {
+        self.last_activity.is_none_or(|last_activity| last_activity < since)
+    }
 }
 
 #[automock]
 pub trait States {
     fn get(&self, chat_id: &str) -> Option<ChatState>;
-    fn change_state(&mut self, chat_id: &str, state: ChatState);
+    fn change_state(&mut self, chat_id: &str, state: ChatState) -> io::Result<()>;
+    /// Removes the chats without activity since `since`, returning how many were removed.
+    fn purge_inactive(&mut self, since: DateTime<Utc>) -> io::Result<usize>;
+    fn count(&self) -> usize;
+    /// Every saved session with its state, sorted by session key.
+    fn all(&self) -> Vec<(String, ChatState)>;
+    fn remove(&mut self, chat_id: &str) -> io::Result<bool>;
+    /// Removes every session held in `chat_id`, e.g. each user's in a group, returning how many.
+    fn remove_chat(&mut self, chat_id: &str) -> io::Result<usize> {
+        let session_keys: Vec<String> = self.all().into_iter()
+            .map(|(session_key, _)| session_key)
+            .filter(|session_key| session_chat_id(session_key) == chat_id)
+            .collect();
+        let mut removed = 0;
+        for session_key in session_keys {
+            if self.remove(&session_key)? {
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+    /// Whether the backing storage can be written, for readiness checks.
+    fn check(&self) -> Result<(), String> {
+        Ok(())
+    }
 }
 
 pub struct StatesInMemory {
@@ -26,13 +71,169 @@ impl StatesInMemory {
 }
 impl States for StatesInMemory {
     fn get(&self, chat_id: &str) -> Option<ChatState> {
-        match self.states.get(chat_id) {
-            Some(state) => Some(state.clone()),
-            None => None,
-        }
+        self.states.get(chat_id).cloned()
+    }
+
+    fn change_state(&mut self, chat_id: &str, state: ChatState) -> io::Result<()> {
+        self.states.insert(chat_id.to_string(), state);
+        Ok(())
+    }
+
+    fn purge_inactive(&mut self, since: DateTime<Utc>) -> io::Result<usize> {
+        let count = self.states.len();
+        self.states.retain(|_, state| !state.is_inactive_since(since));
+        Ok(count - self.states.len())
+    }
+
+    fn count(&self) -> usize {
+        self.states.len()
+    }
+
+    fn all(&self) -> Vec<(String, ChatState)> {
+        sorted(&self.states)
+    }
+
+    fn remove(&mut self, chat_id: &str) -> io::Result<bool> {
+        Ok(self.states.remove(chat_id).is_some())
+    }
+}
+
+/// Keeps every chat state in a single JSON file, rewritten on each change.
+pub struct StatesJsonFile {
+    path: PathBuf,
+    states: HashMap<String, ChatState>,
+    save_error: Option<String>,
+}
+impl StatesJsonFile {
+    pub fn new(path: &str) -> io::Result<Self> {
+        let path = PathBuf::from(path);
+        let states = load_json(&path)?;
+        Ok(Self {
+            path,
+            states,
+            save_error: None,
+        })
+    }
+
+    fn save(&mut self) -> io::Result<()> {
+        let saved = save_json(&self.path, &self.states);
+        self.save_error = saved.as_ref().err().map(|error| error.to_string());
+        saved
+    }
+}
+impl States for StatesJsonFile {
+    fn check(&self) -> Result<(), String> {
+        check_json(&self.path, self.save_error.as_ref())
+    }
+
+    fn get(&self, chat_id: &str) -> Option<ChatState> {
+        self.states.get(chat_id).cloned()
     }
 
-    fn change_state(&mut self, chat_id: &str, state: ChatState) {
+    fn change_state(&mut self, chat_id: &str, state: ChatState) -> io::Result<()> {
         self.states.insert(chat_id.to_string(), state);
+        self.save()
+    }
+
+    fn purge_inactive(&mut self, since: DateTime<Utc>) -> io::Result<usize> {
+        let count = self.states.len();
+        self.states.retain(|_, state| !state.is_inactive_since(since));
+        let purged = count - self.states.len();
+        if purged > 0 {
+            self.save()?;
+        }
+        Ok(purged)
+    }
+
+    fn count(&self) -> usize {
+        self.states.len()
+    }
+
+    fn all(&self) -> Vec<(String, ChatState)> {
+        sorted(&self.states)
+    }
+
+    fn remove(&mut self, chat_id: &str) -> io::Result<bool> {
+        let removed = self.states.remove(chat_id).is_some();
+        if removed {
+            self.save()?;
+        }
+        Ok(removed)
+    }
+}
+
+fn sorted(states: &HashMap<String, ChatState>) -> Vec<(String, ChatState)> {
+    let mut all: Vec<(String, ChatState)> = states.iter().map(|(key, state)| (key.clone(), state.clone())).collect();
+    all.sort_by(|(a, _), (b, _)| a.cmp(b));
+    all
+}
+
+#[cfg(test)]
+mod chat_state_tests {
+    use std::fs;
+
+    use chrono::Utc;
+    use serde_json::Value;
+
+    use super::*;
+
+    #[test]
+    fn states_json_file_should_load_saved_states() {
+        let path = std::env::temp_dir().join(format!("chat-states-{}.json", uuid::Uuid::new_v4()));
+        let path = path.to_str().unwrap();
+        let mut states = StatesJsonFile::new(path).unwrap();
+        let mut data = StateData::new();
+        data.insert("register-age".to_string(), Value::from(30));
+        data.insert("tags".to_string(), Value::from(vec!["a", "b"]));
+
+        states.change_state("111000", ChatState { data, current_state: "menu".to_string(), locale: Some("en".to_string()), last_activity: Some(Utc::now()), role: Some(Role::Admin), chat_type: Some(ChatType::Group) }).unwrap();
+        let loaded = StatesJsonFile::new(path).unwrap().get("111000").unwrap();
+        fs::remove_file(path).unwrap();
+
+        assert_eq!("menu", loaded.current_state);
+        assert_eq!(Some("en".to_string()), loaded.locale);
+        assert_eq!(30, loaded.data.get("register-age").unwrap().as_i64().unwrap());
+        assert_eq!(2, loaded.data.get("tags").unwrap().as_array().unwrap().len());
+        assert!(loaded.last_activity.is_some());
+        assert_eq!((Some(Role::Admin), Some(ChatType::Group)), (loaded.role, loaded.chat_type));
+    }
+
+    #[test]
+    fn states_json_file_should_return_save_errors() {
+        let path = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string()).join("states.json");
+        let mut states = StatesJsonFile::new(path.to_str().unwrap()).unwrap();
+
+        let error = states.change_state("111000", ChatState { data: StateData::new(), current_state: "menu".to_string(), locale: None, last_activity: None, role: None, chat_type: None });
+
+        assert_eq!(io::ErrorKind::NotFound, error.unwrap_err().kind());
+        assert!(states.check().is_err());
+    }
+
+    #[test]
+    fn states_should_purge_inactive_chats() {
+        let mut states = StatesInMemory::new();
+        let chat_state = |last_activity| ChatState { data: StateData::new(), current_state: "menu".to_string(), locale: None, last_activity, role: None, chat_type: None };
+        states.change_state("recent", chat_state(Some(Utc::now()))).unwrap();
+        states.change_state("old", chat_state(Some(Utc::now() - chrono::Duration::days(40)))).unwrap();
+        states.change_state("untracked", chat_state(None)).unwrap();
+
+        let purged = states.purge_inactive(Utc::now() - chrono::Duration::days(30)).unwrap();
+
+        assert_eq!(2, purged);
+        assert!(states.get("recent").is_some());
+        assert!(states.get("old").is_none());
+        assert!(states.get("untracked").is_none());
+    }
+
+    #[test]
+    fn states_should_remove_every_session_of_a_chat() {
+        let mut states = StatesInMemory::new();
+        let chat_state = || ChatState { data: StateData::new(), current_state: "menu".to_string(), locale: None, last_activity: None, role: None, chat_type: None };
+        states.change_state("-100123:222000", chat_state()).unwrap();
+        states.change_state("-100123:333000", chat_state()).unwrap();
+        states.change_state("-100456:222000", chat_state()).unwrap();
+
+        assert_eq!(2, states.remove_chat("-100123").unwrap());
+        assert_eq!(vec!["-100456:222000".to_string()], states.all().into_iter().map(|(key, _)| key).collect::<Vec<_>>());
     }
 }
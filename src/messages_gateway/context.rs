@@ -1,20 +1,54 @@
-use std::{sync::Arc, cell::RefCell};
+use std::{rc::Rc, cell::RefCell, time::Duration};
 
-use super::chat_state::*;
+use crate::config::{Config, ConfigError};
+
+use super::{admins::AdminAllowList, chat_state::*, dedup::*, session::SessionKeying};
 
 pub struct MessagesGatewayContext {    
-    pub states: Arc<RefCell<dyn States>>,
+    pub states: Rc<RefCell<dyn States>>,
+    pub session_ttl: Option<Duration>,
+    pub admins: AdminAllowList,
+    pub session_keying: SessionKeying,
+    pub bot_username: Option<String>,
+    pub processed_messages: Rc<RefCell<dyn ProcessedMessages>>,
+    pub default_locale: String,
 }
 impl MessagesGatewayContext {
-    pub fn build() -> Self {
-        let states = Arc::new(RefCell::new(Self::build_states()));
+    pub fn build(config: &Config) -> Result<Self, ConfigError> {
+        let states = Self::build_states(config)?;
+        let session_ttl = config.sessions.ttl_hours.map(hours);
+        let admins = AdminAllowList::parse(&config.admins.join(","));
+        let processed_messages = Self::build_processed_messages(config)?;
 
-        Self {
+        Ok(Self {
             states,
-        }
+            session_ttl,
+            admins,
+            session_keying: config.sessions.keying,
+            bot_username: config.telegram.bot_username.clone(),
+            processed_messages,
+            default_locale: config.default_locale.clone(),
+        })
+    }
+
+    fn build_processed_messages(config: &Config) -> Result<Rc<RefCell<dyn ProcessedMessages>>, ConfigError> {
+        let ttl = hours(config.sessions.dedup_ttl_hours);
+        Ok(match &config.storage.dedup_file {
+            Some(path) => Rc::new(RefCell::new(ProcessedMessagesJsonFile::new(path, ttl)
+                .map_err(|error| ConfigError::storage(path, error))?)),
+            None => Rc::new(RefCell::new(ProcessedMessagesInMemory::new(ttl))),
+        })
     }
 
-    fn build_states() -> impl States {            
-        StatesInMemory::new()
+    fn build_states(config: &Config) -> Result<Rc<RefCell<dyn States>>, ConfigError> {
+        Ok(match &config.storage.states_file {
+            Some(path) => Rc::new(RefCell::new(StatesJsonFile::new(path)
+                .map_err(|error| ConfigError::storage(path, error))?)),
+            None => Rc::new(RefCell::new(StatesInMemory::new())),
+        })
     }
 }
+
+fn hours(hours: u64) -> Duration {
+    Duration::from_secs(hours * 60 * 60)
+}
@@ -0,0 +1,111 @@
+use std::{collections::HashMap, io, path::PathBuf, time::Duration};
+
+use chrono::{DateTime, Utc};
+use mockall::automock;
+
+use crate::storage::{load_json, save_json};
+
+/// Messages already handled, so updates redelivered by Telegram (after a restart, or a
+/// webhook retry) don't run the state machine twice. Entries expire after a TTL.
+#[automock]
+pub trait ProcessedMessages {
+    /// Whether `key` was marked processed within the TTL before `now`.
+    fn is_processed(&self, key: &str, now: DateTime<Utc>) -> bool;
+    /// Records `key` as processed at `now`. Meant to be called once the message was fully
+    /// handled, so a redelivery of a message that failed is handled again.
+    fn mark_processed(&mut self, key: &str, now: DateTime<Utc>) -> io::Result<()>;
+}
+
+pub struct ProcessedMessagesInMemory {
+    ttl: chrono::Duration,
+    seen: HashMap<String, DateTime<Utc>>,
+}
+impl ProcessedMessagesInMemory {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl: chrono::Duration::from_std(ttl).unwrap(),
+            seen: HashMap::new(),
+        }
+    }
+}
+impl ProcessedMessages for ProcessedMessagesInMemory {
+    fn is_processed(&self, key: &str, now: DateTime<Utc>) -> bool {
+        is_processed(&self.seen, self.ttl, key, now)
+    }
+
+    fn mark_processed(&mut self, key: &str, now: DateTime<Utc>) -> io::Result<()> {
+        mark_processed(&mut self.seen, self.ttl, key, now);
+        Ok(())
+    }
+}
+
+/// Keeps the processed messages in a JSON file, rewritten on each new message, so
+/// redeliveries after a restart are recognized.
+pub struct ProcessedMessagesJsonFile {
+    path: PathBuf,
+    ttl: chrono::Duration,
+    seen: HashMap<String, DateTime<Utc>>,
+}
+impl ProcessedMessagesJsonFile {
+    pub fn new(path: &str, ttl: Duration) -> io::Result<Self> {
+        let path = PathBuf::from(path);
+        let seen = load_json(&path)?;
+        Ok(Self {
+            path,
+            ttl: chrono::Duration::from_std(ttl).unwrap(),
+            seen,
+        })
+    }
+}
+impl ProcessedMessages for ProcessedMessagesJsonFile {
+    fn is_processed(&self, key: &str, now: DateTime<Utc>) -> bool {
+        is_processed(&self.seen, self.ttl, key, now)
+    }
+
+    fn mark_processed(&mut self, key: &str, now: DateTime<Utc>) -> io::Result<()> {
+        mark_processed(&mut self.seen, self.ttl, key, now);
+        save_json(&self.path, &self.seen)
+    }
+}
+
+fn is_processed(seen: &HashMap<String, DateTime<Utc>>, ttl: chrono::Duration, key: &str, now: DateTime<Utc>) -> bool {
+    seen.get(key).is_some_and(|processed_on| now - *processed_on < ttl)
+}
+
+fn mark_processed(seen: &mut HashMap<String, DateTime<Utc>>, ttl: chrono::Duration, key: &str, now: DateTime<Utc>) {
+    seen.retain(|_, processed_on| now - *processed_on < ttl);
+    seen.insert(key.to_string(), now);
+}
+
+#[cfg(test)]
+mod dedup_tests {
+    use std::fs;
+
+    use super::*;
+
+    #[test]
+    fn processed_messages_should_forget_keys_after_ttl() {
+        let now = Utc::now();
+        let mut processed = ProcessedMessagesInMemory::new(Duration::from_secs(60 * 60));
+
+        assert!(!processed.is_processed("111000:7", now));
+
+        processed.mark_processed("111000:7", now).unwrap();
+
+        assert!(processed.is_processed("111000:7", now + chrono::Duration::minutes(30)));
+        assert!(!processed.is_processed("222000:7", now));
+        assert!(!processed.is_processed("111000:7", now + chrono::Duration::minutes(61)));
+    }
+
+    #[test]
+    fn processed_messages_json_file_should_remember_across_restarts() {
+        let path = std::env::temp_dir().join(format!("chat-processed-{}.json", uuid::Uuid::new_v4()));
+        let path = path.to_str().unwrap();
+        let now = Utc::now();
+
+        ProcessedMessagesJsonFile::new(path, Duration::from_secs(60)).unwrap().mark_processed("111000:7", now).unwrap();
+
+        assert!(ProcessedMessagesJsonFile::new(path, Duration::from_secs(60)).unwrap().is_processed("111000:7", now));
+        fs::remove_file(path).unwrap();
+    }
+}
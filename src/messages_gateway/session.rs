@@ -0,0 +1,159 @@
+use serde::Deserialize;
+
+use crate::telegram::{ChatType, TelegramMessageArrived};
+
+/// Separates the chat id from the user id in per user-in-chat session keys.
+const SESSION_KEY_SEPARATOR: char = ':';
+
+/// How arriving messages are grouped into conversations, each with its own `ChatState`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SessionKeying {
+    /// Everyone in a chat shares one conversation.
+    Chat,
+    /// Each user has one conversation, wherever they write from.
+    User,
+    /// Each user has one conversation per chat. Private chats keep the chat id as key.
+    #[default]
+    UserInChat,
+}
+impl SessionKeying {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.trim() {
+            "chat" => Some(Self::Chat),
+            "user" => Some(Self::User),
+            "user-in-chat" => Some(Self::UserInChat),
+            _ => None,
+        }
+    }
+
+    /// Key of the session a message belongs to. Messages without a sender fall back to the chat.
+    pub fn session_key(&self, message: &TelegramMessageArrived) -> String {
+        let chat_id = message.chat.id.to_string();
+        let user_id = match &message.from {
+            Some(user) => user.id,
+            None => return chat_id,
+        };
+        match self {
+            Self::Chat => chat_id,
+            Self::User => user_id.to_string(),
+            Self::UserInChat if message.chat.chat_type == ChatType::Private => chat_id,
+            Self::UserInChat => format!("{}{}{}", chat_id, SESSION_KEY_SEPARATOR, user_id),
+        }
+    }
+}
+
+/// Chat to send a session's messages to. A user's private chat has the user's id, so
+/// [`SessionKeying::User`] keys are chat ids as well.
+pub fn session_chat_id(session_key: &str) -> &str {
+    session_key.split(SESSION_KEY_SEPARATOR).next().unwrap_or(session_key)
+}
+
+/// User of a session kept per user in a group chat, `None` for the other keys.
+pub fn session_user_id(session_key: &str) -> Option<&str> {
+    session_key.split_once(SESSION_KEY_SEPARATOR).map(|(_, user_id)| user_id)
+}
+
+/// Decides whether a message is meant for the bot. In groups the bot only answers commands,
+/// mentions of `@bot_username` and replies to its own messages; in private chats, everything.
+pub struct AddressFilter {
+    bot_username: String,
+}
+impl AddressFilter {
+    pub fn new(bot_username: &str) -> Self {
+        Self {
+            bot_username: bot_username.trim_start_matches('@').to_lowercase(),
+        }
+    }
+
+    /// The text addressed to the bot, without the mention, or `None` if the bot wasn't addressed.
+    pub fn addressed_text(&self, message: &TelegramMessageArrived) -> Option<String> {
+        if message.chat.chat_type == ChatType::Private {
+            return Some(message.text.clone());
+        }
+        let mention = format!("@{}", self.bot_username);
+        let text = message.text.trim();
+        if let Some(command) = text.strip_prefix('/') {
+            let (name, arguments) = command.split_once(char::is_whitespace).unwrap_or((command, ""));
+            return match name.split_once('@') {
+                Some((name, bot)) if bot.eq_ignore_ascii_case(&self.bot_username) => Some(format!("/{} {}", name, arguments).trim_end().to_string()),
+                Some(_) => None,
+                None => Some(text.to_string()),
+            };
+        }
+        if text.get(..mention.len()).is_some_and(|prefix| prefix.eq_ignore_ascii_case(&mention)) {
+            return Some(text[mention.len()..].trim().to_string());
+        }
+        let replies_to_bot = message.reply_to.as_ref()
+            .and_then(|replied| replied.from.as_ref())
+            .is_some_and(|user| user.is_bot && user.username.as_deref().is_some_and(|username| username.eq_ignore_ascii_case(&self.bot_username)));
+        replies_to_bot.then(|| text.to_string())
+    }
+}
+
+#[cfg(test)]
+mod session_tests {
+    use chrono::Utc;
+
+    use crate::telegram::{TelegramChat, TelegramRepliedMessage, TelegramUser};
+
+    use super::*;
+
+    fn user(id: i64, username: &str, is_bot: bool) -> TelegramUser {
+        TelegramUser {
+            id,
+            is_bot,
+            first_name: username.to_string(),
+            last_name: None,
+            username: Some(username.to_string()),
+            language_code: None,
+        }
+    }
+
+    fn group_message(text: &str) -> TelegramMessageArrived {
+        TelegramMessageArrived {
+            message_id: 1,
+            date: Utc::now(),
+            from: Some(user(222000, "ana", false)),
+            chat: TelegramChat { id: -100123, chat_type: ChatType::Group, title: Some("Turma".to_string()) },
+            text: text.to_string(),
+            reply_to: None,
+        }
+    }
+
+    #[test]
+    fn session_keying_should_key_group_members_apart() {
+        let message = group_message("oi");
+
+        assert_eq!("-100123", SessionKeying::Chat.session_key(&message));
+        assert_eq!("222000", SessionKeying::User.session_key(&message));
+        assert_eq!("-100123:222000", SessionKeying::UserInChat.session_key(&message));
+        assert_eq!("-100123", session_chat_id("-100123:222000"));
+    }
+
+    #[test]
+    fn session_keying_should_keep_chat_id_in_private_chats() {
+        let mut message = group_message("oi");
+        message.chat = TelegramChat { id: 222000, chat_type: ChatType::Private, title: None };
+
+        assert_eq!("222000", SessionKeying::UserInChat.session_key(&message));
+        assert_eq!(Some(SessionKeying::UserInChat), SessionKeying::parse("user-in-chat"));
+    }
+
+    #[test]
+    fn address_filter_should_accept_only_messages_for_the_bot_in_groups() {
+        let filter = AddressFilter::new("@Cadastro_Bot");
+        let mut reply = group_message("Ana Silva");
+        reply.reply_to = Some(TelegramRepliedMessage { message_id: 7, from: Some(user(999000, "cadastro_bot", true)) });
+        let mut reply_to_someone_else = group_message("concordo");
+        reply_to_someone_else.reply_to = Some(TelegramRepliedMessage { message_id: 6, from: Some(user(333000, "beto", false)) });
+
+        assert_eq!(None, filter.addressed_text(&group_message("bom dia, pessoal")));
+        assert_eq!(None, filter.addressed_text(&reply_to_someone_else));
+        assert_eq!(None, filter.addressed_text(&group_message("/start@other_bot")));
+        assert_eq!(Some("1".to_string()), filter.addressed_text(&group_message("@cadastro_bot 1")));
+        assert_eq!(Some("/start".to_string()), filter.addressed_text(&group_message("/start@cadastro_bot")));
+        assert_eq!(Some("/menu".to_string()), filter.addressed_text(&group_message("/menu")));
+        assert_eq!(Some("Ana Silva".to_string()), filter.addressed_text(&reply));
+    }
+}
@@ -0,0 +1,112 @@
+pub mod context;
+
+use std::time::Instant;
+
+use prometheus::{Encoder, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, Opts, Registry, TextEncoder};
+
+use crate::state_machine::StateMachineErrors;
+
+const NAMESPACE: &str = "chatbot";
+
+/// Counters and histograms of the bot, rendered in the Prometheus text format by [`Metrics::render`].
+pub struct Metrics {
+    registry: Registry,
+    pub updates_received: IntCounter,
+    messages_sent: IntCounterVec,
+    transitions: IntCounterVec,
+    state_machine_errors: IntCounterVec,
+    pub registrations_created: IntCounter,
+    telegram_api_duration: HistogramVec,
+    pub active_sessions: IntGauge,
+}
+impl Metrics {
+    pub fn new() -> Self {
+        let updates_received = IntCounter::with_opts(Opts::new("updates_received_total", "Telegram updates received")
+            .namespace(NAMESPACE)).unwrap();
+        let messages_sent = IntCounterVec::new(Opts::new("messages_sent_total", "Messages sent, by result")
+            .namespace(NAMESPACE), &["result"]).unwrap();
+        let transitions = IntCounterVec::new(Opts::new("transitions_total", "State machine transitions, by source and target state")
+            .namespace(NAMESPACE), &["from", "to"]).unwrap();
+        let state_machine_errors = IntCounterVec::new(Opts::new("state_machine_errors_total", "State machine errors, by kind")
+            .namespace(NAMESPACE), &["kind"]).unwrap();
+        let registrations_created = IntCounter::with_opts(Opts::new("registrations_created_total", "Registrations created")
+            .namespace(NAMESPACE)).unwrap();
+        let telegram_api_duration = HistogramVec::new(HistogramOpts::new("telegram_api_duration_seconds", "Telegram API request latency, by method")
+            .namespace(NAMESPACE), &["method"]).unwrap();
+        let active_sessions = IntGauge::with_opts(Opts::new("active_sessions", "Sessions kept in the chat states")
+            .namespace(NAMESPACE)).unwrap();
+
+        let registry = Registry::new();
+        registry.register(Box::new(updates_received.clone())).unwrap();
+        registry.register(Box::new(messages_sent.clone())).unwrap();
+        registry.register(Box::new(transitions.clone())).unwrap();
+        registry.register(Box::new(state_machine_errors.clone())).unwrap();
+        registry.register(Box::new(registrations_created.clone())).unwrap();
+        registry.register(Box::new(telegram_api_duration.clone())).unwrap();
+        registry.register(Box::new(active_sessions.clone())).unwrap();
+
+        Self {
+            registry,
+            updates_received,
+            messages_sent,
+            transitions,
+            state_machine_errors,
+            registrations_created,
+            telegram_api_duration,
+            active_sessions,
+        }
+    }
+
+    pub fn message_sent(&self, delivered: bool) {
+        let result = if delivered { "ok" } else { "failed" };
+        self.messages_sent.with_label_values(&[result]).inc();
+    }
+
+    pub fn transition(&self, from: &str, to: &str) {
+        self.transitions.with_label_values(&[from, to]).inc();
+    }
+
+    pub fn state_machine_error(&self, error: &StateMachineErrors) {
+        let kind = match error {
+            StateMachineErrors::WrongTransition => "wrong_transition",
+            StateMachineErrors::StateNotFound => "state_not_found",
+            _ => "other",
+        };
+        self.state_machine_errors.with_label_values(&[kind]).inc();
+    }
+
+    /// Observes how long a Telegram API `method` call, started at `started`, took.
+    pub fn telegram_api_call(&self, method: &str, started: Instant) {
+        self.telegram_api_duration.with_label_values(&[method]).observe(started.elapsed().as_secs_f64());
+    }
+
+    pub fn render(&self) -> String {
+        let mut buffer = Vec::new();
+        TextEncoder::new().encode(&self.registry.gather(), &mut buffer).unwrap();
+        String::from_utf8(buffer).unwrap()
+    }
+}
+
+#[cfg(test)]
+mod metrics_tests {
+    use super::*;
+
+    #[test]
+    fn metrics_should_render_counters_by_label() {
+        let metrics = Metrics::new();
+        metrics.updates_received.inc();
+        metrics.message_sent(true);
+        metrics.message_sent(false);
+        metrics.transition("menu", "register-name");
+        metrics.state_machine_error(&StateMachineErrors::WrongTransition);
+        metrics.active_sessions.set(3);
+
+        let rendered = metrics.render();
+
+        assert!(rendered.contains("chatbot_updates_received_total 1"));
+        assert!(rendered.contains("chatbot_messages_sent_total{result=\"failed\"} 1"));
+        assert!(rendered.contains("chatbot_transitions_total{from=\"menu\",to=\"register-name\"} 1"));
+        assert!(rendered.contains("chatbot_state_machine_errors_total{kind=\"wrong_transition\"} 1"));
+        assert!(rendered.contains("chatbot_active_sessions 3"));
+    }
+}
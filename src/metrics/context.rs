@@ -0,0 +1,16 @@
+use std::sync::Arc;
+
+use super::*;
+
+pub struct MetricsContext {
+    pub metrics: Arc<Metrics>,
+}
+impl MetricsContext {
+    pub fn build() -> Self {
+        let metrics = Arc::new(Metrics::new());
+
+        Self {
+            metrics,
+        }
+    }
+}
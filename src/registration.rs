@@ -1,17 +1,27 @@
-use std::{sync::Arc, cell::RefCell};
+use std::{rc::Rc, sync::Arc, cell::RefCell, io};
 
 use chrono::{Utc, DateTime};
 use mockall::automock;
+use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
+use crate::metrics::Metrics;
+
 mod registrations;
 pub mod context;
 
-#[derive(Clone)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct Registration {
     pub id: String,
     pub name: String,
     pub phone: String,
+    /// Chat the registration was made from, used to reach the user later.
+    pub chat_id: Option<String>,
+    /// Telegram user who made the registration, when known.
+    pub user: Option<RegisteredUser>,
+    /// Message that confirmed the registration, so confirming it again doesn't register twice.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub message_key: Option<String>,
     pub created_on: DateTime<Utc>,
 }
 impl Registration {
@@ -20,47 +30,120 @@ impl Registration {
             id: Uuid::new_v4().to_string(), 
             name: String::from(name),
             phone: String::from(phone),
+            chat_id: None,
+            user: None,
+            message_key: None,
             created_on: Utc::now(),
         }
     }
+
+    pub fn with_chat_id(mut self, chat_id: &str) -> Self {
+        self.chat_id = Some(String::from(chat_id));
+        self
+    }
+
+    pub fn with_user(mut self, user: RegisteredUser) -> Self {
+        self.user = Some(user);
+        self
+    }
+}
+
+/// Sender of a registration, deserializable from the state machine `context.user`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct RegisteredUser {
+    pub id: i64,
+    pub username: Option<String>,
+    pub first_name: String,
+    pub last_name: Option<String>,
 }
 
 #[derive(Debug)]
 pub enum RegistrationManagerError {
     DuplicatedRegistration,    
+    RegistrationNotFound,
+    /// The registrations file couldn't be written.
+    Storage(io::Error),
 }
 
 #[automock]
 pub trait RegistrationManager {
-    fn add(&mut self, name: &str, phone: &str) -> Result<(), RegistrationManagerError>;
+    /// Adds a registration; a registration already confirmed by `message_key` isn't added again.
+    fn add(&mut self, name: &str, phone: &str, chat_id: Option<String>, user: Option<RegisteredUser>, message_key: Option<String>) -> Result<(), RegistrationManagerError>;
     fn get_all_registrations(&self) -> Vec<Registration>;
+    fn delete(&mut self, id: &str) -> Result<(), RegistrationManagerError>;
+    /// Adds a registration exported elsewhere, keeping its id and creation date.
+    fn import(&mut self, registration: Registration) -> Result<(), RegistrationManagerError>;
+    /// Whether the backing storage can be written, for readiness checks.
+    fn check(&self) -> Result<(), String> {
+        Ok(())
+    }
 }
 
 struct RegistrationManagerImpl {
-    registrations: Arc<RefCell<dyn Registrations>>,
+    registrations: Rc<RefCell<dyn Registrations>>,
+    metrics: Option<Arc<Metrics>>,
 }
 impl RegistrationManagerImpl {
-    fn new(registrations: Arc<RefCell<dyn Registrations>>) -> Self {
+    fn new(registrations: Rc<RefCell<dyn Registrations>>) -> Self {
         Self {
             registrations,
+            metrics: None,
         }
     }
+
+    fn with_metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
 }
 impl RegistrationManager for RegistrationManagerImpl {
-    fn add(&mut self, name: &str, phone: &str) -> Result<(), RegistrationManagerError> {
-        let registration = Registration::new(name, phone);        
-        self.registrations.borrow_mut().add(registration);
+    fn add(&mut self, name: &str, phone: &str, chat_id: Option<String>, user: Option<RegisteredUser>, message_key: Option<String>) -> Result<(), RegistrationManagerError> {
+        if message_key.is_some() && self.registrations.borrow().all_registrations().iter().any(|r| r.message_key == message_key) {
+            return Ok(());
+        }
+        let mut registration = Registration::new(name, phone);        
+        registration.chat_id = chat_id;
+        registration.user = user;
+        registration.message_key = message_key;
+        self.registrations.borrow_mut().add(registration).map_err(RegistrationManagerError::Storage)?;
+        if let Some(metrics) = &self.metrics {
+            metrics.registrations_created.inc();
+        }
         Ok(())
     }
 
     fn get_all_registrations(&self) -> Vec<Registration> {
         self.registrations.borrow().all_registrations()
     }
+
+    fn delete(&mut self, id: &str) -> Result<(), RegistrationManagerError> {
+        if self.registrations.borrow_mut().remove(id).map_err(RegistrationManagerError::Storage)? {
+            Ok(())
+        } else {
+            Err(RegistrationManagerError::RegistrationNotFound)
+        }
+    }
+
+    fn import(&mut self, registration: Registration) -> Result<(), RegistrationManagerError> {
+        let mut registrations = self.registrations.borrow_mut();
+        if registrations.all_registrations().iter().any(|r| r.id == registration.id) {
+            return Err(RegistrationManagerError::DuplicatedRegistration);
+        }
+        registrations.add(registration).map_err(RegistrationManagerError::Storage)
+    }
+
+    fn check(&self) -> Result<(), String> {
+        self.registrations.borrow().check()
+    }
 }
 
 trait Registrations {
     fn all_registrations(&self) -> Vec<Registration>;
-    fn add(&mut self, registration: Registration);    
+    fn add(&mut self, registration: Registration) -> io::Result<()>;
+    fn remove(&mut self, id: &str) -> io::Result<bool>;
+    fn check(&self) -> Result<(), String> {
+        Ok(())
+    }
 }
 
 #[cfg(test)]
@@ -82,14 +165,56 @@ mod registration_tests {
 
     #[test]
     fn registration_manager_impl_should_add_new_register() -> Result<(), RegistrationManagerError> {
-        let mut registration_manager = RegistrationManagerImpl::new(Arc::new(RefCell::new(RegistrationsInMemory::new())));
+        let mut registration_manager = RegistrationManagerImpl::new(Rc::new(RefCell::new(RegistrationsInMemory::new())));
         let name = "Fulano de Tal";
         let phone = "+5541123";
         
-        registration_manager.add(name, phone)?;
+        let user = RegisteredUser { id: 222000, username: Some("fulano".to_string()), first_name: "Fulano".to_string(), last_name: None };
+        
+        registration_manager.add(name, phone, Some("111000".to_string()), Some(user.clone()), None)?;
 
         let all_registrations: Vec<Registration> = registration_manager.get_all_registrations();
         assert_eq!(1, all_registrations.len());
+        assert_eq!(Some("111000".to_string()), all_registrations[0].chat_id);
+        assert_eq!(Some(user), all_registrations[0].user);
+        Ok(())
+    }
+
+    #[test]
+    fn registration_manager_impl_should_add_once_per_message() -> Result<(), RegistrationManagerError> {
+        let mut registration_manager = RegistrationManagerImpl::new(Rc::new(RefCell::new(RegistrationsInMemory::new())));
+
+        registration_manager.add("Fulano de Tal", "+5541123", None, None, Some("111000:7".to_string()))?;
+        registration_manager.add("Fulano de Tal", "+5541123", None, None, Some("111000:7".to_string()))?;
+        registration_manager.add("Fulano de Tal", "+5541123", None, None, Some("111000:9".to_string()))?;
+
+        let message_keys: Vec<Option<String>> = registration_manager.get_all_registrations().into_iter().map(|r| r.message_key).collect();
+        assert_eq!(vec![Some("111000:7".to_string()), Some("111000:9".to_string())], message_keys);
+        Ok(())
+    }
+
+    #[test]
+    fn registration_manager_impl_should_delete_register() -> Result<(), RegistrationManagerError> {
+        let mut registration_manager = RegistrationManagerImpl::new(Rc::new(RefCell::new(RegistrationsInMemory::new())));
+        registration_manager.add("Fulano de Tal", "+5541123", None, None, None)?;
+        let id = registration_manager.get_all_registrations()[0].id.clone();
+
+        registration_manager.delete(&id)?;
+
+        assert!(registration_manager.get_all_registrations().is_empty());
+        assert!(matches!(registration_manager.delete(&id), Err(RegistrationManagerError::RegistrationNotFound)));
+        Ok(())
+    }
+
+    #[test]
+    fn registration_manager_impl_should_import_registrations_once() -> Result<(), RegistrationManagerError> {
+        let mut registration_manager = RegistrationManagerImpl::new(Rc::new(RefCell::new(RegistrationsInMemory::new())));
+        let registration = Registration::new("Fulano de Tal", "+5541123").with_chat_id("111000");
+
+        registration_manager.import(registration.clone())?;
+
+        assert_eq!(vec![registration.clone()], registration_manager.get_all_registrations());
+        assert!(matches!(registration_manager.import(registration), Err(RegistrationManagerError::DuplicatedRegistration)));
         Ok(())
     }
 }
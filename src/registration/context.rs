@@ -1,27 +1,33 @@
-use std::{sync::Arc, cell::RefCell};
+use std::{rc::Rc, sync::Arc, cell::RefCell};
 
-use super::{RegistrationManager, Registrations, registrations::RegistrationsInMemory, RegistrationManagerImpl};
+use crate::{config::{Config, ConfigError}, metrics::Metrics};
+
+use super::{RegistrationManager, Registrations, registrations::{RegistrationsInMemory, RegistrationsJsonFile}, RegistrationManagerImpl};
 
 pub struct RegistrationContext {
-    pub registration_manager: Arc<RefCell<dyn RegistrationManager>>,
-    registrations: Arc<RefCell<dyn Registrations>>,
+    pub registration_manager: Rc<RefCell<dyn RegistrationManager>>,
+    registrations: Rc<RefCell<dyn Registrations>>,
 }
 impl RegistrationContext {
-    pub fn build() -> Self {
-        let registrations = Arc::new(RefCell::new(Self::build_registrations()));
-        let registration_manager = Arc::new(RefCell::new(Self::build_registration_manager(registrations.clone())));
+    pub fn build(config: &Config, metrics: Arc<Metrics>) -> Result<Self, ConfigError> {
+        let registrations = Self::build_registrations(config)?;
+        let registration_manager = Rc::new(RefCell::new(Self::build_registration_manager(registrations.clone(), metrics)));
 
-        Self {
+        Ok(Self {
             registration_manager,
             registrations,
-        }
+        })
     }
 
-    fn build_registration_manager(registrations: Arc<RefCell<dyn Registrations>>) -> impl RegistrationManager {
-        RegistrationManagerImpl::new(registrations)
+    fn build_registration_manager(registrations: Rc<RefCell<dyn Registrations>>, metrics: Arc<Metrics>) -> impl RegistrationManager {
+        RegistrationManagerImpl::new(registrations).with_metrics(metrics)
     }
 
-    fn build_registrations() -> impl Registrations {
-        RegistrationsInMemory::new()
+    fn build_registrations(config: &Config) -> Result<Rc<RefCell<dyn Registrations>>, ConfigError> {
+        Ok(match &config.storage.registrations_file {
+            Some(path) => Rc::new(RefCell::new(RegistrationsJsonFile::new(path)
+                .map_err(|error| ConfigError::storage(path, error))?)),
+            None => Rc::new(RefCell::new(RegistrationsInMemory::new())),
+        })
     }
 }
@@ -1,3 +1,7 @@
+use std::{io, path::PathBuf};
+
+use crate::storage::{check_json, load_json, save_json};
+
 use super::{Registrations, Registration};
 
 pub struct RegistrationsInMemory {
@@ -13,13 +17,70 @@ impl Registrations for RegistrationsInMemory {
         self.registrations.clone()
     }
 
-    fn add(&mut self, registration: Registration) {
+    fn add(&mut self, registration: Registration) -> io::Result<()> {
         self.registrations.push(registration);
+        Ok(())
+    }
+
+    fn remove(&mut self, id: &str) -> io::Result<bool> {
+        let count = self.registrations.len();
+        self.registrations.retain(|r| r.id != id);
+        Ok(self.registrations.len() < count)
+    }
+}
+
+/// Keeps every registration in a single JSON file, rewritten on each change.
+pub struct RegistrationsJsonFile {
+    path: PathBuf,
+    registrations: Vec<Registration>,
+    save_error: Option<String>,
+}
+impl RegistrationsJsonFile {
+    pub fn new(path: &str) -> io::Result<Self> {
+        let path = PathBuf::from(path);
+        let registrations = load_json(&path)?;
+        Ok(Self {
+            path,
+            registrations,
+            save_error: None,
+        })
+    }
+
+    fn save(&mut self) -> io::Result<()> {
+        let saved = save_json(&self.path, &self.registrations);
+        self.save_error = saved.as_ref().err().map(|error| error.to_string());
+        saved
+    }
+}
+impl Registrations for RegistrationsJsonFile {
+    fn check(&self) -> Result<(), String> {
+        check_json(&self.path, self.save_error.as_ref())
+    }
+
+    fn all_registrations(&self) -> Vec<Registration> {
+        self.registrations.clone()
+    }
+
+    fn add(&mut self, registration: Registration) -> io::Result<()> {
+        self.registrations.push(registration);
+        self.save()
+    }
+
+    fn remove(&mut self, id: &str) -> io::Result<bool> {
+        let count = self.registrations.len();
+        self.registrations.retain(|r| r.id != id);
+        let removed = self.registrations.len() < count;
+        if removed {
+            self.save()?;
+        }
+        Ok(removed)
     }
 }
 
 #[cfg(test)]
 mod registrations_tests {
+    use std::fs;
+
     use super::*;
 
     #[test]
@@ -28,9 +89,9 @@ mod registrations_tests {
         let registration_01 = Registration::new("Fulano One", "+55411");
         let registration_02 = Registration::new("Fulano Two", "+55412");
         let registration_03 = Registration::new("Fulano Three", "+55413");
-        registrations.add(registration_01);
-        registrations.add(registration_02);
-        registrations.add(registration_03);
+        registrations.add(registration_01).unwrap();
+        registrations.add(registration_02).unwrap();
+        registrations.add(registration_03).unwrap();
 
         let vec = registrations.all_registrations();
 
@@ -39,4 +100,29 @@ mod registrations_tests {
         assert_eq!("Fulano Two", &vec[1].name);
         assert_eq!("Fulano Three", &vec[2].name);
     }
+
+    #[test]
+    fn registrations_json_file_should_load_saved_registrations() {
+        let path = std::env::temp_dir().join(format!("chat-registrations-{}.json", uuid::Uuid::new_v4()));
+        let path = path.to_str().unwrap();
+        let registration = Registration::new("Fulano One", "+55411").with_chat_id("111000");
+
+        RegistrationsJsonFile::new(path).unwrap().add(registration.clone()).unwrap();
+
+        assert_eq!(vec![registration.clone()], RegistrationsJsonFile::new(path).unwrap().all_registrations());
+        assert!(RegistrationsJsonFile::new(path).unwrap().remove(&registration.id).unwrap());
+        assert!(RegistrationsJsonFile::new(path).unwrap().all_registrations().is_empty());
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn registrations_json_file_should_return_save_errors() {
+        let path = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string()).join("registrations.json");
+        let mut registrations = RegistrationsJsonFile::new(path.to_str().unwrap()).unwrap();
+
+        let error = registrations.add(Registration::new("Fulano One", "+55411"));
+
+        assert_eq!(io::ErrorKind::NotFound, error.unwrap_err().kind());
+        assert!(registrations.check().is_err());
+    }
 }
@@ -0,0 +1,245 @@
+pub mod context;
+
+use std::{io, path::PathBuf};
+
+use chrono::{DateTime, Utc};
+use mockall::automock;
+use serde::{Deserialize, Serialize};
+
+use crate::storage::{check_json, load_json, save_json};
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum JobKind {
+    /// Sends a fixed text to the chat.
+    Message { text: String },
+    /// Follows the chat's current state timeout, if it expired meanwhile.
+    Timeout,
+    /// Sends one message of a broadcast, reporting its delivery.
+    Broadcast { broadcast_id: String, text: String },
+}
+
+/// Work to be done for a chat once `due_at` is reached. Jobs bound to a `state` are dropped
+/// when the chat has left that state by then.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ScheduledJob {
+    pub id: String,
+    pub chat_id: String,
+    pub due_at: DateTime<Utc>,
+    pub state: Option<String>,
+    pub kind: JobKind,
+}
+impl ScheduledJob {
+    pub fn new(chat_id: &str, due_at: DateTime<Utc>, kind: JobKind) -> Self {
+        Self {
+            id: uuid::Uuid::new_v4().to_string(),
+            chat_id: chat_id.to_string(),
+            due_at,
+            state: None,
+            kind,
+        }
+    }
+
+    pub fn for_state(mut self, state: &str) -> Self {
+        self.state = Some(state.to_string());
+        self
+    }
+}
+
+#[automock]
+pub trait Schedules {
+    fn add(&mut self, job: ScheduledJob) -> io::Result<()>;
+    /// Adds every job in one write, or none of them if the write fails.
+    fn add_all(&mut self, jobs: Vec<ScheduledJob>) -> io::Result<()>;
+    /// Removes and returns the jobs due at `now`, oldest first. The jobs are kept if they
+    /// can't be removed from the storage, so they are retried instead of lost.
+    fn take_due(&mut self, now: DateTime<Utc>) -> io::Result<Vec<ScheduledJob>>;
+    /// Removes the chat's jobs bound to a state, returning how many were removed.
+    fn cancel_state_jobs(&mut self, chat_id: &str) -> io::Result<usize>;
+    /// Whether the backing storage can be written, for readiness checks.
+    fn check(&self) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+pub struct SchedulesInMemory {
+    jobs: Vec<ScheduledJob>,
+}
+impl SchedulesInMemory {
+    pub fn new() -> Self {
+        Self {
+            jobs: Vec::new(),
+        }
+    }
+}
+impl Schedules for SchedulesInMemory {
+    fn add(&mut self, job: ScheduledJob) -> io::Result<()> {
+        self.jobs.push(job);
+        Ok(())
+    }
+
+    fn add_all(&mut self, jobs: Vec<ScheduledJob>) -> io::Result<()> {
+        self.jobs.extend(jobs);
+        Ok(())
+    }
+
+    fn take_due(&mut self, now: DateTime<Utc>) -> io::Result<Vec<ScheduledJob>> {
+        Ok(take_due(&mut self.jobs, now))
+    }
+
+    fn cancel_state_jobs(&mut self, chat_id: &str) -> io::Result<usize> {
+        Ok(cancel_state_jobs(&mut self.jobs, chat_id))
+    }
+}
+
+/// Keeps every scheduled job in a single JSON file, rewritten on each change, so jobs
+/// survive restarts.
+pub struct SchedulesJsonFile {
+    path: PathBuf,
+    jobs: Vec<ScheduledJob>,
+    save_error: Option<String>,
+}
+impl SchedulesJsonFile {
+    pub fn new(path: &str) -> io::Result<Self> {
+        let path = PathBuf::from(path);
+        let jobs = load_json(&path)?;
+        Ok(Self {
+            path,
+            jobs,
+            save_error: None,
+        })
+    }
+
+    fn save(&mut self) -> io::Result<()> {
+        let saved = save_json(&self.path, &self.jobs);
+        self.save_error = saved.as_ref().err().map(|error| error.to_string());
+        saved
+    }
+}
+impl Schedules for SchedulesJsonFile {
+    fn check(&self) -> Result<(), String> {
+        check_json(&self.path, self.save_error.as_ref())
+    }
+
+    fn add(&mut self, job: ScheduledJob) -> io::Result<()> {
+        self.jobs.push(job);
+        self.save()
+    }
+
+    fn add_all(&mut self, jobs: Vec<ScheduledJob>) -> io::Result<()> {
+        let count = self.jobs.len();
+        self.jobs.extend(jobs);
+        let saved = self.save();
+        if saved.is_err() {
+            self.jobs.truncate(count);
+        }
+        saved
+    }
+
+    fn take_due(&mut self, now: DateTime<Utc>) -> io::Result<Vec<ScheduledJob>> {
+        let due = take_due(&mut self.jobs, now);
+        if !due.is_empty() {
+            if let Err(error) = self.save() {
+                self.jobs.extend(due);
+                return Err(error);
+            }
+        }
+        Ok(due)
+    }
+
+    fn cancel_state_jobs(&mut self, chat_id: &str) -> io::Result<usize> {
+        let cancelled = cancel_state_jobs(&mut self.jobs, chat_id);
+        if cancelled > 0 {
+            self.save()?;
+        }
+        Ok(cancelled)
+    }
+}
+
+fn take_due(jobs: &mut Vec<ScheduledJob>, now: DateTime<Utc>) -> Vec<ScheduledJob> {
+    let (mut due, pending): (Vec<ScheduledJob>, Vec<ScheduledJob>) = jobs.drain(..).partition(|job| job.due_at <= now);
+    *jobs = pending;
+    due.sort_by_key(|job| job.due_at);
+    due
+}
+
+fn cancel_state_jobs(jobs: &mut Vec<ScheduledJob>, chat_id: &str) -> usize {
+    let count = jobs.len();
+    jobs.retain(|job| job.chat_id != chat_id || job.state.is_none());
+    count - jobs.len()
+}
+
+#[cfg(test)]
+mod scheduler_tests {
+    use std::fs;
+
+    use chrono::Duration;
+
+    use super::*;
+
+    fn message(text: &str) -> JobKind {
+        JobKind::Message { text: text.to_string() }
+    }
+
+    #[test]
+    fn schedules_should_take_only_due_jobs_in_order() {
+        let now = Utc::now();
+        let mut schedules = SchedulesInMemory::new();
+        schedules.add(ScheduledJob::new("1", now + Duration::minutes(1), message("later"))).unwrap();
+        schedules.add(ScheduledJob::new("1", now - Duration::minutes(1), message("second"))).unwrap();
+        schedules.add(ScheduledJob::new("2", now - Duration::minutes(2), message("first"))).unwrap();
+
+        let due = schedules.take_due(now).unwrap();
+
+        assert_eq!(vec![message("first"), message("second")], due.into_iter().map(|j| j.kind).collect::<Vec<_>>());
+        assert_eq!(1, schedules.take_due(now + Duration::minutes(1)).unwrap().len());
+    }
+
+    #[test]
+    fn schedules_should_cancel_state_jobs_of_chat() {
+        let now = Utc::now();
+        let mut schedules = SchedulesInMemory::new();
+        schedules.add(ScheduledJob::new("1", now, JobKind::Timeout).for_state("form")).unwrap();
+        schedules.add(ScheduledJob::new("1", now, message("follow-up"))).unwrap();
+        schedules.add(ScheduledJob::new("2", now, JobKind::Timeout).for_state("form")).unwrap();
+
+        let cancelled = schedules.cancel_state_jobs("1").unwrap();
+
+        assert_eq!(1, cancelled);
+        assert_eq!(2, schedules.take_due(now).unwrap().len());
+    }
+
+    #[test]
+    fn schedules_json_file_should_load_saved_jobs() {
+        let path = std::env::temp_dir().join(format!("chat-schedules-{}.json", uuid::Uuid::new_v4()));
+        let path = path.to_str().unwrap();
+        let now = Utc::now();
+        let mut schedules = SchedulesJsonFile::new(path).unwrap();
+
+        schedules.add(ScheduledJob::new("1", now, message("reminder")).for_state("form")).unwrap();
+        let loaded = SchedulesJsonFile::new(path).unwrap().take_due(now).unwrap();
+        fs::remove_file(path).unwrap();
+
+        assert_eq!(1, loaded.len());
+        assert_eq!(Some("form".to_string()), loaded[0].state);
+        assert_eq!(message("reminder"), loaded[0].kind);
+    }
+
+    #[test]
+    fn schedules_json_file_should_keep_due_jobs_when_save_fails() {
+        let directory = std::env::temp_dir().join(format!("chat-schedules-{}", uuid::Uuid::new_v4()));
+        fs::create_dir(&directory).unwrap();
+        let now = Utc::now();
+        let mut schedules = SchedulesJsonFile::new(directory.join("schedules.json").to_str().unwrap()).unwrap();
+        schedules.add(ScheduledJob::new("1", now, message("reminder"))).unwrap();
+        fs::remove_dir_all(&directory).unwrap();
+
+        assert!(schedules.take_due(now).is_err());
+        assert!(schedules.check().is_err());
+
+        fs::create_dir(&directory).unwrap();
+
+        assert_eq!(1, schedules.take_due(now).unwrap().len());
+        assert!(schedules.check().is_ok());
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
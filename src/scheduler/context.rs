@@ -0,0 +1,26 @@
+use std::{rc::Rc, cell::RefCell};
+
+use crate::config::{Config, ConfigError};
+
+use super::*;
+
+pub struct SchedulerContext {
+    pub schedules: Rc<RefCell<dyn Schedules>>,
+}
+impl SchedulerContext {
+    pub fn build(config: &Config) -> Result<Self, ConfigError> {
+        let schedules = Self::build_schedules(config)?;
+
+        Ok(Self {
+            schedules,
+        })
+    }
+
+    fn build_schedules(config: &Config) -> Result<Rc<RefCell<dyn Schedules>>, ConfigError> {
+        Ok(match &config.storage.schedules_file {
+            Some(path) => Rc::new(RefCell::new(SchedulesJsonFile::new(path)
+                .map_err(|error| ConfigError::storage(path, error))?)),
+            None => Rc::new(RefCell::new(SchedulesInMemory::new())),
+        })
+    }
+}
@@ -1,22 +1,112 @@
-use std::{collections::HashMap};
+use std::{collections::HashMap, time::Duration};
 
-use self::transitions::EmptyTransitionOutput;
+use serde_json::{Map, Value};
+
+use self::transitions::{EmptyTransitionAction, EmptyTransitionOutput};
 
 pub mod transitions;
+pub mod transition_rules;
+pub mod template;
 pub mod state_output;
 pub mod form_states;
+pub mod validation;
+pub mod graph;
 mod state_machine_tests;
 
+/// Data kept by a state machine between transitions, stored as JSON values so flows can
+/// keep numbers, lists and nested objects.
+pub type StateData = Map<String, Value>;
+
+/// Key under which the read-only context set with [`StateMachine::set_context`] is visible to
+/// rules, actions and outputs.
+pub const CONTEXT_KEY: &str = "context";
+
+/// Outputs of a transition followed by the output of the state it leads to.
+pub type TransitionOutputs = (Option<String>, Option<String>);
+
 pub trait TransitionRule {
-    fn test(&self, data: &mut HashMap<String, String>, action: &str) -> bool;
+    fn test(&self, data: &StateData, action: &str) -> bool;
+
+    /// How well `action` matches this rule, from 0.0 to 1.0, or `None` when it doesn't match.
+    /// States take the best-scoring transition among the matching ones.
+    fn score(&self, data: &StateData, action: &str) -> Option<f64> {
+        if self.test(data, action) {
+            Some(1.0)
+        } else {
+            None
+        }
+    }
+
+    /// Describes what this rule accepts, so tooling can list valid options without running it.
+    fn describe(&self) -> Option<RuleDescription> {
+        None
+    }
+}
+
+pub trait TransitionAction {
+    fn execute(&self, data: &mut StateData, action: &str) -> Result<(), StateMachineErrors>;
 }
 
 pub trait TransitionOutput {
-    fn generate_output(&self, data: &mut HashMap<String, String>, action: &str) -> Option<String>;
+    fn generate_output(&self, data: &StateData, action: &str) -> Result<Option<String>, StateMachineErrors>;
+
+    fn describe(&self) -> Option<String> {
+        None
+    }
 }
 
 pub trait StateOutput {
-    fn generate_output(&self, data: &mut HashMap<String, String>) -> Option<String>;
+    fn generate_output(&self, data: &StateData) -> Result<Option<String>, StateMachineErrors>;
+
+    fn describe(&self) -> Option<String> {
+        None
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RuleKind {
+    Exact,
+    Default,
+    Field,
+    Pattern,
+    Range,
+    Command,
+    Intent,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RuleDescription {
+    pub kind: RuleKind,
+    pub expected_input: Option<String>,
+    pub label: Option<String>,
+}
+impl RuleDescription {
+    pub fn new(kind: RuleKind) -> Self {
+        Self {
+            kind,
+            expected_input: None,
+            label: None,
+        }
+    }
+
+    pub fn with_expected_input(mut self, expected_input: &str) -> Self {
+        self.expected_input = Some(String::from(expected_input));
+        self
+    }
+
+    pub fn with_label(mut self, label: Option<&str>) -> Self {
+        self.label = label.map(String::from);
+        self
+    }
+
+    /// Text shown to users for this option, e.g. `1 (Novo registro)`.
+    fn option_text(&self) -> Option<String> {
+        match (&self.expected_input, &self.label) {
+            (Some(input), Some(label)) => Some(format!("{} ({})", input, label)),
+            (Some(input), None) => Some(String::from(input)),
+            (None, _) => None,
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -24,12 +114,133 @@ pub enum StateMachineErrors {
     StateNotFound,
     InitialStateNotSet,
     WrongTransition,
+    ActionFailed(String),
+    OutputFailed(String),
+}
+
+/// A transition to `target` taken when `rule` matches. Among matching transitions the state
+/// takes the highest priority, then the highest rule score, then the one added first.
+pub struct Transition {
+    target: String,
+    rule: Box<dyn TransitionRule>,
+    action: Box<dyn TransitionAction>,
+    output: Box<dyn TransitionOutput>,
+    priority: i32,
+}
+impl Transition {
+    pub fn new<TR>(target: &str, rule: TR) -> Self
+    where TR: TransitionRule + 'static {
+        Self {
+            target: String::from(target),
+            rule: Box::new(rule),
+            action: Box::new(EmptyTransitionAction::new()),
+            output: Box::new(EmptyTransitionOutput::new()),
+            priority: 0,
+        }
+    }
+
+    pub fn with_action<TA>(mut self, action: TA) -> Self
+    where TA: TransitionAction + 'static {
+        self.action = Box::new(action);
+        self
+    }
+
+    pub fn with_output<TO>(mut self, output: TO) -> Self
+    where TO: TransitionOutput + 'static {
+        self.output = Box::new(output);
+        self
+    }
+
+    pub fn with_priority(mut self, priority: i32) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    fn is_default(&self) -> bool {
+        matches!(self.rule.describe(), Some(RuleDescription { kind: RuleKind::Default, .. }))
+    }
+
+    fn apply(&self, data: &mut StateData, action: &str) -> Result<(String, Option<String>), StateMachineErrors> {
+        self.action.execute(data, action)?;
+        Ok((String::from(&self.target), self.output.generate_output(data, action)?))
+    }
+}
+
+/// Answered from any state without leaving it, such as a report for admins. The action runs
+/// over a copy of the data that only feeds the output, so whatever it adds isn't kept and a
+/// form in progress is left as it was.
+pub struct Command {
+    rule: Box<dyn TransitionRule>,
+    action: Box<dyn TransitionAction>,
+    output: Box<dyn TransitionOutput>,
+}
+impl Command {
+    pub fn new<TR>(rule: TR) -> Self
+    where TR: TransitionRule + 'static {
+        Self {
+            rule: Box::new(rule),
+            action: Box::new(EmptyTransitionAction::new()),
+            output: Box::new(EmptyTransitionOutput::new()),
+        }
+    }
+
+    pub fn with_action<TA>(mut self, action: TA) -> Self
+    where TA: TransitionAction + 'static {
+        self.action = Box::new(action);
+        self
+    }
+
+    pub fn with_output<TO>(mut self, output: TO) -> Self
+    where TO: TransitionOutput + 'static {
+        self.output = Box::new(output);
+        self
+    }
+}
+
+/// Leaves a state for `target` once the chat stays idle in it for `after`, e.g. to drop a
+/// half-filled form. The output, if any, is meant to tell the user what happened.
+pub struct Timeout {
+    after: Duration,
+    target: String,
+    action: Box<dyn TransitionAction>,
+    output: Box<dyn TransitionOutput>,
+}
+impl Timeout {
+    pub fn new(after: Duration, target: &str) -> Self {
+        Self {
+            after,
+            target: String::from(target),
+            action: Box::new(EmptyTransitionAction::new()),
+            output: Box::new(EmptyTransitionOutput::new()),
+        }
+    }
+
+    pub fn with_action<TA>(mut self, action: TA) -> Self
+    where TA: TransitionAction + 'static {
+        self.action = Box::new(action);
+        self
+    }
+
+    pub fn with_output<TO>(mut self, output: TO) -> Self
+    where TO: TransitionOutput + 'static {
+        self.output = Box::new(output);
+        self
+    }
+}
+
+/// A message for chats still in the state `after` entering it, such as a reminder to finish
+/// a form. Scheduling and delivering it is up to the caller.
+struct Reminder {
+    after: Duration,
+    output: Box<dyn StateOutput>,
 }
 
 pub struct State {
     pub name: String,    
-    transitions: Vec<(String, Box<dyn TransitionRule>, Box<dyn TransitionOutput>)>,
+    transitions: Vec<Transition>,
     output: Option<Box<dyn StateOutput>>,    
+    timeout: Option<Timeout>,
+    reminders: Vec<Reminder>,
 }
 impl State {
     pub fn new(name: &str) -> Self {
@@ -37,17 +248,33 @@ impl State {
             name: String::from(name),
             transitions: Vec::new(),
             output: None,
+            timeout: None,
+            reminders: Vec::new(),
          }
     }
     
+    pub fn add(&mut self, transition: Transition) {
+        self.transitions.push(transition);
+    }
+
     pub fn add_transition<TR> (&mut self, target: &str, rule: TR)
     where TR: TransitionRule + 'static {
-        self.add_transition_with_output(target, rule, EmptyTransitionOutput::new());
+        self.add(Transition::new(target, rule));
     }
 
     pub fn add_transition_with_output<TR, TO>(&mut self, target: &str, rule: TR, output: TO)
     where TR: TransitionRule + 'static, TO: TransitionOutput + 'static {
-        self.transitions.push((String::from(target), Box::new(rule), Box::new(output)));
+        self.add(Transition::new(target, rule).with_output(output));
+    }
+
+    pub fn add_transition_with_action<TR, TA>(&mut self, target: &str, rule: TR, action: TA)
+    where TR: TransitionRule + 'static, TA: TransitionAction + 'static {
+        self.add(Transition::new(target, rule).with_action(action));
+    }
+
+    pub fn add_transition_with_action_and_output<TR, TA, TO>(&mut self, target: &str, rule: TR, action: TA, output: TO)
+    where TR: TransitionRule + 'static, TA: TransitionAction + 'static, TO: TransitionOutput + 'static {
+        self.add(Transition::new(target, rule).with_action(action).with_output(output));
     }
 
     pub fn set_output<O>(&mut self, output: O)
@@ -55,21 +282,61 @@ impl State {
         self.output = Some(Box::new(output));
     }
 
-    pub fn generate_output(&self, data: &mut HashMap<String, String>) -> Option<String> {
+    pub fn set_timeout(&mut self, timeout: Timeout) {
+        self.timeout = Some(timeout);
+    }
+
+    pub fn add_reminder<O>(&mut self, after: Duration, output: O)
+    where O: StateOutput + 'static {
+        self.reminders.push(Reminder { after, output: Box::new(output) });
+    }
+
+    pub fn generate_output(&self, data: &StateData) -> Result<Option<String>, StateMachineErrors> {
         match &self.output {
-            None => None,
+            None => Ok(None),
             Some(state_output) => state_output.generate_output(data)
         }
     }
 
-    pub fn transition(&self, data: &mut HashMap<String, String>, action: &str) -> Option<(String, Option<String>)> {        
-        for (target, rule, output) in &self.transitions {
-            if rule.test(data, action) {
-                return Some((String::from(target), output.generate_output(data, action)));
+    /// Lists the inputs accepted by this state's transitions, as described by their rules.
+    pub fn valid_options(&self) -> Vec<String> {
+        transitions_options(&self.transitions)
+    }
+
+    /// Finds the matching transition for `action` with the highest priority and score (the
+    /// first one added wins ties), runs its action over `data` and returns the target state
+    /// name with the transition output.
+    pub fn transition(&self, data: &mut StateData, action: &str) -> Result<Option<(String, Option<String>)>, StateMachineErrors> {
+        match select_transition(&self.transitions, data, action) {
+            Some(transition) => Ok(Some(transition.apply(data, action)?)),
+            None => Ok(None),
+        }
+    }
+}
+
+fn select_transition<'a>(transitions: &'a [Transition], data: &StateData, action: &str) -> Option<&'a Transition> {
+    let mut best: Option<(&Transition, f64)> = None;
+    for transition in transitions {
+        if let Some(score) = transition.rule.score(data, action) {
+            let better = best.is_none_or(|(best_transition, best_score)| {
+                (transition.priority, score) > (best_transition.priority, best_score)
+            });
+            if better {
+                best = Some((transition, score));
             }
         }
-        None
     }
+    best.map(|(t, _)| t)
+}
+
+fn transitions_options(transitions: &[Transition]) -> Vec<String> {
+    let mut options: Vec<String> = Vec::new();
+    for option in transitions.iter().filter_map(|t| t.rule.describe()?.option_text()) {
+        if !options.contains(&option) {
+            options.push(option);
+        }
+    }
+    options
 }
 
 pub struct StateMachine
@@ -77,23 +344,78 @@ pub struct StateMachine
     states: HashMap<String, State>,    
     initial_state_name: Option<String>,
     current_state: Option<String>,
-    state_data: HashMap<String, String>,
+    state_data: StateData,
+    wrong_transition_message: String,
+    global_transitions: Vec<Transition>,
+    global_commands: Vec<Command>,
+    help_rule: Option<Box<dyn TransitionRule>>,
+    context: Option<Value>,
 }
 impl StateMachine
 {
-    pub fn new(state_data: HashMap<String, String>) -> Self {
+    pub fn new(state_data: StateData) -> Self {
         Self { 
             states: HashMap::new(),
             initial_state_name: None,
             current_state: None,
-            state_data: state_data,
+            state_data,
+            wrong_transition_message: String::from("valid options are: "),
+            global_transitions: Vec::new(),
+            global_commands: Vec::new(),
+            help_rule: None,
+            context: None,
+        }
+    }
+
+    /// Sets information about the conversation, such as the user and chat, that rules, actions
+    /// and outputs read from the [`CONTEXT_KEY`] entry of the data. It's restored after each
+    /// action and never kept in the state data, so actions can't change it.
+    pub fn set_context(&mut self, context: Value) {
+        self.context = Some(context);
+    }
+
+    /// State data with the context added, as seen while running transitions.
+    fn data_with_context(&self) -> StateData {
+        let mut data = self.state_data.clone();
+        self.restore_context(&mut data);
+        data
+    }
+
+    fn restore_context(&self, data: &mut StateData) {
+        if let Some(context) = &self.context {
+            data.insert(String::from(CONTEXT_KEY), context.clone());
         }
     }
 
+    fn commit_data(&mut self, mut data: StateData) {
+        if self.context.is_some() {
+            data.remove(CONTEXT_KEY);
+        }
+        self.state_data = data;
+    }
+
     pub fn add_state(&mut self, state: State) {
         self.states.insert(state.name.clone(), state);
     }
 
+    /// Adds a transition available from any state, checked before the current state's own
+    /// transitions. Useful for commands like `/start` or `/cancel`.
+    pub fn add_global_transition(&mut self, transition: Transition) {
+        self.global_transitions.push(transition);
+    }
+
+    /// Adds a command answered from any state, checked before the global transitions.
+    pub fn add_global_command(&mut self, command: Command) {
+        self.global_commands.push(command);
+    }
+
+    /// Sets the rule recognizing help requests, answered from any state with
+    /// [`StateMachine::help`] without leaving the current state.
+    pub fn set_help_rule<TR>(&mut self, rule: TR)
+    where TR: TransitionRule + 'static {
+        self.help_rule = Some(Box::new(rule));
+    }
+
     fn get_states(&self) -> &HashMap<String, State> {
         &self.states
     }
@@ -126,32 +448,144 @@ impl StateMachine
         self.current_state.clone()
     }
 
+    /// Sets the timeout of a state already added, such as the ones created by
+    /// [`form_states::FormStates`].
+    pub fn set_state_timeout(&mut self, state_name: &str, timeout: Timeout) -> Result<(), StateMachineErrors> {
+        let state = self.states.get_mut(state_name).ok_or(StateMachineErrors::StateNotFound)?;
+        state.set_timeout(timeout);
+        Ok(())
+    }
+
+    /// Adds a reminder to a state already added, such as the ones created by
+    /// [`form_states::FormStates`].
+    pub fn add_state_reminder<O>(&mut self, state_name: &str, after: Duration, output: O) -> Result<(), StateMachineErrors>
+    where O: StateOutput + 'static {
+        let state = self.states.get_mut(state_name).ok_or(StateMachineErrors::StateNotFound)?;
+        state.add_reminder(after, output);
+        Ok(())
+    }
+
+    /// Timeout duration of the current state, if it has one.
+    pub fn current_timeout(&self) -> Option<Duration> {
+        let current_state = self.states.get(self.current_state.as_ref()?)?;
+        current_state.timeout.as_ref().map(|timeout| timeout.after)
+    }
+
+    /// Reminders of the current state with their delays, rendered with the current data.
+    pub fn current_reminders(&self) -> Result<Vec<(Duration, String)>, StateMachineErrors> {
+        let current_state = self.current_state.as_ref()
+            .and_then(|name| self.states.get(name))
+            .ok_or(StateMachineErrors::InitialStateNotSet)?;
+        let mut reminders = Vec::new();
+        for reminder in &current_state.reminders {
+            if let Some(text) = reminder.output.generate_output(&self.data_with_context())? {
+                reminders.push((reminder.after, text));
+            }
+        }
+        Ok(reminders)
+    }
+
+    /// Follows the current state's timeout when the chat has been idle for at least its
+    /// duration, returning the timeout output and the new state output. Returns `None` when
+    /// the current state has no timeout or it hasn't expired yet.
+    pub fn timeout_state(&mut self, idle: Duration) -> Result<Option<TransitionOutputs>, StateMachineErrors> {
+        let current_state = self.current_state.as_ref()
+            .and_then(|name| self.states.get(name))
+            .ok_or(StateMachineErrors::InitialStateNotSet)?;
+        let timeout = match &current_state.timeout {
+            Some(timeout) if idle >= timeout.after => timeout,
+            _ => return Ok(None),
+        };
+        let new_state = self.states.get(&timeout.target).ok_or(StateMachineErrors::StateNotFound)?;
+
+        let mut data = self.data_with_context();
+        timeout.action.execute(&mut data, "")?;
+        self.restore_context(&mut data);
+        let timeout_output = timeout.output.generate_output(&data, "")?;
+        let state_output = new_state.generate_output(&data)?;
+        self.current_state = Some(String::from(&timeout.target));
+        self.commit_data(data);
+        Ok(Some((timeout_output, state_output)))
+    }
+
+    /// Runs `action` against the current state. The target state is resolved before the
+    /// transition action runs, so side effects don't happen for transitions that can't complete.
+    /// Data changes made by transition actions are applied to a copy and only committed when the
+    /// whole transition succeeds.
     pub fn transition_state(&mut self, action: &str) -> Result<(Option<String>, Option<String>), StateMachineErrors> {    
-        let current_state_name = match &self.current_state {
+        let current_state = match &self.current_state {
             Some(s) => self.states.get(s),
             None => return Err(StateMachineErrors::InitialStateNotSet),
         };
 
-        let current_state = match current_state_name {
+        let current_state = match current_state {
             Some(s) => s,
             None => return Err(StateMachineErrors::InitialStateNotSet),
         };
         
-        let new_state_name = current_state.transition(&mut self.state_data, action);
-        
-        if let Some((n, transition_output)) = new_state_name {
-            if !self.states.contains_key(&n) {
-                return Err(StateMachineErrors::StateNotFound)
+        let mut data = self.data_with_context();
+        if self.help_rule.as_ref().is_some_and(|r| r.test(&data, action)) {
+            return Ok((self.help(), None));
+        }
+        if let Some(command) = self.global_commands.iter().find(|c| c.rule.test(&data, action)) {
+            command.action.execute(&mut data, action)?;
+            self.restore_context(&mut data);
+            return Ok((command.output.generate_output(&data, action)?, None));
+        }
+
+        let transition = select_transition(&self.global_transitions, &data, action)
+            .or_else(|| select_transition(&current_state.transitions, &data, action))
+            .ok_or(StateMachineErrors::WrongTransition)?;
+        let new_state_name = transition.target.clone();
+        let new_state = self.states.get(&new_state_name).ok_or(StateMachineErrors::StateNotFound)?;
+
+        transition.action.execute(&mut data, action)?;
+        self.restore_context(&mut data);
+        let transition_output = transition.output.generate_output(&data, action)?;
+        let state_output = new_state.generate_output(&data)?;
+        self.commit_data(data);
+        self.current_state = Some(new_state_name);
+        Ok((transition_output, state_output))
+    }
+
+    /// Sets the text preceding the valid options in [`StateMachine::wrong_transition_help`].
+    pub fn set_wrong_transition_message(&mut self, message: &str) {
+        self.wrong_transition_message = String::from(message);
+    }
+
+    /// Help text listing the current state's valid options and the global commands, meant to
+    /// be shown after a `WrongTransition`. Returns `None` when no rule describes its inputs.
+    pub fn wrong_transition_help(&self) -> Option<String> {
+        let current_state = self.states.get(self.current_state.as_ref()?)?;
+        let mut options = current_state.valid_options();
+        let command_options = self.global_commands.iter().filter_map(|c| c.rule.describe()?.option_text());
+        for option in transitions_options(&self.global_transitions).into_iter().chain(command_options) {
+            if !options.contains(&option) {
+                options.push(option);
             }
-            let state_output = self.states.get(&n).unwrap().generate_output(&mut self.state_data);
-            self.current_state = Some(n);            
-            return Ok((transition_output, state_output));
-        };
-        
-        Err(StateMachineErrors::WrongTransition)
+        }
+        if let Some(option) = self.help_rule.as_ref().and_then(|r| r.describe()?.option_text()) {
+            options.push(option);
+        }
+        if options.is_empty() {
+            return None;
+        }
+        Some(format!("{}{}", &self.wrong_transition_message, options.join(", ")))
+    }
+
+    /// Context help for the current state: its output (e.g. the pending question) followed by
+    /// the valid options.
+    pub fn help(&self) -> Option<String> {
+        let state_output = self.current_state.as_ref()
+            .and_then(|name| self.states.get(name))
+            .and_then(|state| state.generate_output(&self.data_with_context()).ok().flatten());
+        match (state_output, self.wrong_transition_help()) {
+            (Some(output), Some(options)) => Some(format!("{}\n\n{}", output, options)),
+            (output, options) => output.or(options),
+        }
     }
 
-    pub fn get_state_data(&self) -> &HashMap<String, String> {
-        return &self.state_data;
+    pub fn get_state_data(&self) -> &StateData {
+        &self.state_data
     }
 }
@@ -1,12 +1,29 @@
-use crate::state_machine::transitions::FnTransitionRule;
+use serde_json::{Number, Value};
+use tracing::debug;
 
-use super::{State, StateMachine, state_output::FixedStateOutput};
+use super::{RuleDescription, RuleKind, State, StateData, StateMachine, StateMachineErrors, TransitionAction, TransitionRule, state_output::FixedStateOutput};
 
 
+#[derive(Clone, Copy)]
 pub enum FieldType {
     String,
     Number,
 }
+impl FieldType {
+    fn parse(&self, action: &str) -> Option<Value> {
+        match self {
+            Self::String => Some(Value::String(action.to_string())),
+            Self::Number => action.trim().parse::<Number>().ok().map(Value::Number),
+        }
+    }
+
+    fn label(&self) -> &str {
+        match self {
+            Self::String => "text",
+            Self::Number => "number",
+        }
+    }
+}
 pub enum FieldOption {
     Optional,
     Required,
@@ -14,6 +31,32 @@ pub enum FieldOption {
 
 struct Field(String, String, FieldType, FieldOption);
 
+struct FieldTransitionRule {
+    field_type: FieldType,
+}
+impl TransitionRule for FieldTransitionRule {
+    fn test(&self, _data: &StateData, action: &str) -> bool {
+        self.field_type.parse(action).is_some()
+    }
+
+    fn describe(&self) -> Option<RuleDescription> {
+        Some(RuleDescription::new(RuleKind::Field).with_label(Some(self.field_type.label())))
+    }
+}
+
+struct FieldTransitionAction {
+    key: String,
+    field_type: FieldType,
+}
+impl TransitionAction for FieldTransitionAction {
+    fn execute(&self, data: &mut StateData, action: &str) -> Result<(), StateMachineErrors> {
+        let value = self.field_type.parse(action)
+            .ok_or_else(|| StateMachineErrors::ActionFailed(format!("invalid value for {}", &self.key)))?;
+        data.insert(self.key.to_string(), value);
+        Ok(())
+    }
+}
+
 pub struct FormStates {
     states_prefix: String,
     fields: Vec<Field>,
@@ -40,15 +83,15 @@ impl FormStates {
     }
 
     fn apply_form_field_state(&self, field: &Field, next_state: &str, previous_fields: &[Field], state_machine: &mut StateMachine) {
-        let Field(field_name, label, field_type, field_option) = field;
-        let state_name = self.states_prefix.to_owned() + &field_name;
+        let Field(field_name, label, field_type, _field_option) = field;
+        let state_name = self.states_prefix.to_owned() + field_name;
         let mut state = State::new(&state_name);
-        state.set_output(FixedStateOutput::new(&label));
-        let field_data_key = state_name.to_string();
-        state.add_transition(next_state, FnTransitionRule::new(move |data, action| {
-            data.insert(field_data_key.to_string(), action.to_string());
-            true
-        }));
+        state.set_output(FixedStateOutput::new(label));
+        state.add_transition_with_action(
+            next_state,
+            FieldTransitionRule { field_type: *field_type },
+            FieldTransitionAction { key: state_name.to_string(), field_type: *field_type },
+        );
         state_machine.add_state(state);
 
         if !previous_fields.is_empty() {
@@ -60,20 +103,18 @@ impl FormStates {
             );
         }        
 
-        println!("add {} -> {} ({})", &state_name, next_state, previous_fields.len());
+        debug!(state = %state_name, next_state, previous_fields = previous_fields.len(), "add form field state");
     }
 }
 
 #[cfg(test)]
 mod form_states_test {
-    use std::collections::HashMap;
-
-    use crate::state_machine::{transitions::{EqTransitionRule, DefaultTransitionRule}, state_output::{FixedStateOutput}};
+    use crate::state_machine::{transitions::{EqTransitionRule, DefaultTransitionRule}, state_output::FixedStateOutput};
 
     use super::*;
 
     fn build_basic_state_machine(form_state: &str) -> StateMachine {
-        let mut state_machine = StateMachine::new(HashMap::new());        
+        let mut state_machine = StateMachine::new(StateData::new());        
         let mut state = State::new("initial");
         state.add_transition(form_state, EqTransitionRule::new("1"));
         state_machine.add_state(state);
@@ -146,6 +187,21 @@ mod form_states_test {
 
         assert_eq!("John", data.get("register-first-name").unwrap());
         assert_eq!("Smith", data.get("register-last-name").unwrap());
-        assert_eq!("30", data.get("register-age").unwrap());
+        assert_eq!(30, data.get("register-age").unwrap().as_i64().unwrap());
+    }
+
+    #[test]
+    fn form_states_should_reject_invalid_number_fields() {
+        let mut state_machine = build_basic_state_machine("register-age");
+        let mut form_states = FormStates::new("register-");
+        form_states.add_field("age", "Age?", FieldType::Number, FieldOption::Optional);
+        form_states.apply_states("register-created-state", &mut state_machine);
+
+        state_machine.transition_state("1").unwrap();
+        let result = state_machine.transition_state("thirty");
+
+        assert!(matches!(result, Err(StateMachineErrors::WrongTransition)));
+        assert_eq!("register-age", state_machine.get_current_state().unwrap());
+        assert!(!state_machine.get_state_data().contains_key("register-age"));
     }
 }
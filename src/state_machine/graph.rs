@@ -0,0 +1,234 @@
+use super::{RuleKind, State, StateMachine, Timeout, Transition, validation::GLOBAL_STATE_NAME};
+
+impl StateMachine {
+    /// Exports the states graph in Graphviz DOT format.
+    pub fn to_dot(&self) -> String {
+        let mut output = String::from("digraph state_machine {\n");
+        for state in self.sorted_states() {
+            let shape = if self.is_initial_state(state) { "doublecircle" } else { "box" };
+            let label = match state.output.as_ref().and_then(|o| o.describe()) {
+                Some(text) => format!("{}\n{}", &state.name, text),
+                None => state.name.to_string(),
+            };
+            output.push_str(&format!("    \"{}\" [shape={}, label=\"{}\"];\n", escape_dot(&state.name), shape, escape_dot(&label)));
+        }
+        for state in self.sorted_states() {
+            for transition in &state.transitions {
+                let style = if transition.is_default() { ", style=dashed" } else { "" };
+                output.push_str(&format!("    \"{}\" -> \"{}\" [label=\"{}\"{}];\n",
+                    escape_dot(&state.name),
+                    escape_dot(&transition.target),
+                    escape_dot(&transition_label(transition)),
+                    style,
+                ));
+            }
+            if let Some(timeout) = &state.timeout {
+                output.push_str(&format!("    \"{}\" -> \"{}\" [label=\"{}\", style=dashed, color=gray];\n",
+                    escape_dot(&state.name),
+                    escape_dot(&timeout.target),
+                    escape_dot(&timeout_label(timeout)),
+                ));
+            }
+        }
+        if !self.global_transitions.is_empty() {
+            output.push_str(&format!("    \"{}\" [shape=plaintext];\n", GLOBAL_STATE_NAME));
+        }
+        for transition in &self.global_transitions {
+            output.push_str(&format!("    \"{}\" -> \"{}\" [label=\"{}\", style=dotted];\n",
+                GLOBAL_STATE_NAME,
+                escape_dot(&transition.target),
+                escape_dot(&transition_label(transition)),
+            ));
+        }
+        output.push_str("}\n");
+        output
+    }
+
+    /// Exports the states graph as a Mermaid state diagram. Targets that aren't states get
+    /// their own node too, so each dangling transition shows where it points.
+    pub fn to_mermaid(&self) -> String {
+        let states = self.sorted_states();
+        let mut unknown_targets: Vec<&str> = states.iter()
+            .flat_map(|s| s.transitions.iter().map(|t| t.target.as_str()).chain(s.timeout.iter().map(|t| t.target.as_str())))
+            .chain(self.global_transitions.iter().map(|t| t.target.as_str()))
+            .filter(|target| !self.states.contains_key(*target))
+            .collect();
+        unknown_targets.sort();
+        unknown_targets.dedup();
+        let names: Vec<&str> = states.iter().map(|s| s.name.as_str()).chain(unknown_targets.iter().copied()).collect();
+        let id = |name: &str| format!("s{}", names.iter().position(|n| *n == name).unwrap_or_default());
+        let mut output = String::from("stateDiagram-v2\n");
+        for state in &states {
+            output.push_str(&format!("    state \"{}\" as {}\n", escape_mermaid(&state.name), id(&state.name)));
+            if let Some(text) = state.output.as_ref().and_then(|o| o.describe()) {
+                for line in text.lines() {
+                    output.push_str(&format!("    {} : {}\n", id(&state.name), escape_mermaid(line)));
+                }
+            }
+        }
+        for target in &unknown_targets {
+            output.push_str(&format!("    state \"{}\" as {}\n", escape_mermaid(target), id(target)));
+        }
+        for state in &states {
+            if self.is_initial_state(state) {
+                output.push_str(&format!("    [*] --> {}\n", id(&state.name)));
+            }
+        }
+        for state in &states {
+            for transition in &state.transitions {
+                let label = transition_label(transition);
+                if label.is_empty() {
+                    output.push_str(&format!("    {} --> {}\n", id(&state.name), id(&transition.target)));
+                } else {
+                    output.push_str(&format!("    {} --> {} : {}\n", id(&state.name), id(&transition.target), escape_mermaid(&label)));
+                }
+            }
+            if let Some(timeout) = &state.timeout {
+                output.push_str(&format!("    {} --> {} : {}\n", id(&state.name), id(&timeout.target), escape_mermaid(&timeout_label(timeout))));
+            }
+        }
+        if !self.global_transitions.is_empty() {
+            output.push_str(&format!("    state \"{}\" as global\n", GLOBAL_STATE_NAME));
+        }
+        for transition in &self.global_transitions {
+            output.push_str(&format!("    global --> {} : {}\n", id(&transition.target), escape_mermaid(&transition_label(transition))));
+        }
+        output
+    }
+
+    fn sorted_states(&self) -> Vec<&State> {
+        let mut states: Vec<&State> = self.states.values().collect();
+        states.sort_by(|a, b| a.name.cmp(&b.name));
+        states
+    }
+
+    fn is_initial_state(&self, state: &State) -> bool {
+        self.initial_state_name.as_deref() == Some(state.name.as_str())
+    }
+}
+
+fn transition_label(transition: &Transition) -> String {
+    let rule_label = match transition.rule.describe() {
+        Some(description) if description.kind == RuleKind::Default => String::from("*"),
+        Some(description) => description.expected_input.or(description.label).unwrap_or_default(),
+        None => String::new(),
+    };
+    match transition.output.describe() {
+        Some(output) => format!("{} / {}", rule_label, output),
+        None => rule_label,
+    }
+}
+
+fn timeout_label(timeout: &Timeout) -> String {
+    let label = format!("timeout {}s", timeout.after.as_secs());
+    match timeout.output.describe() {
+        Some(output) => format!("{} / {}", label, output),
+        None => label,
+    }
+}
+
+fn escape_dot(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+fn escape_mermaid(text: &str) -> String {
+    text.replace('"', "#quot;").replace('\n', " ")
+}
+
+#[cfg(test)]
+mod graph_tests {
+    use crate::state_machine::{StateData, transitions::{EqTransitionRule, DefaultTransitionRule, FixedTransitionOutput}, state_output::FixedStateOutput};
+
+    use super::*;
+
+    fn build_state_machine() -> StateMachine {
+        let mut state_machine = StateMachine::new(StateData::new());
+        let mut state = State::new("menu");
+        state.set_output(FixedStateOutput::new("1: \"New\"\n2: List"));
+        state.add_transition("register-name", EqTransitionRule::new("1"));
+        state.add_transition_with_output("menu", DefaultTransitionRule::new(), FixedTransitionOutput::new("Invalid!"));
+        state_machine.add_state(state);
+        let mut state = State::new("register-name");
+        state.add_transition("menu", DefaultTransitionRule::new());
+        state_machine.add_state(state);
+        state_machine.set_initial_state_name("menu").unwrap();
+        state_machine
+    }
+
+    #[test]
+    fn to_dot_should_export_states_and_transitions() {
+        let state_machine = build_state_machine();
+
+        let dot = state_machine.to_dot();
+
+        assert_eq!(concat!(
+            "digraph state_machine {\n",
+            "    \"menu\" [shape=doublecircle, label=\"menu\\n1: \\\"New\\\"\\n2: List\"];\n",
+            "    \"register-name\" [shape=box, label=\"register-name\"];\n",
+            "    \"menu\" -> \"register-name\" [label=\"1\"];\n",
+            "    \"menu\" -> \"menu\" [label=\"* / Invalid!\", style=dashed];\n",
+            "    \"register-name\" -> \"menu\" [label=\"*\", style=dashed];\n",
+            "}\n",
+        ), dot);
+    }
+
+    #[test]
+    fn to_mermaid_should_export_states_and_transitions() {
+        let state_machine = build_state_machine();
+
+        let mermaid = state_machine.to_mermaid();
+
+        assert_eq!(concat!(
+            "stateDiagram-v2\n",
+            "    state \"menu\" as s0\n",
+            "    s0 : 1: #quot;New#quot;\n",
+            "    s0 : 2: List\n",
+            "    state \"register-name\" as s1\n",
+            "    [*] --> s0\n",
+            "    s0 --> s1 : 1\n",
+            "    s0 --> s0 : * / Invalid!\n",
+            "    s1 --> s0 : *\n",
+        ), mermaid);
+    }
+
+    #[test]
+    fn graph_should_export_global_transitions() {
+        let mut state_machine = build_state_machine();
+        state_machine.add_global_transition(Transition::new("menu", EqTransitionRule::new("/menu")));
+
+        let dot = state_machine.to_dot();
+        let mermaid = state_machine.to_mermaid();
+
+        assert!(dot.ends_with("    \"*\" [shape=plaintext];\n    \"*\" -> \"menu\" [label=\"/menu\", style=dotted];\n}\n"));
+        assert!(mermaid.ends_with("    state \"*\" as global\n    global --> s0 : /menu\n"));
+    }
+
+    #[test]
+    fn to_mermaid_should_give_each_unknown_target_its_own_id() {
+        let mut state_machine = build_state_machine();
+        state_machine.add_global_transition(Transition::new("missing-b", EqTransitionRule::new("/b")));
+        state_machine.set_state_timeout("register-name", Timeout::new(std::time::Duration::from_secs(60), "missing-a")).unwrap();
+        let mut state = State::new("other");
+        state.add_transition("missing-a", EqTransitionRule::new("a"));
+        state_machine.add_state(state);
+
+        let mermaid = state_machine.to_mermaid();
+
+        assert!(mermaid.contains("    state \"missing-a\" as s3\n    state \"missing-b\" as s4\n"));
+        assert!(mermaid.contains("    s1 --> s3 : a\n"));
+        assert!(mermaid.contains("    s2 --> s3 : timeout 60s\n"));
+        assert!(mermaid.ends_with("    global --> s4 : /b\n"));
+    }
+
+    #[test]
+    fn graph_should_export_state_timeouts() {
+        let mut state_machine = build_state_machine();
+        state_machine.set_state_timeout("register-name", Timeout::new(std::time::Duration::from_secs(60), "menu")).unwrap();
+
+        let dot = state_machine.to_dot();
+        let mermaid = state_machine.to_mermaid();
+
+        assert!(dot.contains("    \"register-name\" -> \"menu\" [label=\"timeout 60s\", style=dashed, color=gray];\n"));
+        assert!(mermaid.ends_with("    s1 --> s0 : *\n    s1 --> s0 : timeout 60s\n"));
+    }
+}
@@ -2,7 +2,10 @@
 mod tests {
     use super::super::*;
     use super::super::transitions::*;
+    use super::super::transition_rules::*;
     use super::super::state_output::*;
+    use super::super::template::*;
+    use serde_json::Value;
 
     #[test]
     fn state_should_have_name() {
@@ -15,16 +18,16 @@ mod tests {
 
     #[test]
     fn state_should_transition_to_right_state() { 
-        let mut data = HashMap::new();
+        let mut data = StateData::new();
         let mut state: State = State::new("base");        
         let transition_rule_1 = FnTransitionRule::new(|_data,action|action == "1");
         let transition_rule_2 = FnTransitionRule::new(|_data,action|action == "2");
         state.add_transition("one", transition_rule_1);
         state.add_transition("two", transition_rule_2);
 
-        let new_state_1 = state.transition(&mut data, "1");
-        let new_state_2 = state.transition(&mut data, "2");
-        let new_state_3 = state.transition(&mut data, "3");
+        let new_state_1 = state.transition(&mut data, "1").unwrap();
+        let new_state_2 = state.transition(&mut data, "2").unwrap();
+        let new_state_3 = state.transition(&mut data, "3").unwrap();
         
         assert_eq!("one", new_state_1.as_ref().unwrap().0);
         assert_eq!("two", new_state_2.as_ref().unwrap().0);
@@ -42,21 +45,21 @@ mod tests {
 
     #[test]
     fn state_should_have_optional_output() {
-        let mut data = HashMap::new();
+        let data = StateData::new();
         let name = "state 1";
         let mut state: State = State::new(name);
         let state_output = FixedStateOutput::new("hello there!");
         state.set_output(state_output);
         
-        let output: Option<String> = state.generate_output(&mut data);
+        let output: Option<String> = state.generate_output(&data).unwrap();
 
-        assert_eq!(true, output.is_some());
+        assert!(output.is_some());
         assert_eq!("hello there!", output.as_ref().unwrap());
     }
 
     #[test]
     fn state_machine_should_receive_states() {
-        let mut state_machine: StateMachine = StateMachine::new(HashMap::new());
+        let mut state_machine: StateMachine = StateMachine::new(StateData::new());
         let name_1 = "state 1";
         let name_2 = "state 2";
 
@@ -68,7 +71,7 @@ mod tests {
 
     #[test]
     fn state_machine_should_return_state_by_name() {
-        let mut state_machine: StateMachine = StateMachine::new(HashMap::new());
+        let mut state_machine: StateMachine = StateMachine::new(StateData::new());
         let name_1 = "state 1";
         let name_2 = "state 2";
         state_machine.add_state(State::new(name_1));
@@ -83,7 +86,7 @@ mod tests {
 
     #[test]
     fn state_machine_should_have_initial_state() -> Result<(), StateMachineErrors> {
-        let mut state_machine: StateMachine = StateMachine::new(HashMap::new());
+        let mut state_machine: StateMachine = StateMachine::new(StateData::new());
         let name_1 = "state 1";
         let name_2 = "state 2";
         state_machine.add_state(State::new(name_1));
@@ -97,7 +100,7 @@ mod tests {
 
     #[test]
     fn state_machine_should_transition_state() -> Result<(), StateMachineErrors> {
-        let mut state_machine: StateMachine = StateMachine::new(HashMap::new());
+        let mut state_machine: StateMachine = StateMachine::new(StateData::new());
         let name_1 = "state 1";
         let name_2 = "state 2";
         let mut state_1 = State::new(name_1);
@@ -116,7 +119,7 @@ mod tests {
 
     #[test]
     fn state_machine_should_transition_back_state() -> Result<(), StateMachineErrors> {
-        let mut state_machine: StateMachine = StateMachine::new(HashMap::new());
+        let mut state_machine: StateMachine = StateMachine::new(StateData::new());
         let name_1 = "state 1";
         let name_2 = "state 2";
         let mut state_1 = State::new(name_1);
@@ -136,7 +139,7 @@ mod tests {
 
     #[test]
     fn state_machine_should_transition_with_state_output() -> Result<(), StateMachineErrors> {
-        let mut state_machine: StateMachine = StateMachine::new(HashMap::new());
+        let mut state_machine: StateMachine = StateMachine::new(StateData::new());
         let name_1 = "state 1";
         let name_2 = "state 2";
         let mut state_1 = State::new(name_1);
@@ -151,9 +154,347 @@ mod tests {
         let (_transition_output_01, state_output_01) = state_machine.transition_state("hi")?;
         let (_transition_output_02, state_output_02) = state_machine.transition_state("hi")?;
 
-        assert_eq!(true, state_output_01.is_some());
+        assert!(state_output_01.is_some());
         assert_eq!("fixed value", state_output_01.unwrap());
-        assert_eq!(true, state_output_02.is_none());
+        assert!(state_output_02.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn state_should_run_action_only_for_matching_transition() -> Result<(), StateMachineErrors> {
+        let mut data = StateData::new();
+        let mut state: State = State::new("base");
+        state.add_transition_with_action("one", EqTransitionRule::new("1"), StoreTransitionAction::new("one"));
+        state.add_transition_with_action("two", DefaultTransitionRule::new(), StoreTransitionAction::new("two"));
+
+        state.transition(&mut data, "2")?;
+
+        assert!(!data.contains_key("one"));
+        assert_eq!("2", data.get("two").unwrap());
+        Ok(())
+    }
+
+    #[test]
+    fn state_machine_should_commit_action_data_changes() -> Result<(), StateMachineErrors> {
+        let mut state_machine: StateMachine = StateMachine::new(StateData::new());
+        let mut state_1 = State::new("state 1");
+        state_1.add_transition_with_action("state 2", DefaultTransitionRule::new(), StoreTransitionAction::new("name"));
+        state_machine.add_state(state_1);
+        state_machine.add_state(State::new("state 2"));
+        state_machine.set_initial_state_name("state 1")?;
+
+        state_machine.transition_state("John")?;
+
+        assert_eq!("John", state_machine.get_state_data().get("name").unwrap());
+        Ok(())
+    }
+
+    #[test]
+    fn state_machine_should_rollback_data_when_action_fails() -> Result<(), StateMachineErrors> {
+        let mut state_machine: StateMachine = StateMachine::new(StateData::from_iter([("name".to_string(), Value::from("John"))]));
+        let mut state_1 = State::new("state 1");
+        state_1.add_transition_with_action("state 2", DefaultTransitionRule::new(), FnTransitionAction::new(|data, action| {
+            data.insert("name".to_string(), Value::from(action));
+            Err(StateMachineErrors::ActionFailed("failed".to_string()))
+        }));
+        state_machine.add_state(state_1);
+        state_machine.add_state(State::new("state 2"));
+        state_machine.set_initial_state_name("state 1")?;
+
+        let result = state_machine.transition_state("Mary");
+
+        assert!(matches!(result, Err(StateMachineErrors::ActionFailed(_))));
+        assert_eq!("John", state_machine.get_state_data().get("name").unwrap());
+        assert_eq!("state 1", state_machine.get_current_state().unwrap());
+        Ok(())
+    }
+
+    #[test]
+    fn state_machine_should_rollback_data_when_target_state_is_not_found() -> Result<(), StateMachineErrors> {
+        let mut state_machine: StateMachine = StateMachine::new(StateData::new());
+        let mut state_1 = State::new("state 1");
+        state_1.add_transition_with_action("missing", DefaultTransitionRule::new(), StoreTransitionAction::new("name"));
+        state_machine.add_state(state_1);
+        state_machine.set_initial_state_name("state 1")?;
+
+        let result = state_machine.transition_state("John");
+
+        assert!(matches!(result, Err(StateMachineErrors::StateNotFound)));
+        assert!(!state_machine.get_state_data().contains_key("name"));
+        Ok(())
+    }
+
+    #[test]
+    fn state_machine_should_not_run_action_when_target_state_is_not_found() -> Result<(), StateMachineErrors> {
+        let executed = std::rc::Rc::new(std::cell::Cell::new(false));
+        let executed_by_action = executed.clone();
+        let mut state_machine: StateMachine = StateMachine::new(StateData::new());
+        let mut state_1 = State::new("state 1");
+        state_1.add_transition_with_action("missing", DefaultTransitionRule::new(), FnTransitionAction::new(move |_data, _action| {
+            executed_by_action.set(true);
+            Ok(())
+        }));
+        state_machine.add_state(state_1);
+        state_machine.set_initial_state_name("state 1")?;
+
+        let result = state_machine.transition_state("John");
+
+        assert!(matches!(result, Err(StateMachineErrors::StateNotFound)));
+        assert!(!executed.get());
+        Ok(())
+    }
+
+    #[test]
+    fn state_should_list_valid_options_from_rule_descriptions() {
+        let mut state: State = State::new("base");
+        state.add_transition("one", EqTransitionRule::new("1").with_label("first"));
+        state.add_transition("two", EqTransitionRule::new("2"));
+        state.add_transition("two", EqTransitionRule::new("2"));
+        state.add_transition("any", FnTransitionRule::new(|_data, action| action == "3"));
+        state.add_transition("base", DefaultTransitionRule::new());
+
+        assert_eq!(vec!["1 (first)", "2"], state.valid_options());
+    }
+
+    #[test]
+    fn state_machine_should_generate_wrong_transition_help() -> Result<(), StateMachineErrors> {
+        let mut state_machine: StateMachine = StateMachine::new(StateData::new());
+        let mut state_1 = State::new("state 1");
+        state_1.add_transition("state 1", EqTransitionRule::new("sim"));
+        state_1.add_transition("state 1", EqTransitionRule::new("não"));
+        state_machine.add_state(state_1);
+        state_machine.set_initial_state_name("state 1")?;
+        state_machine.set_wrong_transition_message("Opções válidas: ");
+
+        assert_eq!("Opções válidas: sim, não", state_machine.wrong_transition_help().unwrap());
+        Ok(())
+    }
+
+    #[test]
+    fn state_should_pick_best_scoring_transition() -> Result<(), StateMachineErrors> {
+        let mut data = StateData::new();
+        let mut state: State = State::new("base");
+        state.add_transition("fallback", DefaultTransitionRule::new());
+        state.add_transition("new", IntentTransitionRule::new(&["novo"]));
+        state.add_transition("list", IntentTransitionRule::new(&["listar", "lista"]));
+
+        let new_state_1 = state.transition(&mut data, "nvo")?;
+        let new_state_2 = state.transition(&mut data, "lista")?;
+        let new_state_3 = state.transition(&mut data, "olá")?;
+
+        assert_eq!("new", new_state_1.unwrap().0);
+        assert_eq!("list", new_state_2.unwrap().0);
+        assert_eq!("fallback", new_state_3.unwrap().0);
+        Ok(())
+    }
+
+    #[test]
+    fn state_should_pick_highest_priority_transition() -> Result<(), StateMachineErrors> {
+        let mut data = StateData::new();
+        let mut state: State = State::new("base");
+        state.add_transition("exact", EqTransitionRule::new("cancelar"));
+        state.add(Transition::new("command", IntentTransitionRule::new(&["cancelar"])).with_priority(1));
+        state.add(Transition::new("fallback", DefaultTransitionRule::new()).with_priority(-1));
+
+        let new_state_1 = state.transition(&mut data, "cancelar")?;
+        let new_state_2 = state.transition(&mut data, "olá")?;
+
+        assert_eq!("command", new_state_1.unwrap().0);
+        assert_eq!("fallback", new_state_2.unwrap().0);
+        Ok(())
+    }
+
+    #[test]
+    fn state_should_break_ties_by_insertion_order() -> Result<(), StateMachineErrors> {
+        let mut data = StateData::new();
+        let mut state: State = State::new("base");
+        state.add_transition("first", EqTransitionRule::new("1"));
+        state.add_transition("second", FnTransitionRule::new(|_data, action| action == "1"));
+
+        let new_state = state.transition(&mut data, "1")?;
+
+        assert_eq!("first", new_state.unwrap().0);
+        Ok(())
+    }
+
+    #[test]
+    fn state_should_not_be_shadowed_by_default_transition_added_first() -> Result<(), StateMachineErrors> {
+        let mut data = StateData::new();
+        let mut state: State = State::new("base");
+        state.add_transition("fallback", DefaultTransitionRule::new());
+        state.add_transition("one", EqTransitionRule::new("1"));
+
+        let new_state = state.transition(&mut data, "1")?;
+
+        assert_eq!("one", new_state.unwrap().0);
+        Ok(())
+    }
+
+    #[test]
+    fn state_machine_should_rollback_data_when_output_fails() -> Result<(), StateMachineErrors> {
+        let mut state_machine: StateMachine = StateMachine::new(StateData::new());
+        let mut state_1 = State::new("state 1");
+        state_1.add_transition_with_action("state 2", DefaultTransitionRule::new(), StoreTransitionAction::new("name"));
+        state_machine.add_state(state_1);
+        let mut state_2 = State::new("state 2");
+        state_2.set_output(TemplateStateOutput::new("{{name}} {{phone}}")?);
+        state_machine.add_state(state_2);
+        state_machine.set_initial_state_name("state 1")?;
+
+        let result = state_machine.transition_state("John");
+
+        assert!(matches!(result, Err(StateMachineErrors::OutputFailed(_))));
+        assert!(!state_machine.get_state_data().contains_key("name"));
+        assert_eq!("state 1", state_machine.get_current_state().unwrap());
+        Ok(())
+    }
+
+    fn build_state_machine_with_global_commands() -> Result<StateMachine, StateMachineErrors> {
+        let mut state_machine: StateMachine = StateMachine::new(StateData::new());
+        let mut menu = State::new("menu");
+        menu.set_output(FixedStateOutput::new("1: form"));
+        menu.add_transition("form", EqTransitionRule::new("1"));
+        state_machine.add_state(menu);
+        let mut form = State::new("form");
+        form.set_output(FixedStateOutput::new("Name?"));
+        form.add_transition_with_action("menu", DefaultTransitionRule::new(), StoreTransitionAction::new("name"));
+        state_machine.add_state(form);
+        state_machine.add_global_transition(Transition::new("menu", CommandTransitionRule::new("cancel")).with_action(ClearDataTransitionAction::new()));
+        state_machine.set_help_rule(CommandTransitionRule::new("help"));
+        state_machine.set_initial_state_name("menu")?;
+        Ok(state_machine)
+    }
+
+    #[test]
+    fn state_machine_should_run_global_transitions_before_state_transitions() -> Result<(), StateMachineErrors> {
+        let mut state_machine = build_state_machine_with_global_commands()?;
+
+        state_machine.transition_state("1")?;
+        let (_, state_output) = state_machine.transition_state("/cancel")?;
+
+        assert_eq!("menu", state_machine.get_current_state().unwrap());
+        assert_eq!("1: form", state_output.unwrap());
+        assert!(!state_machine.get_state_data().contains_key("name"));
+        Ok(())
+    }
+
+    #[test]
+    fn state_machine_should_answer_help_without_changing_state() -> Result<(), StateMachineErrors> {
+        let mut state_machine = build_state_machine_with_global_commands()?;
+
+        state_machine.transition_state("1")?;
+        let (transition_output, state_output) = state_machine.transition_state("/help")?;
+
+        assert_eq!("form", state_machine.get_current_state().unwrap());
+        assert_eq!("Name?\n\nvalid options are: /cancel, /help", transition_output.unwrap());
+        assert!(state_output.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn state_machine_should_answer_global_commands_without_changing_state_or_data() -> Result<(), StateMachineErrors> {
+        let mut state_machine = build_state_machine_with_global_commands()?;
+        state_machine.add_global_command(Command::new(CommandTransitionRule::new("count"))
+            .with_action(FnTransitionAction::new(|data, _action| {
+                data.insert(String::from("count"), Value::from(42));
+                Ok(())
+            }))
+            .with_output(TemplateTransitionOutput::new("count: {{count}}")?)
+        );
+
+        state_machine.transition_state("1")?;
+        let (transition_output, state_output) = state_machine.transition_state("/count")?;
+
+        assert_eq!("form", state_machine.get_current_state().unwrap());
+        assert_eq!("count: 42", transition_output.unwrap());
+        assert!(state_output.is_none());
+        assert!(!state_machine.get_state_data().contains_key("count"));
+        Ok(())
+    }
+
+    fn build_state_machine_with_timeout() -> Result<StateMachine, StateMachineErrors> {
+        let mut state_machine = StateMachine::new(StateData::new());
+        let mut menu = State::new("menu");
+        menu.set_output(FixedStateOutput::new("1: form"));
+        menu.add_transition("form", EqTransitionRule::new("1"));
+        state_machine.add_state(menu);
+        let mut form = State::new("form");
+        form.add_transition_with_action("form", DefaultTransitionRule::new(), StoreTransitionAction::new("name"));
+        form.set_timeout(
+            Timeout::new(std::time::Duration::from_secs(60), "menu")
+                .with_action(ClearDataTransitionAction::new())
+                .with_output(FixedTransitionOutput::new("Still there?"))
+        );
+        state_machine.add_state(form);
+        state_machine.set_initial_state_name("menu")?;
+        Ok(state_machine)
+    }
+
+    #[test]
+    fn state_machine_should_follow_expired_state_timeout() -> Result<(), StateMachineErrors> {
+        let mut state_machine = build_state_machine_with_timeout()?;
+        state_machine.transition_state("1")?;
+        state_machine.transition_state("Fulano")?;
+
+        let outputs = state_machine.timeout_state(std::time::Duration::from_secs(61))?;
+
+        assert_eq!(Some((Some("Still there?".to_string()), Some("1: form".to_string()))), outputs);
+        assert_eq!("menu", state_machine.get_current_state().unwrap());
+        assert!(state_machine.get_state_data().is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn state_machine_should_keep_state_before_timeout() -> Result<(), StateMachineErrors> {
+        let mut state_machine = build_state_machine_with_timeout()?;
+        state_machine.transition_state("1")?;
+
+        let form_outputs = state_machine.timeout_state(std::time::Duration::from_secs(59))?;
+        state_machine.set_current_state("menu")?;
+        let menu_outputs = state_machine.timeout_state(std::time::Duration::from_secs(3600))?;
+
+        assert_eq!(None, form_outputs);
+        assert_eq!(None, menu_outputs);
+        Ok(())
+    }
+
+    #[test]
+    fn state_machine_should_render_current_state_reminders() -> Result<(), StateMachineErrors> {
+        let mut state_machine = build_state_machine_with_timeout()?;
+        state_machine.add_state_reminder("form", std::time::Duration::from_secs(30), TemplateStateOutput::new("Hi {{name | default \"there\"}}, still there?")?)?;
+
+        let menu_reminders = state_machine.current_reminders()?;
+        state_machine.transition_state("1")?;
+        let form_reminders = state_machine.current_reminders()?;
+
+        assert!(menu_reminders.is_empty());
+        assert_eq!(vec![(std::time::Duration::from_secs(30), "Hi there, still there?".to_string())], form_reminders);
+        assert_eq!(Some(std::time::Duration::from_secs(60)), state_machine.current_timeout());
+        Ok(())
+    }
+
+    #[test]
+    fn state_machine_should_expose_read_only_context() -> Result<(), StateMachineErrors> {
+        let mut state_machine = StateMachine::new(StateData::new());
+        let mut start = State::new("start");
+        start.add(Transition::new("group", FnTransitionRule::new(|data: &StateData, _action: &str| data["context"]["chat"]["type"] == "group")));
+        start.add(Transition::new("private", DefaultTransitionRule::new())
+            .with_action(FnTransitionAction::new(|data, _action| {
+                data.insert("context".to_string(), Value::from("changed"));
+                Ok(())
+            }))
+            .with_output(TemplateTransitionOutput::new("Hi {{context.user.first_name}}!")?));
+        state_machine.add_state(start);
+        state_machine.add_state(State::new("group"));
+        state_machine.add_state(State::new("private"));
+        state_machine.set_initial_state_name("start")?;
+        state_machine.set_context(serde_json::json!({ "user": { "first_name": "Ana" }, "chat": { "type": "private" } }));
+
+        let (transition_output, _) = state_machine.transition_state("oi")?;
+
+        assert_eq!("private", state_machine.get_current_state().unwrap());
+        assert_eq!("Hi Ana!", transition_output.unwrap());
+        assert!(!state_machine.get_state_data().contains_key("context"));
         Ok(())
     }
 }
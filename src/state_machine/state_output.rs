@@ -1,6 +1,4 @@
-use std::collections::HashMap;
-
-use super::StateOutput;
+use super::{StateData, StateMachineErrors, StateOutput};
 
 pub struct FixedStateOutput {
     output: String,
@@ -13,17 +11,21 @@ impl FixedStateOutput {
     }
 }
 impl StateOutput for FixedStateOutput {
-    fn generate_output(&self, _data: &mut HashMap<String, String>) -> Option<String> {
+    fn generate_output(&self, _data: &StateData) -> Result<Option<String>, StateMachineErrors> {
+        Ok(Some(String::from(&self.output)))
+    }
+
+    fn describe(&self) -> Option<String> {
         Some(String::from(&self.output))
     }
 }
 
 pub struct FnStateOutput<F>
-where F: Fn(&mut HashMap<String, String>) -> Option<String> {
+where F: Fn(&StateData) -> Option<String> {
     rule: F,
 }
 impl <F> FnStateOutput<F>
-where F: Fn(&mut HashMap<String, String>) -> Option<String> {
+where F: Fn(&StateData) -> Option<String> {
     pub fn new(rule: F) -> Self {
         Self {
             rule,
@@ -31,8 +33,8 @@ where F: Fn(&mut HashMap<String, String>) -> Option<String> {
     }
 }
 impl <F> StateOutput for FnStateOutput<F>
-where F: Fn(&mut HashMap<String, String>) -> Option<String> {
-    fn generate_output(&self, data: &mut HashMap<String, String>) -> Option<String> {
-        (&self.rule)(data)
+where F: Fn(&StateData) -> Option<String> {
+    fn generate_output(&self, data: &StateData) -> Result<Option<String>, StateMachineErrors> {
+        Ok((self.rule)(data))
     }
 }
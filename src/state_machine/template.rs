@@ -0,0 +1,315 @@
+use std::fmt;
+
+use serde_json::Value;
+
+use super::{StateData, StateMachineErrors, StateOutput, TransitionOutput};
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum TemplateError {
+    Syntax(String),
+    MissingVariable(String),
+}
+impl fmt::Display for TemplateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Syntax(message) => write!(f, "template syntax error: {}", message),
+            Self::MissingVariable(name) => write!(f, "missing template variable: {}", name),
+        }
+    }
+}
+impl From<TemplateError> for StateMachineErrors {
+    fn from(error: TemplateError) -> Self {
+        StateMachineErrors::OutputFailed(error.to_string())
+    }
+}
+
+enum Node {
+    Text(String),
+    Variable { path: String, default: Option<String> },
+    If { path: String, then: Vec<Node>, otherwise: Vec<Node> },
+    Each { path: String, body: Vec<Node> },
+}
+
+/// Text with `{{variable}}` placeholders rendered from state data. Supports
+/// `{{variable | default "text"}}`, `{{#if variable}}...{{else}}...{{/if}}` and
+/// `{{#each list}}...{{this}}...{{@index}}...{{/each}}`. Dotted paths read nested objects,
+/// and inside `#each` the current item's fields are looked up before the state data.
+pub struct Template {
+    source: String,
+    nodes: Vec<Node>,
+}
+impl Template {
+    pub fn parse(source: &str) -> Result<Self, TemplateError> {
+        let mut parser = Parser { rest: source };
+        let (nodes, end) = parser.parse_nodes()?;
+        if let Some(tag) = end {
+            return Err(TemplateError::Syntax(format!("unexpected {{{{{}}}}}", tag)));
+        }
+        Ok(Self {
+            source: String::from(source),
+            nodes,
+        })
+    }
+
+    pub fn render(&self, data: &StateData) -> Result<String, TemplateError> {
+        let mut output = String::new();
+        let root = Value::Object(data.clone());
+        render_nodes(&self.nodes, &Scope { value: &root, index: None, parent: None }, &mut output)?;
+        Ok(output)
+    }
+
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+}
+
+struct Parser<'a> {
+    rest: &'a str,
+}
+impl <'a> Parser<'a> {
+    /// Parses nodes until the end of the input or a closing tag (`else`, `/if`, `/each`),
+    /// which is returned so the caller can check it.
+    fn parse_nodes(&mut self) -> Result<(Vec<Node>, Option<String>), TemplateError> {
+        let mut nodes = Vec::new();
+        loop {
+            let start = match self.rest.find("{{") {
+                Some(i) => i,
+                None => {
+                    if !self.rest.is_empty() {
+                        nodes.push(Node::Text(String::from(self.rest)));
+                    }
+                    self.rest = "";
+                    return Ok((nodes, None));
+                }
+            };
+            if start > 0 {
+                nodes.push(Node::Text(String::from(&self.rest[..start])));
+            }
+            let end = self.rest[start..].find("}}")
+                .ok_or_else(|| TemplateError::Syntax(String::from("unclosed {{")))?;
+            let tag = self.rest[start + 2..start + end].trim().to_string();
+            self.rest = &self.rest[start + end + 2..];
+
+            if let Some(path) = tag.strip_prefix("#if ") {
+                let (then, end_tag) = self.parse_nodes()?;
+                let otherwise = match end_tag.as_deref() {
+                    Some("else") => self.expect_end("/if")?,
+                    Some("/if") => Vec::new(),
+                    _ => return Err(TemplateError::Syntax(format!("unclosed {{{{#if {}}}}}", path.trim()))),
+                };
+                nodes.push(Node::If { path: String::from(path.trim()), then, otherwise });
+            } else if let Some(path) = tag.strip_prefix("#each ") {
+                let body = self.expect_end("/each")?;
+                nodes.push(Node::Each { path: String::from(path.trim()), body });
+            } else if tag == "else" || tag.starts_with('/') {
+                return Ok((nodes, Some(tag)));
+            } else {
+                nodes.push(parse_variable(&tag)?);
+            }
+        }
+    }
+
+    fn expect_end(&mut self, end: &str) -> Result<Vec<Node>, TemplateError> {
+        match self.parse_nodes()? {
+            (nodes, Some(tag)) if tag == end => Ok(nodes),
+            _ => Err(TemplateError::Syntax(format!("expected {{{{{}}}}}", end))),
+        }
+    }
+}
+
+fn parse_variable(tag: &str) -> Result<Node, TemplateError> {
+    let (path, default) = match tag.split_once('|') {
+        Some((path, filter)) => {
+            let default = filter.trim().strip_prefix("default")
+                .map(str::trim)
+                .and_then(|d| d.strip_prefix('"'))
+                .and_then(|d| d.strip_suffix('"'))
+                .ok_or_else(|| TemplateError::Syntax(format!("invalid filter in {{{{{}}}}}", tag)))?;
+            (path.trim(), Some(String::from(default)))
+        },
+        None => (tag, None),
+    };
+    if path.is_empty() {
+        return Err(TemplateError::Syntax(String::from("empty variable")));
+    }
+    Ok(Node::Variable { path: String::from(path), default })
+}
+
+struct Scope<'a> {
+    value: &'a Value,
+    index: Option<usize>,
+    parent: Option<&'a Scope<'a>>,
+}
+impl <'a> Scope<'a> {
+    fn lookup(&self, path: &str) -> Option<Value> {
+        if path == "this" {
+            return Some(self.value.clone());
+        }
+        if path == "@index" {
+            return self.index.map(Value::from);
+        }
+        let path = path.strip_prefix("this.").unwrap_or(path);
+        let mut value = self.value;
+        for key in path.split('.') {
+            value = match value.get(key) {
+                Some(v) => v,
+                None => return self.parent.and_then(|p| p.lookup(path)),
+            };
+        }
+        Some(value.clone())
+    }
+}
+
+fn is_truthy(value: &Option<Value>) -> bool {
+    match value {
+        None | Some(Value::Null) => false,
+        Some(Value::Bool(b)) => *b,
+        Some(Value::String(s)) => !s.is_empty(),
+        Some(Value::Number(n)) => n.as_f64() != Some(0.0),
+        Some(Value::Array(a)) => !a.is_empty(),
+        Some(Value::Object(o)) => !o.is_empty(),
+    }
+}
+
+fn render_nodes(nodes: &[Node], scope: &Scope, output: &mut String) -> Result<(), TemplateError> {
+    for node in nodes {
+        match node {
+            Node::Text(text) => output.push_str(text),
+            Node::Variable { path, default } => match (scope.lookup(path), default) {
+                (Some(Value::Null), Some(default)) | (None, Some(default)) => output.push_str(default),
+                (Some(Value::Null), None) | (None, None) => return Err(TemplateError::MissingVariable(String::from(path))),
+                (Some(Value::String(s)), _) => output.push_str(&s),
+                (Some(value), _) => output.push_str(&value.to_string()),
+            },
+            Node::If { path, then, otherwise } => {
+                if is_truthy(&scope.lookup(path)) {
+                    render_nodes(then, scope, output)?;
+                } else {
+                    render_nodes(otherwise, scope, output)?;
+                }
+            },
+            Node::Each { path, body } => {
+                let items = match scope.lookup(path) {
+                    Some(Value::Array(items)) => items,
+                    Some(Value::Null) | None => return Err(TemplateError::MissingVariable(String::from(path))),
+                    Some(value) => vec![value],
+                };
+                for (index, item) in items.iter().enumerate() {
+                    render_nodes(body, &Scope { value: item, index: Some(index), parent: Some(scope) }, output)?;
+                }
+            },
+        }
+    }
+    Ok(())
+}
+
+pub struct TemplateStateOutput {
+    template: Template,
+}
+impl TemplateStateOutput {
+    pub fn new(template: &str) -> Result<Self, TemplateError> {
+        Ok(Self {
+            template: Template::parse(template)?,
+        })
+    }
+}
+impl StateOutput for TemplateStateOutput {
+    fn generate_output(&self, data: &StateData) -> Result<Option<String>, StateMachineErrors> {
+        Ok(Some(self.template.render(data)?))
+    }
+
+    fn describe(&self) -> Option<String> {
+        Some(String::from(self.template.source()))
+    }
+}
+
+pub struct TemplateTransitionOutput {
+    template: Template,
+}
+impl TemplateTransitionOutput {
+    pub fn new(template: &str) -> Result<Self, TemplateError> {
+        Ok(Self {
+            template: Template::parse(template)?,
+        })
+    }
+}
+impl TransitionOutput for TemplateTransitionOutput {
+    fn generate_output(&self, data: &StateData, _action: &str) -> Result<Option<String>, StateMachineErrors> {
+        Ok(Some(self.template.render(data)?))
+    }
+
+    fn describe(&self) -> Option<String> {
+        Some(String::from(self.template.source()))
+    }
+}
+
+#[cfg(test)]
+mod template_tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn data(value: Value) -> StateData {
+        value.as_object().unwrap().clone()
+    }
+
+    #[test]
+    fn template_should_render_variables() {
+        let template = Template::parse("Nome: {{register-name}}, idade: {{age}}, cidade: {{address.city}}").unwrap();
+
+        let output = template.render(&data(json!({"register-name": "Fulano", "age": 30, "address": {"city": "Curitiba"}})));
+
+        assert_eq!(Ok(String::from("Nome: Fulano, idade: 30, cidade: Curitiba")), output);
+    }
+
+    #[test]
+    fn template_should_use_default_for_missing_variables() {
+        let template = Template::parse("Telefone: {{phone | default \"não informado\"}}").unwrap();
+
+        assert_eq!(Ok(String::from("Telefone: não informado")), template.render(&StateData::new()));
+    }
+
+    #[test]
+    fn template_should_report_missing_variables() {
+        let template = Template::parse("Nome: {{name}}").unwrap();
+
+        assert_eq!(Err(TemplateError::MissingVariable(String::from("name"))), template.render(&StateData::new()));
+    }
+
+    #[test]
+    fn template_should_render_conditionals() {
+        let template = Template::parse("{{#if phone}}Telefone: {{phone}}{{else}}Sem telefone{{/if}}").unwrap();
+
+        assert_eq!(Ok(String::from("Telefone: 123")), template.render(&data(json!({"phone": "123"}))));
+        assert_eq!(Ok(String::from("Sem telefone")), template.render(&data(json!({"phone": ""}))));
+    }
+
+    #[test]
+    fn template_should_render_loops() {
+        let template = Template::parse("{{#each people}}{{@index}}: {{name}} ({{city}})\n{{/each}}").unwrap();
+
+        let output = template.render(&data(json!({"city": "Curitiba", "people": [{"name": "Fulano"}, {"name": "Beltrano"}]})));
+
+        assert_eq!(Ok(String::from("0: Fulano (Curitiba)\n1: Beltrano (Curitiba)\n")), output);
+    }
+
+    #[test]
+    fn template_should_reject_invalid_syntax() {
+        assert!(matches!(Template::parse("{{#if name}}no end"), Err(TemplateError::Syntax(_))));
+        assert!(matches!(Template::parse("{{name"), Err(TemplateError::Syntax(_))));
+        assert!(matches!(Template::parse("{{/each}}"), Err(TemplateError::Syntax(_))));
+    }
+
+    #[test]
+    fn template_state_output_should_fail_on_missing_variable() {
+        let output = TemplateStateOutput::new("Nome: {{name}}").unwrap();
+
+        assert!(matches!(output.generate_output(&StateData::new()), Err(StateMachineErrors::OutputFailed(_))));
+    }
+
+    #[test]
+    fn template_outputs_should_reject_invalid_syntax() {
+        assert!(matches!(TemplateStateOutput::new("{{#if name}}no end"), Err(TemplateError::Syntax(_))));
+        assert!(matches!(TemplateTransitionOutput::new("{{name"), Err(TemplateError::Syntax(_))));
+    }
+}
@@ -0,0 +1,485 @@
+use regex::Regex;
+use serde_json::Value;
+use unicode_normalization::{UnicodeNormalization, char::is_combining_mark};
+
+use super::{RuleDescription, RuleKind, StateData, StateMachineErrors, TransitionAction, TransitionRule};
+
+/// Lowercases `text`, removes accents and collapses whitespace, so "  Não " becomes "nao".
+pub fn normalize(text: &str) -> String {
+    let text: String = text.nfd().filter(|c| !is_combining_mark(*c)).collect();
+    text.split_whitespace().collect::<Vec<&str>>().join(" ").to_lowercase()
+}
+
+/// Like `EqTransitionRule`, but ignoring case, accents and extra whitespace.
+pub struct NormalizedEqTransitionRule {
+    value: String,
+    normalized_value: String,
+}
+impl NormalizedEqTransitionRule {
+    pub fn new(value: &str) -> Self {
+        Self {
+            value: String::from(value),
+            normalized_value: normalize(value),
+        }
+    }
+}
+impl TransitionRule for NormalizedEqTransitionRule {
+    fn test(&self, _data: &StateData, action: &str) -> bool {
+        normalize(action) == self.normalized_value
+    }
+
+    fn describe(&self) -> Option<RuleDescription> {
+        Some(RuleDescription::new(RuleKind::Exact).with_expected_input(&self.value))
+    }
+}
+
+/// Accepts any of the given words, compared after normalization. The first word is the
+/// one shown as expected input.
+pub struct SynonymsTransitionRule {
+    values: Vec<String>,
+    normalized_values: Vec<String>,
+}
+impl SynonymsTransitionRule {
+    pub fn new(values: &[&str]) -> Self {
+        Self {
+            values: values.iter().map(|v| String::from(*v)).collect(),
+            normalized_values: values.iter().map(|v| normalize(v)).collect(),
+        }
+    }
+}
+impl TransitionRule for SynonymsTransitionRule {
+    fn test(&self, _data: &StateData, action: &str) -> bool {
+        self.normalized_values.contains(&normalize(action))
+    }
+
+    fn describe(&self) -> Option<RuleDescription> {
+        Some(RuleDescription::new(RuleKind::Exact).with_expected_input(self.values.first()?))
+    }
+}
+
+pub struct RegexTransitionRule {
+    regex: Regex,
+}
+impl RegexTransitionRule {
+    pub fn new(pattern: &str) -> Result<Self, regex::Error> {
+        Ok(Self {
+            regex: Regex::new(pattern)?,
+        })
+    }
+
+    /// An action storing this rule's named groups, sharing its compiled pattern.
+    pub fn capture_action(&self) -> RegexCaptureAction {
+        RegexCaptureAction {
+            regex: self.regex.clone(),
+        }
+    }
+}
+impl TransitionRule for RegexTransitionRule {
+    fn test(&self, _data: &StateData, action: &str) -> bool {
+        self.regex.is_match(action)
+    }
+
+    fn describe(&self) -> Option<RuleDescription> {
+        Some(RuleDescription::new(RuleKind::Pattern).with_label(Some(self.regex.as_str())))
+    }
+}
+
+/// Stores the named capture groups of `pattern` into state data, keyed by group name. Use
+/// [`RegexTransitionRule::capture_action`] to capture what a rule matched.
+pub struct RegexCaptureAction {
+    regex: Regex,
+}
+impl RegexCaptureAction {
+    pub fn new(pattern: &str) -> Result<Self, regex::Error> {
+        Ok(Self {
+            regex: Regex::new(pattern)?,
+        })
+    }
+}
+impl TransitionAction for RegexCaptureAction {
+    fn execute(&self, data: &mut StateData, action: &str) -> Result<(), StateMachineErrors> {
+        let captures = self.regex.captures(action)
+            .ok_or_else(|| StateMachineErrors::ActionFailed(format!("'{}' doesn't match {}", action, self.regex.as_str())))?;
+        for name in self.regex.capture_names().flatten() {
+            if let Some(value) = captures.name(name) {
+                data.insert(String::from(name), Value::String(String::from(value.as_str())));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Accepts numbers between `min` and `max`, both inclusive.
+pub struct NumberRangeTransitionRule {
+    min: f64,
+    max: f64,
+}
+impl NumberRangeTransitionRule {
+    pub fn new(min: f64, max: f64) -> Self {
+        Self {
+            min,
+            max,
+        }
+    }
+}
+impl TransitionRule for NumberRangeTransitionRule {
+    fn test(&self, _data: &StateData, action: &str) -> bool {
+        match action.trim().replace(',', ".").parse::<f64>() {
+            Ok(n) => n >= self.min && n <= self.max,
+            Err(_) => false,
+        }
+    }
+
+    fn describe(&self) -> Option<RuleDescription> {
+        Some(RuleDescription::new(RuleKind::Range).with_label(Some(&format!("{}-{}", self.min, self.max))))
+    }
+}
+
+pub struct PrefixTransitionRule {
+    prefix: String,
+}
+impl PrefixTransitionRule {
+    pub fn new(prefix: &str) -> Self {
+        Self {
+            prefix: normalize(prefix),
+        }
+    }
+}
+impl TransitionRule for PrefixTransitionRule {
+    fn test(&self, _data: &StateData, action: &str) -> bool {
+        normalize(action).starts_with(&self.prefix)
+    }
+
+    fn describe(&self) -> Option<RuleDescription> {
+        Some(RuleDescription::new(RuleKind::Pattern).with_label(Some(&format!("{}...", self.prefix))))
+    }
+}
+
+/// Matches bot commands like `/start`, including the `/start@bot_name` form and arguments.
+pub struct CommandTransitionRule {
+    command: String,
+}
+impl CommandTransitionRule {
+    pub fn new(command: &str) -> Self {
+        Self {
+            command: String::from(command.trim_start_matches('/')),
+        }
+    }
+}
+impl TransitionRule for CommandTransitionRule {
+    fn test(&self, _data: &StateData, action: &str) -> bool {
+        let word = match action.split_whitespace().next() {
+            Some(w) => w,
+            None => return false,
+        };
+        let command = match word.strip_prefix('/') {
+            Some(c) => c,
+            None => return false,
+        };
+        let command = command.split('@').next().unwrap_or_default();
+        command.eq_ignore_ascii_case(&self.command)
+    }
+
+    fn describe(&self) -> Option<RuleDescription> {
+        Some(RuleDescription::new(RuleKind::Command).with_expected_input(&format!("/{}", &self.command)))
+    }
+}
+
+pub struct AndTransitionRule {
+    left: Box<dyn TransitionRule>,
+    right: Box<dyn TransitionRule>,
+}
+impl AndTransitionRule {
+    pub fn new<L, R>(left: L, right: R) -> Self
+    where L: TransitionRule + 'static, R: TransitionRule + 'static {
+        Self {
+            left: Box::new(left),
+            right: Box::new(right),
+        }
+    }
+}
+impl TransitionRule for AndTransitionRule {
+    fn test(&self, data: &StateData, action: &str) -> bool {
+        self.left.test(data, action) && self.right.test(data, action)
+    }
+
+    fn score(&self, data: &StateData, action: &str) -> Option<f64> {
+        Some(self.left.score(data, action)?.min(self.right.score(data, action)?))
+    }
+
+    /// The left rule's description, completed with the right one's expected input or label.
+    /// A default rule on either side accepts anything, so the other side describes the pair.
+    fn describe(&self) -> Option<RuleDescription> {
+        match (self.left.describe(), self.right.describe()) {
+            (Some(left), Some(right)) if left.kind == RuleKind::Default => Some(right),
+            (Some(left), Some(right)) if right.kind == RuleKind::Default => Some(left),
+            (Some(left), Some(right)) => Some(RuleDescription {
+                kind: left.kind,
+                expected_input: left.expected_input.or(right.expected_input),
+                label: left.label.or(right.label),
+            }),
+            (left, right) => left.or(right),
+        }
+    }
+}
+
+pub struct OrTransitionRule {
+    left: Box<dyn TransitionRule>,
+    right: Box<dyn TransitionRule>,
+}
+impl OrTransitionRule {
+    pub fn new<L, R>(left: L, right: R) -> Self
+    where L: TransitionRule + 'static, R: TransitionRule + 'static {
+        Self {
+            left: Box::new(left),
+            right: Box::new(right),
+        }
+    }
+}
+impl TransitionRule for OrTransitionRule {
+    fn test(&self, data: &StateData, action: &str) -> bool {
+        self.left.test(data, action) || self.right.test(data, action)
+    }
+
+    fn score(&self, data: &StateData, action: &str) -> Option<f64> {
+        match (self.left.score(data, action), self.right.score(data, action)) {
+            (Some(l), Some(r)) => Some(l.max(r)),
+            (l, r) => l.or(r),
+        }
+    }
+
+    fn describe(&self) -> Option<RuleDescription> {
+        self.left.describe().or_else(|| self.right.describe())
+    }
+}
+
+pub struct NotTransitionRule {
+    rule: Box<dyn TransitionRule>,
+}
+impl NotTransitionRule {
+    pub fn new<R>(rule: R) -> Self
+    where R: TransitionRule + 'static {
+        Self {
+            rule: Box::new(rule),
+        }
+    }
+}
+impl TransitionRule for NotTransitionRule {
+    fn test(&self, data: &StateData, action: &str) -> bool {
+        !self.rule.test(data, action)
+    }
+
+    /// A pattern without expected input, since any input but the negated one is accepted.
+    fn describe(&self) -> Option<RuleDescription> {
+        let negated = self.rule.describe()?;
+        let text = negated.expected_input.or(negated.label)?;
+        Some(RuleDescription::new(RuleKind::Pattern).with_label(Some(&format!("not {}", text))))
+    }
+}
+
+/// Matches free text against an intent's keywords, tolerating small typos. Scores close to
+/// 1.0 for exact keywords and lower as the edit distance grows; a keyword found among the
+/// action words scores slightly less than the whole action matching it.
+pub struct IntentTransitionRule {
+    keywords: Vec<String>,
+    max_distance: usize,
+    min_score: f64,
+}
+impl IntentTransitionRule {
+    pub fn new(keywords: &[&str]) -> Self {
+        Self {
+            keywords: keywords.iter().map(|k| normalize(k)).collect(),
+            max_distance: 2,
+            min_score: 0.6,
+        }
+    }
+
+    /// Maximum number of edits between a keyword and the typed text.
+    pub fn with_max_distance(mut self, max_distance: usize) -> Self {
+        self.max_distance = max_distance;
+        self
+    }
+
+    /// Minimum similarity, from 0.0 to 1.0, for the rule to match.
+    pub fn with_min_score(mut self, min_score: f64) -> Self {
+        self.min_score = min_score;
+        self
+    }
+
+    fn keyword_score(&self, keyword: &str, text: &str) -> Option<f64> {
+        if strsim::levenshtein(keyword, text) > self.max_distance {
+            return None;
+        }
+        let score = strsim::normalized_levenshtein(keyword, text);
+        if score >= self.min_score {
+            Some(score)
+        } else {
+            None
+        }
+    }
+}
+impl TransitionRule for IntentTransitionRule {
+    fn test(&self, data: &StateData, action: &str) -> bool {
+        self.score(data, action).is_some()
+    }
+
+    fn score(&self, _data: &StateData, action: &str) -> Option<f64> {
+        let action = normalize(action);
+        let mut best: Option<f64> = None;
+        for keyword in &self.keywords {
+            let whole = self.keyword_score(keyword, &action);
+            let words = action.split(' ')
+                .filter_map(|word| self.keyword_score(keyword, word))
+                .map(|score| score * 0.9)
+                .reduce(f64::max);
+            for score in [whole, words].into_iter().flatten() {
+                if best.is_none_or(|b| score > b) {
+                    best = Some(score);
+                }
+            }
+        }
+        best
+    }
+
+    fn describe(&self) -> Option<RuleDescription> {
+        Some(RuleDescription::new(RuleKind::Intent).with_label(Some(&self.keywords.join(", "))))
+    }
+}
+
+#[cfg(test)]
+mod transition_rules_tests {
+    use crate::state_machine::transitions::EqTransitionRule;
+
+    use super::*;
+
+    #[test]
+    fn normalize_should_ignore_case_accents_and_whitespace() {
+        assert_eq!("nao sei", normalize("  Não   SEI "));
+    }
+
+    #[test]
+    fn normalized_eq_rule_should_accept_variations() {
+        let data = StateData::new();
+        let rule = NormalizedEqTransitionRule::new("não");
+
+        assert!(rule.test(&data, "NÃO"));
+        assert!(rule.test(&data, "nao "));
+        assert!(!rule.test(&data, "no"));
+    }
+
+    #[test]
+    fn synonyms_rule_should_accept_any_synonym() {
+        let data = StateData::new();
+        let rule = SynonymsTransitionRule::new(&["sim", "s", "yes"]);
+
+        assert!(rule.test(&data, "Sim"));
+        assert!(rule.test(&data, "SIM"));
+        assert!(rule.test(&data, "s"));
+        assert!(rule.test(&data, "sim "));
+        assert!(!rule.test(&data, "simples"));
+        assert_eq!(Some(String::from("sim")), rule.describe().unwrap().expected_input);
+    }
+
+    #[test]
+    fn regex_rule_should_match_and_capture_named_groups() -> Result<(), StateMachineErrors> {
+        let mut data = StateData::new();
+        let pattern = r"^(?P<ddd>\d{2})\s*(?P<number>\d{8,9})$";
+        let rule = RegexTransitionRule::new(pattern).unwrap();
+        let action = rule.capture_action();
+
+        assert!(!rule.test(&data, "abc"));
+        assert!(rule.test(&data, "41 999998888"));
+        action.execute(&mut data, "41 999998888")?;
+
+        assert_eq!("41", data.get("ddd").unwrap());
+        assert_eq!("999998888", data.get("number").unwrap());
+        Ok(())
+    }
+
+    #[test]
+    fn number_range_rule_should_accept_numbers_in_range() {
+        let data = StateData::new();
+        let rule = NumberRangeTransitionRule::new(18.0, 120.0);
+
+        assert!(rule.test(&data, "18"));
+        assert!(rule.test(&data, " 30,5 "));
+        assert!(!rule.test(&data, "17"));
+        assert!(!rule.test(&data, "abc"));
+    }
+
+    #[test]
+    fn prefix_rule_should_match_start_of_action() {
+        let data = StateData::new();
+        let rule = PrefixTransitionRule::new("cad");
+
+        assert!(rule.test(&data, "Cadastrar"));
+        assert!(!rule.test(&data, "listar"));
+    }
+
+    #[test]
+    fn command_rule_should_match_bot_commands() {
+        let data = StateData::new();
+        let rule = CommandTransitionRule::new("/start");
+
+        assert!(rule.test(&data, "/start"));
+        assert!(rule.test(&data, "/START@my_bot"));
+        assert!(rule.test(&data, "/start 123"));
+        assert!(!rule.test(&data, "start"));
+        assert!(!rule.test(&data, "/started"));
+    }
+
+    #[test]
+    fn combinators_should_combine_rules() {
+        let data = StateData::new();
+        let and_rule = AndTransitionRule::new(PrefixTransitionRule::new("a"), NotTransitionRule::new(EqTransitionRule::new("ab")));
+        let or_rule = OrTransitionRule::new(EqTransitionRule::new("1"), NormalizedEqTransitionRule::new("um"));
+
+        assert!(and_rule.test(&data, "ac"));
+        assert!(!and_rule.test(&data, "ab"));
+        assert!(!and_rule.test(&data, "b"));
+        assert!(or_rule.test(&data, "1"));
+        assert!(or_rule.test(&data, "UM"));
+        assert!(!or_rule.test(&data, "2"));
+    }
+
+    #[test]
+    fn combinators_should_describe_both_rules() {
+        let command_with_argument = AndTransitionRule::new(CommandTransitionRule::new("delete"), RegexTransitionRule::new(r"\S+\s+\S").unwrap());
+        let pattern_then_command = AndTransitionRule::new(RegexTransitionRule::new(r"\S+\s+\S").unwrap(), CommandTransitionRule::new("delete"));
+        let not_rule = NotTransitionRule::new(EqTransitionRule::new("ab"));
+
+        let description = command_with_argument.describe().unwrap();
+        assert_eq!((RuleKind::Command, Some("/delete"), Some(r"\S+\s+\S")),
+            (description.kind, description.expected_input.as_deref(), description.label.as_deref()));
+        assert_eq!(Some(String::from("/delete")), pattern_then_command.describe().unwrap().expected_input);
+        assert_eq!(Some(String::from("not ab")), not_rule.describe().unwrap().label);
+        assert_eq!(None, not_rule.describe().unwrap().expected_input);
+    }
+
+    #[test]
+    fn regex_rule_should_reject_invalid_patterns() {
+        assert!(RegexTransitionRule::new("(unclosed").is_err());
+        assert!(RegexCaptureAction::new("(unclosed").is_err());
+    }
+
+    #[test]
+    fn intent_rule_should_score_keywords_and_typos() {
+        let data = StateData::new();
+        let rule = IntentTransitionRule::new(&["cadastrar", "novo"]);
+
+        assert_eq!(Some(1.0), rule.score(&data, "Novo"));
+        assert!(rule.score(&data, "cadastar").unwrap() < 1.0);
+        assert!(rule.score(&data, "quero cadastrar").unwrap() < 1.0);
+        assert!(rule.score(&data, "listar").is_none());
+    }
+
+    #[test]
+    fn intent_rule_should_respect_thresholds() {
+        let data = StateData::new();
+        let strict_rule = IntentTransitionRule::new(&["cadastrar"]).with_max_distance(0);
+        let lenient_rule = IntentTransitionRule::new(&["cadastrar"]).with_max_distance(4).with_min_score(0.5);
+
+        assert!(!strict_rule.test(&data, "cadastar"));
+        assert!(lenient_rule.test(&data, "cdastr"));
+    }
+}
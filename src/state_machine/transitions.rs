@@ -1,20 +1,33 @@
-use std::collections::HashMap;
+use serde_json::Value;
 
-use super::{TransitionOutput, TransitionRule};
+use super::{RuleDescription, RuleKind, StateData, StateMachineErrors, TransitionAction, TransitionOutput, TransitionRule};
 
 pub struct EqTransitionRule {
     value: String,    
+    label: Option<String>,
 }
 impl EqTransitionRule {
     pub fn new(value: &str) -> Self {
         Self {
             value: String::from(value),
+            label: None,
         }
     }
+
+    pub fn with_label(mut self, label: &str) -> Self {
+        self.label = Some(String::from(label));
+        self
+    }
 }
 impl TransitionRule for EqTransitionRule {
-    fn test(&self, _data: &mut HashMap<String, String>, action: &str) -> bool {
-        action == &self.value
+    fn test(&self, _data: &StateData, action: &str) -> bool {
+        action == self.value
+    }
+
+    fn describe(&self) -> Option<RuleDescription> {
+        Some(RuleDescription::new(RuleKind::Exact)
+            .with_expected_input(&self.value)
+            .with_label(self.label.as_deref()))
     }
 }
 
@@ -25,17 +38,25 @@ impl DefaultTransitionRule {
     }
 }
 impl TransitionRule for DefaultTransitionRule {
-    fn test(&self, _data: &mut HashMap<String, String>, _action: &str) -> bool {
+    fn test(&self, _data: &StateData, _action: &str) -> bool {
         true
     }
+
+    fn score(&self, _data: &StateData, _action: &str) -> Option<f64> {
+        Some(0.0)
+    }
+
+    fn describe(&self) -> Option<RuleDescription> {
+        Some(RuleDescription::new(RuleKind::Default))
+    }
 }
 
 pub struct FnTransitionRule<F>
-where F: Fn(&mut HashMap<String, String>, &str) -> bool {
+where F: Fn(&StateData, &str) -> bool {
     rule: F,    
 }
 impl <F> FnTransitionRule<F>
-where F: Fn(&mut HashMap<String, String>, &str) -> bool {
+where F: Fn(&StateData, &str) -> bool {
     pub fn new(rule: F) -> Self {
         Self {
             rule,
@@ -43,9 +64,70 @@ where F: Fn(&mut HashMap<String, String>, &str) -> bool {
     }
 }
 impl <F> TransitionRule for FnTransitionRule<F>
-where F: Fn(&mut HashMap<String, String>, &str) -> bool {
-    fn test(&self, data: &mut HashMap<String, String>, action: &str) -> bool {
-        (&self.rule)(data, action)
+where F: Fn(&StateData, &str) -> bool {
+    fn test(&self, data: &StateData, action: &str) -> bool {
+        (self.rule)(data, action)
+    }
+}
+
+pub struct EmptyTransitionAction;
+impl EmptyTransitionAction {
+    pub fn new() -> Self {
+        Self {}
+    }
+}
+impl TransitionAction for EmptyTransitionAction {
+    fn execute(&self, _data: &mut StateData, _action: &str) -> Result<(), StateMachineErrors> {
+        Ok(())
+    }
+}
+
+pub struct StoreTransitionAction {
+    key: String,
+}
+impl StoreTransitionAction {
+    pub fn new(key: &str) -> Self {
+        Self {
+            key: String::from(key),
+        }
+    }
+}
+impl TransitionAction for StoreTransitionAction {
+    fn execute(&self, data: &mut StateData, action: &str) -> Result<(), StateMachineErrors> {
+        data.insert(self.key.to_string(), Value::String(action.to_string()));
+        Ok(())
+    }
+}
+
+pub struct ClearDataTransitionAction;
+impl ClearDataTransitionAction {
+    pub fn new() -> Self {
+        Self {}
+    }
+}
+impl TransitionAction for ClearDataTransitionAction {
+    fn execute(&self, data: &mut StateData, _action: &str) -> Result<(), StateMachineErrors> {
+        data.clear();
+        Ok(())
+    }
+}
+
+pub struct FnTransitionAction<F>
+where F: Fn(&mut StateData, &str) -> Result<(), StateMachineErrors> {
+    action: F,
+}
+impl <F> FnTransitionAction<F>
+where F: Fn(&mut StateData, &str) -> Result<(), StateMachineErrors> {
+    pub fn new(action: F) -> Self {
+        Self {
+            action,
+        }
+    }
+}
+impl <F> TransitionAction for FnTransitionAction<F>
+where F: Fn(&mut StateData, &str) -> Result<(), StateMachineErrors> {
+    fn execute(&self, data: &mut StateData, action: &str) -> Result<(), StateMachineErrors> {
+        (self.action)(data, action)
     }
 }
 
@@ -56,8 +138,8 @@ impl EmptyTransitionOutput {
     }
 }
 impl TransitionOutput for EmptyTransitionOutput {
-    fn generate_output(&self, _data: &mut HashMap<String, String>, _action: &str) -> Option<String> {
-        None
+    fn generate_output(&self, _data: &StateData, _action: &str) -> Result<Option<String>, StateMachineErrors> {
+        Ok(None)
     }
 }
 
@@ -72,17 +154,21 @@ impl FixedTransitionOutput {
     }
 }
 impl TransitionOutput for FixedTransitionOutput {
-    fn generate_output(&self, _data: &mut HashMap<String, String>, _action: &str) -> Option<String> {
+    fn generate_output(&self, _data: &StateData, _action: &str) -> Result<Option<String>, StateMachineErrors> {
+        Ok(Some(String::from(&self.output)))
+    }
+
+    fn describe(&self) -> Option<String> {
         Some(String::from(&self.output))
     }
 }
 
 pub struct FnTransitionOutput<F>
-where F: Fn(&mut HashMap<String, String>, &str) -> Option<String> {
+where F: Fn(&StateData, &str) -> Option<String> {
     rule: F,
 }
 impl <F> FnTransitionOutput<F>
-where F: Fn(&mut HashMap<String, String>, &str) -> Option<String> {
+where F: Fn(&StateData, &str) -> Option<String> {
     pub fn new(rule: F) -> Self {
         Self {
             rule,
@@ -90,8 +176,8 @@ where F: Fn(&mut HashMap<String, String>, &str) -> Option<String> {
     }
 }
 impl <F> TransitionOutput for FnTransitionOutput<F>
-where F: Fn(&mut HashMap<String, String>, &str) -> Option<String> {
-    fn generate_output(&self, data: &mut HashMap<String, String>, action: &str) -> Option<String> {
-        (&self.rule)(data, action)
+where F: Fn(&StateData, &str) -> Option<String> {
+    fn generate_output(&self, data: &StateData, action: &str) -> Result<Option<String>, StateMachineErrors> {
+        Ok((self.rule)(data, action))
     }
 }
@@ -0,0 +1,264 @@
+use std::{collections::{HashSet, VecDeque}, fmt};
+
+use super::{StateMachine, Transition};
+
+/// Name used in issues about global transitions, which don't belong to a state.
+pub const GLOBAL_STATE_NAME: &str = "*";
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ValidationIssue {
+    MissingInitialState,
+    DanglingTarget { state: String, target: String },
+    UnreachableState(String),
+    DeadEnd(String),
+    ShadowedTransition { state: String, target: String },
+    /// A state transition expecting the same input as a global transition or command, which
+    /// are checked first.
+    ShadowedByGlobal { state: String, target: String, input: String },
+}
+impl fmt::Display for ValidationIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::MissingInitialState => write!(f, "initial state is not set"),
+            Self::DanglingTarget { state, target } => write!(f, "state '{}' transitions to unknown state '{}'", state, target),
+            Self::UnreachableState(state) => write!(f, "state '{}' is unreachable from the initial state", state),
+            Self::DeadEnd(state) => write!(f, "state '{}' has no outgoing transitions", state),
+            Self::ShadowedTransition { state, target } => write!(f, "transition '{}' -> '{}' is shadowed by a default transition", state, target),
+            Self::ShadowedByGlobal { state, target, input } => write!(f, "transition '{}' -> '{}' on '{}' is shadowed by a global transition", state, target, input),
+        }
+    }
+}
+
+impl StateMachine {
+    /// Checks the states graph without running it, returning every issue found.
+    pub fn validate(&self) -> Result<(), Vec<ValidationIssue>> {
+        let mut issues = Vec::new();
+        let mut state_names: Vec<&String> = self.states.keys().collect();
+        state_names.sort();
+        let global_inputs: HashSet<String> = self.global_transitions.iter().map(|t| &t.rule)
+            .chain(self.global_commands.iter().map(|c| &c.rule))
+            .filter_map(|rule| rule.describe()?.expected_input)
+            .collect();
+
+        for name in &state_names {
+            let state = &self.states[*name];
+            if state.transitions.is_empty() {
+                issues.push(ValidationIssue::DeadEnd(name.to_string()));
+            }
+            for (index, transition) in state.transitions.iter().enumerate() {
+                if !self.states.contains_key(&transition.target) {
+                    issues.push(ValidationIssue::DanglingTarget { state: name.to_string(), target: transition.target.to_string() });
+                }
+                let shadowed = state.transitions.iter().enumerate()
+                    .any(|(other_index, other)| other_index != index && shadows(other, other_index, transition, index));
+                if shadowed {
+                    issues.push(ValidationIssue::ShadowedTransition { state: name.to_string(), target: transition.target.to_string() });
+                }
+                if let Some(input) = transition.rule.describe().and_then(|d| d.expected_input).filter(|i| global_inputs.contains(i)) {
+                    issues.push(ValidationIssue::ShadowedByGlobal { state: name.to_string(), target: transition.target.to_string(), input });
+                }
+            }
+            if let Some(timeout) = state.timeout.as_ref().filter(|t| !self.states.contains_key(&t.target)) {
+                issues.push(ValidationIssue::DanglingTarget { state: name.to_string(), target: timeout.target.to_string() });
+            }
+        }
+
+        for transition in self.global_transitions.iter().filter(|t| !self.states.contains_key(&t.target)) {
+            issues.push(ValidationIssue::DanglingTarget { state: String::from(GLOBAL_STATE_NAME), target: transition.target.to_string() });
+        }
+
+        match &self.initial_state_name {
+            None => issues.push(ValidationIssue::MissingInitialState),
+            Some(initial_state_name) => {
+                let reachable = self.reachable_states(initial_state_name);
+                for name in state_names.iter().filter(|n| !reachable.contains(n.as_str())) {
+                    issues.push(ValidationIssue::UnreachableState(name.to_string()));
+                }
+            }
+        }
+
+        if issues.is_empty() {
+            Ok(())
+        } else {
+            Err(issues)
+        }
+    }
+
+    fn reachable_states(&self, initial_state_name: &str) -> HashSet<String> {
+        let mut reachable = HashSet::from([initial_state_name.to_string()]);
+        let mut pending = VecDeque::from([initial_state_name.to_string()]);
+        for transition in &self.global_transitions {
+            if reachable.insert(transition.target.to_string()) {
+                pending.push_back(transition.target.to_string());
+            }
+        }
+        while let Some(name) = pending.pop_front() {
+            let state = match self.states.get(&name) {
+                Some(s) => s,
+                None => continue,
+            };
+            let targets = state.transitions.iter().map(|t| &t.target)
+                .chain(state.timeout.iter().map(|t| &t.target));
+            for target in targets {
+                if reachable.insert(target.to_string()) {
+                    pending.push_back(target.to_string());
+                }
+            }
+        }
+        reachable
+    }
+}
+
+/// A default transition matches every action, so it hides transitions with lower priority
+/// and, being scored lowest, only ties with other default transitions added after it.
+fn shadows(default: &Transition, default_index: usize, transition: &Transition, index: usize) -> bool {
+    default.is_default() && (
+        default.priority > transition.priority
+        || (default.priority == transition.priority && transition.is_default() && default_index < index)
+    )
+}
+
+#[cfg(test)]
+mod validation_tests {
+    use std::time::Duration;
+
+    use crate::state_machine::{State, StateData, Timeout, transitions::{EqTransitionRule, DefaultTransitionRule}};
+
+    use super::*;
+
+    fn build_state_machine(extra_state_2_transitions: &[(&str, &str)]) -> StateMachine {
+        let mut state_machine = StateMachine::new(StateData::new());
+        let mut state = State::new("state-1");
+        state.add_transition("state-2", EqTransitionRule::new("1"));
+        for (target, value) in extra_state_2_transitions {
+            state.add_transition(target, EqTransitionRule::new(value));
+        }
+        state_machine.add_state(state);
+        let mut state = State::new("state-2");
+        state.add_transition("state-1", DefaultTransitionRule::new());
+        state_machine.add_state(state);
+        state_machine
+    }
+
+    #[test]
+    fn validate_should_accept_valid_state_machine() {
+        let mut state_machine = build_state_machine(&[]);
+        state_machine.set_initial_state_name("state-1").unwrap();
+
+        assert_eq!(Ok(()), state_machine.validate());
+    }
+
+    #[test]
+    fn validate_should_report_missing_initial_state() {
+        let state_machine = build_state_machine(&[]);
+
+        assert_eq!(Err(vec![ValidationIssue::MissingInitialState]), state_machine.validate());
+    }
+
+    #[test]
+    fn validate_should_report_dangling_targets_and_dead_ends() {
+        let mut state_machine = build_state_machine(&[("state-3", "3"), ("state-4", "4")]);
+        let mut state = State::new("state-3");
+        state.add_transition("missing", EqTransitionRule::new("1"));
+        state_machine.add_state(state);
+        state_machine.add_state(State::new("state-4"));
+        state_machine.set_initial_state_name("state-1").unwrap();
+
+        assert_eq!(
+            Err(vec![
+                ValidationIssue::DanglingTarget { state: "state-3".to_string(), target: "missing".to_string() },
+                ValidationIssue::DeadEnd("state-4".to_string()),
+            ]),
+            state_machine.validate()
+        );
+    }
+
+    #[test]
+    fn validate_should_report_unreachable_states() {
+        let mut state_machine = build_state_machine(&[]);
+        let mut state = State::new("state-3");
+        state.add_transition("state-1", DefaultTransitionRule::new());
+        state_machine.add_state(state);
+        state_machine.set_initial_state_name("state-1").unwrap();
+
+        assert_eq!(Err(vec![ValidationIssue::UnreachableState("state-3".to_string())]), state_machine.validate());
+    }
+
+    #[test]
+    fn validate_should_report_transitions_shadowed_by_default_transition() {
+        let mut state_machine = StateMachine::new(StateData::new());
+        let mut state = State::new("state-1");
+        state.add(Transition::new("state-1", DefaultTransitionRule::new()).with_priority(1));
+        state.add_transition("state-2", EqTransitionRule::new("1"));
+        state_machine.add_state(state);
+        let mut state = State::new("state-2");
+        state.add_transition("state-1", DefaultTransitionRule::new());
+        state.add_transition("state-2", DefaultTransitionRule::new());
+        state_machine.add_state(state);
+        state_machine.set_initial_state_name("state-1").unwrap();
+
+        assert_eq!(
+            Err(vec![
+                ValidationIssue::ShadowedTransition { state: "state-1".to_string(), target: "state-2".to_string() },
+                ValidationIssue::ShadowedTransition { state: "state-2".to_string(), target: "state-2".to_string() },
+            ]),
+            state_machine.validate()
+        );
+    }
+
+    #[test]
+    fn validate_should_accept_default_transition_added_before_other_transitions() {
+        let mut state_machine = StateMachine::new(StateData::new());
+        let mut state = State::new("state-1");
+        state.add_transition("state-1", DefaultTransitionRule::new());
+        state.add_transition("state-1", EqTransitionRule::new("1"));
+        state_machine.add_state(state);
+        state_machine.set_initial_state_name("state-1").unwrap();
+
+        assert_eq!(Ok(()), state_machine.validate());
+    }
+
+    #[test]
+    fn validate_should_consider_global_transitions() {
+        let mut state_machine = build_state_machine(&[]);
+        let mut state = State::new("state-3");
+        state.add_transition("state-1", DefaultTransitionRule::new());
+        state_machine.add_state(state);
+        state_machine.add_global_transition(Transition::new("state-3", EqTransitionRule::new("/3")));
+        state_machine.add_global_transition(Transition::new("missing", EqTransitionRule::new("/missing")));
+        state_machine.set_initial_state_name("state-1").unwrap();
+
+        assert_eq!(
+            Err(vec![ValidationIssue::DanglingTarget { state: "*".to_string(), target: "missing".to_string() }]),
+            state_machine.validate()
+        );
+    }
+
+    #[test]
+    fn validate_should_find_state_transitions_shadowed_by_global_ones() {
+        let mut state_machine = build_state_machine(&[("state-1", "/menu")]);
+        state_machine.add_global_transition(Transition::new("state-1", EqTransitionRule::new("/menu")));
+        state_machine.set_initial_state_name("state-1").unwrap();
+
+        assert_eq!(
+            Err(vec![ValidationIssue::ShadowedByGlobal { state: "state-1".to_string(), target: "state-1".to_string(), input: "/menu".to_string() }]),
+            state_machine.validate()
+        );
+    }
+
+    #[test]
+    fn validate_should_consider_state_timeouts() {
+        let mut state_machine = build_state_machine(&[]);
+        let mut state = State::new("state-3");
+        state.add_transition("state-1", DefaultTransitionRule::new());
+        state_machine.add_state(state);
+        state_machine.set_state_timeout("state-2", Timeout::new(Duration::from_secs(60), "state-3")).unwrap();
+        state_machine.set_state_timeout("state-3", Timeout::new(Duration::from_secs(60), "missing")).unwrap();
+        state_machine.set_initial_state_name("state-1").unwrap();
+
+        assert_eq!(
+            Err(vec![ValidationIssue::DanglingTarget { state: "state-3".to_string(), target: "missing".to_string() }]),
+            state_machine.validate()
+        );
+    }
+}
@@ -0,0 +1,86 @@
+use std::{fs::{self, File, OpenOptions}, io::{self, Write}, path::{Path, PathBuf}};
+
+use serde::{de::DeserializeOwned, Serialize};
+
+/// Reads a store kept as a single JSON file. Missing or empty files, e.g. one created by a
+/// readiness check, hold `T::default()`; unreadable or corrupt ones are errors, so the bot
+/// doesn't silently start over on top of them.
+pub fn load_json<T: DeserializeOwned + Default>(path: &Path) -> io::Result<T> {
+    let content = match fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(T::default()),
+        Err(error) => return Err(error),
+    };
+    if content.trim().is_empty() {
+        return Ok(T::default());
+    }
+    serde_json::from_str(&content).map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))
+}
+
+pub fn save_json<T: Serialize>(path: &Path, value: &T) -> io::Result<()> {
+    let content = serde_json::to_string(value).map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))?;
+    write_atomically(path, content.as_bytes())
+}
+
+/// Replaces the file at `path` through a temporary file in the same directory, so a process
+/// killed mid-write leaves the old content instead of a truncated file.
+pub fn write_atomically(path: &Path, content: &[u8]) -> io::Result<()> {
+    let mut temp_path = path.as_os_str().to_owned();
+    temp_path.push(".tmp");
+    let temp_path = PathBuf::from(temp_path);
+    let written = File::create(&temp_path)
+        .and_then(|mut file| file.write_all(content).and_then(|_| file.sync_all()))
+        .and_then(|_| fs::rename(&temp_path, path));
+    if written.is_err() {
+        let _ = fs::remove_file(&temp_path);
+    }
+    written
+}
+
+/// Readiness of a JSON file store: the last save must have succeeded and the file must still
+/// be writable, since a save only happens on the next change.
+pub fn check_json(path: &Path, save_error: Option<&String>) -> Result<(), String> {
+    if let Some(error) = save_error {
+        return Err(format!("{}: {}", path.display(), error));
+    }
+    OpenOptions::new().create(true).append(true).open(path)
+        .map(|_| ())
+        .map_err(|error| format!("{}: {}", path.display(), error))
+}
+
+#[cfg(test)]
+mod storage_tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    #[test]
+    fn load_json_should_start_empty_only_for_missing_or_empty_files() {
+        let path = std::env::temp_dir().join(format!("chat-storage-{}.json", uuid::Uuid::new_v4()));
+
+        assert_eq!(HashMap::new(), load_json::<HashMap<String, i64>>(&path).unwrap());
+
+        fs::write(&path, "").unwrap();
+        assert_eq!(HashMap::new(), load_json::<HashMap<String, i64>>(&path).unwrap());
+
+        fs::write(&path, "{\"a\": 1").unwrap();
+        let error = load_json::<HashMap<String, i64>>(&path).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, error.kind());
+
+        save_json(&path, &HashMap::from([("a".to_string(), 1)])).unwrap();
+        assert_eq!(HashMap::from([("a".to_string(), 1)]), load_json::<HashMap<String, i64>>(&path).unwrap());
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn save_json_should_replace_the_file_without_leaving_a_temporary_one() {
+        let path = std::env::temp_dir().join(format!("chat-storage-{}.json", uuid::Uuid::new_v4()));
+        fs::write(&path, "{\"a\": 1}").unwrap();
+
+        save_json(&path, &HashMap::from([("b".to_string(), 2)])).unwrap();
+
+        assert_eq!("{\"b\":2}", fs::read_to_string(&path).unwrap());
+        assert!(!path.with_extension("json.tmp").exists());
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -1,15 +1,65 @@
 pub mod context;
+pub mod offsets;
 
-use std::{sync::{mpsc, Arc}, thread};
+use std::{cell::{Cell, RefCell}, rc::Rc, sync::{atomic::{AtomicBool, AtomicI64, Ordering}, mpsc::{self, Receiver, RecvTimeoutError, Sender}, Arc}, thread, time::{Duration, Instant}};
 
 use mockall::automock;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tracing::{debug, error, info, info_span, warn};
 
-#[derive(Debug, Clone)]
+use crate::{health::Health, metrics::Metrics};
+
+use self::offsets::UpdateOffsets;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TelegramUser {
+    pub id: i64,
+    #[serde(default)]
+    pub is_bot: bool,
+    pub first_name: String,
+    pub last_name: Option<String>,
+    pub username: Option<String>,
+    pub language_code: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChatType {
+    Private,
+    Group,
+    Supergroup,
+    Channel,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TelegramChat {
+    pub id: i64,
+    #[serde(rename = "type")]
+    pub chat_type: ChatType,
+    pub title: Option<String>,
+}
+
+/// The message a [`TelegramMessageArrived`] replies to, reduced to what tells who wrote it.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct TelegramRepliedMessage {
+    pub message_id: i64,
+    pub from: Option<TelegramUser>,
+}
+
+/// A text message from an update, as sent by Telegram. Messages without text (stickers,
+/// photos, ...) don't parse.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
 pub struct TelegramMessageArrived {
-    pub from: Option<String>,
     pub message_id: i64,
-    pub chat_id: i64,
+    #[serde(with = "chrono::serde::ts_seconds")]
+    pub date: DateTime<Utc>,
+    pub from: Option<TelegramUser>,
+    pub chat: TelegramChat,
     pub text: String,
+    #[serde(default, rename = "reply_to_message")]
+    pub reply_to: Option<TelegramRepliedMessage>,
 }
 
 pub struct SendTelegramMessage {
@@ -17,75 +67,219 @@ pub struct SendTelegramMessage {
     pub text: String,
 }
 
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TelegramError {
+    /// The request couldn't be made or its response read.
+    Request(String),
+    /// Telegram answered with an error, e.g. the user blocked the bot.
+    Api(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct BotCommand {
+    pub command: String,
+    pub description: String,
+}
+
+/// How often listeners are ticked while waiting for messages.
+const TICK_INTERVAL: Duration = Duration::from_secs(1);
+/// How long the poller waits before trying again after a failed getUpdates.
+const POLL_RETRY_DELAY: Duration = Duration::from_secs(5);
+/// Extra time a getUpdates request gets beyond its long poll timeout before it's abandoned.
+pub const POLL_REQUEST_MARGIN: Duration = Duration::from_secs(10);
+/// How many times an update the listeners failed to handle is received before it's skipped.
+const MAX_DELIVERY_ATTEMPTS: u32 = 5;
+
 pub trait TelegramListener {
-    fn message_arrived(&self, message: TelegramMessageArrived);
+    /// Returns whether the message was handled; unhandled ones are received again.
+    fn message_arrived(&self, message: TelegramMessageArrived) -> bool;
+
+    /// Called periodically from the receiving thread, so listeners can run scheduled work.
+    fn tick(&self) {}
 }
 
 pub trait TelegramReceiver {
-    fn add_message_arrived_listener(&mut self, listener: Arc<dyn TelegramListener>);
+    fn add_message_arrived_listener(&mut self, listener: Rc<dyn TelegramListener>);
+    /// Receives until the shutdown flag is set, then handles what was already received.
     fn start_receive(&self);
 }
 
+/// An update from getUpdates: its id and its text message, if it has one.
+type ReceivedUpdate = (i64, Option<TelegramMessageArrived>);
+
+/// Stands for "no update acknowledged yet" in the offset shared with the poller.
+const NO_UPDATE: i64 = i64::MIN;
+
 struct LongPollingTelegramReceiver {
-    token: String,
-    listeners: Vec<Arc<dyn TelegramListener>>,
+    bot_url: String,
+    poll_timeout: Duration,
+    listeners: Vec<Rc<dyn TelegramListener>>,
+    metrics: Arc<Metrics>,
+    health: Arc<Health>,
+    offsets: RefCell<Box<dyn UpdateOffsets>>,
+    acknowledged: Arc<AtomicI64>,
+    /// Asks the poller to forward the unacknowledged updates again.
+    redeliver: Arc<AtomicBool>,
+    /// Update the listeners failed to handle and how many times it was tried.
+    retrying: Cell<Option<(i64, u32)>>,
+    shutdown: Arc<AtomicBool>,
 }
 impl LongPollingTelegramReceiver {
-    fn new(token: &str) -> Self {
+    fn new(api_base_url: &str, token: &str, poll_timeout: Duration, metrics: Arc<Metrics>, health: Arc<Health>, offsets: Box<dyn UpdateOffsets>, shutdown: Arc<AtomicBool>) -> Self {
+        let acknowledged = Arc::new(AtomicI64::new(offsets.last_acknowledged().unwrap_or(NO_UPDATE)));
         Self {
-            token: String::from(token),
+            bot_url: bot_url(api_base_url, token),
+            poll_timeout,
             listeners: Vec::new(),
+            metrics,
+            health,
+            offsets: RefCell::new(offsets),
+            acknowledged,
+            redeliver: Arc::new(AtomicBool::new(false)),
+            retrying: Cell::new(None),
+            shutdown,
         }
     }
 
     fn poll_messages(&self) {
         let (tx, rx) = mpsc::channel();
-        let token = String::from(&self.token);
+        self.spawn_poller(tx);
+        self.receive(rx);
+    }
+
+    /// Telegram drops the updates before the requested offset, so the poller only confirms
+    /// updates acknowledged by the listeners and skips the ones it already forwarded.
+    fn spawn_poller(&self, tx: Sender<ReceivedUpdate>) {
+        let bot_url = String::from(&self.bot_url);
+        let timeout = self.poll_timeout.as_secs();
+        // reqwest gives up after 30 seconds by default, which would cut longer polls short.
+        let client = reqwest::blocking::Client::builder()
+            .timeout(self.poll_timeout + POLL_REQUEST_MARGIN)
+            .build()
+            .expect("failed to build the getUpdates client");
+        let metrics = self.metrics.clone();
+        let health = self.health.clone();
+        let acknowledged = self.acknowledged.clone();
+        let redeliver = self.redeliver.clone();
+        let shutdown = self.shutdown.clone();
         thread::spawn(move || {
-            let mut last_offset: Option<i64> = None;
-            loop {
-                println!("GetUpdate...");
-                let timeout = 15;
-                let offset = match last_offset {
-                    Some(n) => (n + 1).to_string(),
-                    None => "".to_string()
+            let mut last_received: Option<i64> = None;
+            while !shutdown.load(Ordering::SeqCst) {
+                if redeliver.swap(false, Ordering::SeqCst) {
+                    last_received = None;
+                    thread::sleep(POLL_RETRY_DELAY);
+                }
+                let offset = match acknowledged.load(Ordering::SeqCst) {
+                    NO_UPDATE => "".to_string(),
+                    n => (n + 1).to_string(),
+                };
+                debug!(offset, "getUpdates");
+                let url = format!("{}/getUpdates?timeout={}&offset={}", &bot_url, timeout, &offset);
+                let started = Instant::now();
+                let resp = client.get(url).send().and_then(|resp| resp.json::<serde_json::Value>());
+                metrics.telegram_api_call("getUpdates", started);
+                let results = match resp.as_ref().map(|resp| resp["result"].as_array()) {
+                    Ok(Some(results)) => results,
+                    Ok(None) => {
+                        warn!(response = ?resp, "getUpdates failed");
+                        thread::sleep(POLL_RETRY_DELAY);
+                        continue;
+                    },
+                    Err(error) => {
+                        warn!(%error, "getUpdates failed");
+                        thread::sleep(POLL_RETRY_DELAY);
+                        continue;
+                    },
                 };
-                let url = format!("https://api.telegram.org/bot{}/getUpdates?timeout={}&offset={}", &token, timeout, &offset);
-                let resp: serde_json::Value = reqwest::blocking::get(url).unwrap()
-                    .json().unwrap();
-
-                println!("{:?}", resp);
-                for result in resp["result"].as_array().unwrap() {
-                    last_offset = Some(result["update_id"].as_i64().unwrap());
-                    
-                    if result["message"].is_object() {
-                        let username = match result["message"]["from"]["username"].as_str() {
-                            Some(name) => Some(String::from(name)),
-                            None => None
-                        };
-                        let message = TelegramMessageArrived {
-                            from: username,
-                            message_id: result["message"]["message_id"].as_i64().unwrap(),
-                            chat_id: result["message"]["chat"]["id"].as_i64().unwrap(),
-                            text: String::from(result["message"]["text"].as_str().unwrap()),
-                        };
-                        tx.send(message).unwrap();
-                    }                    
+                health.polled(Utc::now());
+                debug!(updates = results.len(), "updates received");
 
+                let mut forwarded = 0;
+                for result in results {
+                    let update_id = result["update_id"].as_i64().unwrap();
+                    if last_received.is_some_and(|last_received| update_id <= last_received) {
+                        continue;
+                    }
+                    last_received = Some(update_id);
+                    let message = if result["message"].is_object() {
+                        serde_json::from_value::<TelegramMessageArrived>(result["message"].clone())
+                            .map_err(|error| debug!(update_id, %error, "ignoring message"))
+                            .ok()
+                    } else {
+                        None
+                    };
+                    if tx.send((update_id, message)).is_err() {
+                        return;
+                    }
+                    forwarded += 1;
                 }
-            }    
+                if forwarded == 0 && !results.is_empty() {
+                    // Only updates still being handled came back, wait for their acknowledgement.
+                    thread::sleep(TICK_INTERVAL);
+                }
+            }
         });
-                    
-        for message in rx {
+    }
+
+    fn receive(&self, rx: Receiver<ReceivedUpdate>) {
+        while !self.shutdown.load(Ordering::SeqCst) {
+            match rx.recv_timeout(TICK_INTERVAL) {
+                Ok(update) => self.dispatch(update),
+                Err(RecvTimeoutError::Timeout) => {},
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+            self.tick();
+        }
+
+        info!("shutting down, handling updates already received");
+        while let Ok(update) = rx.try_recv() {
+            self.dispatch(update);
+        }
+        self.tick();
+    }
+
+    /// Acknowledges the update once every listener handled it. A failed update is asked
+    /// again, holding back the ones after it, until it's handled or runs out of attempts.
+    fn dispatch(&self, (update_id, message): ReceivedUpdate) {
+        let attempts = match self.retrying.get() {
+            Some((retrying, _)) if retrying != update_id => return,
+            Some((_, attempts)) => attempts,
+            None => 0,
+        };
+        if let Some(message) = message {
+            let span = info_span!("update", update_id, chat_id = message.chat.id);
+            let _entered = span.enter();
+            let mut handled = true;
             for listener in &self.listeners {
-                listener.message_arrived(message.clone());
+                handled &= listener.message_arrived(message.clone());
+            }
+            if !handled && attempts + 1 < MAX_DELIVERY_ATTEMPTS {
+                warn!(attempts = attempts + 1, "update not handled, receiving it again");
+                self.retrying.set(Some((update_id, attempts + 1)));
+                self.redeliver.store(true, Ordering::SeqCst);
+                return;
+            }
+            if !handled {
+                error!(attempts = MAX_DELIVERY_ATTEMPTS, "update not handled, skipping it");
             }
-            println!("{:?}", message);
+        }
+        self.retrying.set(None);
+        self.acknowledged.store(update_id, Ordering::SeqCst);
+        let acknowledged = self.offsets.borrow_mut().acknowledge(update_id);
+        self.health.record_storage("offsets", acknowledged.as_ref().map(|_| ()).map_err(|error| error.to_string()));
+        if let Err(error) = acknowledged {
+            error!(update_id, %error, "failed to save update offset");
+        }
+    }
+
+    fn tick(&self) {
+        for listener in &self.listeners {
+            listener.tick();
         }
     }
 }
 impl TelegramReceiver for LongPollingTelegramReceiver {
-    fn add_message_arrived_listener(&mut self, listener: Arc<dyn TelegramListener>) {
+    fn add_message_arrived_listener(&mut self, listener: Rc<dyn TelegramListener>) {
         self.listeners.push(listener);
     }
 
@@ -94,37 +288,254 @@ impl TelegramReceiver for LongPollingTelegramReceiver {
     }
 }
 
+/// Base of the Bot API methods, e.g. `https://api.telegram.org/bot<token>`.
+fn bot_url(api_base_url: &str, token: &str) -> String {
+    format!("{}/bot{}", api_base_url.trim_end_matches('/'), token)
+}
+
 #[automock]
 pub trait TelegramSender {
-    fn send_message(&self, message: SendTelegramMessage);
+    fn send_message(&self, message: SendTelegramMessage) -> Result<(), TelegramError>;
+    fn set_my_commands(&self, commands: Vec<BotCommand>, language_code: Option<String>) -> Result<(), TelegramError>;
 }
 struct TelegramSenderImpl {
-    token: String,
+    bot_url: String,
+    metrics: Arc<Metrics>,
 }
 impl TelegramSenderImpl {
-    fn new(token: &str) -> Self {
+    fn new(api_base_url: &str, token: &str, metrics: Arc<Metrics>) -> Self {
         Self {
-            token: String::from(token),
+            bot_url: bot_url(api_base_url, token),
+            metrics,
         }
     }
 }
 impl TelegramSender for TelegramSenderImpl {
-    fn send_message(&self, message: SendTelegramMessage) {
+    fn send_message(&self, message: SendTelegramMessage) -> Result<(), TelegramError> {
         let chat_id = message.chat_id;        
         let text = &message.text;
-        let url = format!("https://api.telegram.org/bot{}/sendMessage?chat_id={}&text={}",
-            &self.token, 
+        let url = format!("{}/sendMessage?chat_id={}&text={}",
+            &self.bot_url, 
             chat_id, 
             urlencoding::encode(text),
         );
 
-        let result = reqwest::blocking::get(url).unwrap();
+        let started = Instant::now();
+        let result = reqwest::blocking::get(url).map_err(|e| TelegramError::Request(e.to_string()));
+        self.metrics.telegram_api_call("sendMessage", started);
+        let result = result?;
         let status = result.status();
-        println!("{}", status);
+        debug!(chat_id, %status, "sendMessage");
         if !status.is_success() {            
-            if let Ok(json) = result.json::<serde_json::Value>() {
-                println!("{:?}", json);
-            }    
+            let description = result.json::<serde_json::Value>().ok()
+                .and_then(|json| json["description"].as_str().map(String::from))
+                .unwrap_or_else(|| status.to_string());
+            return Err(TelegramError::Api(description));
         }        
+        Ok(())
+    }
+
+    fn set_my_commands(&self, commands: Vec<BotCommand>, language_code: Option<String>) -> Result<(), TelegramError> {
+        let url = format!("{}/setMyCommands", &self.bot_url);
+        let mut body = json!({ "commands": commands });
+        if let Some(language_code) = language_code {
+            body["language_code"] = json!(language_code);
+        }
+
+        let started = Instant::now();
+        let result = reqwest::blocking::Client::new().post(url).json(&body).send()
+            .map_err(|e| TelegramError::Request(e.to_string()));
+        self.metrics.telegram_api_call("setMyCommands", started);
+        let result = result?;
+        let status = result.status();
+        debug!(%status, "setMyCommands");
+        if !status.is_success() {
+            let description = result.json::<serde_json::Value>().ok()
+                .and_then(|json| json["description"].as_str().map(String::from))
+                .unwrap_or_else(|| status.to_string());
+            return Err(TelegramError::Api(description));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod telegram_tests {
+    use std::sync::Mutex;
+
+    use super::{*, offsets::MockUpdateOffsets};
+
+    #[derive(Default)]
+    struct RecordingListener {
+        texts: Mutex<Vec<String>>,
+        /// Texts the listener fails to handle.
+        failing: Mutex<Vec<String>>,
+        ticks: Mutex<usize>,
+    }
+    impl TelegramListener for RecordingListener {
+        fn message_arrived(&self, message: TelegramMessageArrived) -> bool {
+            let handled = !self.failing.lock().unwrap().contains(&message.text);
+            self.texts.lock().unwrap().push(message.text);
+            handled
+        }
+
+        fn tick(&self) {
+            *self.ticks.lock().unwrap() += 1;
+        }
+    }
+
+    fn arrived(text: &str) -> TelegramMessageArrived {
+        serde_json::from_value(serde_json::json!({
+            "message_id": 7,
+            "date": 1760000000,
+            "chat": { "id": 111000, "type": "private" },
+            "text": text
+        })).unwrap()
+    }
+
+    #[test]
+    fn telegram_message_should_parse_user_and_chat() {
+        let message = serde_json::json!({
+            "message_id": 7,
+            "date": 1760000000,
+            "from": { "id": 222000, "is_bot": false, "first_name": "Ana", "last_name": "Silva", "username": "ana", "language_code": "pt-br" },
+            "chat": { "id": -100123, "type": "supergroup", "title": "Turma" },
+            "text": "oi"
+        });
+
+        let message: TelegramMessageArrived = serde_json::from_value(message).unwrap();
+
+        assert_eq!("Ana", message.from.as_ref().unwrap().first_name);
+        assert_eq!(Some("pt-br".to_string()), message.from.unwrap().language_code);
+        assert_eq!(ChatType::Supergroup, message.chat.chat_type);
+        assert_eq!(-100123, message.chat.id);
+        assert_eq!(1760000000, message.date.timestamp());
+        assert_eq!(None, message.reply_to);
+    }
+
+    #[test]
+    fn telegram_message_should_parse_reply_to_bot() {
+        let message = serde_json::json!({
+            "message_id": 8,
+            "date": 1760000000,
+            "from": { "id": 222000, "first_name": "Ana" },
+            "chat": { "id": -100123, "type": "group", "title": "Turma" },
+            "text": "Ana Silva",
+            "reply_to_message": {
+                "message_id": 7,
+                "date": 1759999990,
+                "from": { "id": 999000, "is_bot": true, "first_name": "Bot", "username": "cadastro_bot" },
+                "chat": { "id": -100123, "type": "group", "title": "Turma" },
+                "text": "Qual o nome?"
+            }
+        });
+
+        let message: TelegramMessageArrived = serde_json::from_value(message).unwrap();
+
+        let replied_from = message.reply_to.unwrap().from.unwrap();
+        assert!(replied_from.is_bot);
+        assert_eq!(Some("cadastro_bot".to_string()), replied_from.username);
+    }
+
+    #[test]
+    fn telegram_message_should_not_parse_without_text() {
+        let message = serde_json::json!({
+            "message_id": 7,
+            "date": 1760000000,
+            "chat": { "id": 111000, "type": "private" },
+            "sticker": {}
+        });
+
+        assert!(serde_json::from_value::<TelegramMessageArrived>(message).is_err());
+    }
+
+    #[test]
+    fn receiver_should_handle_received_updates_and_acknowledge_them_on_shutdown() {
+        let mut offsets = MockUpdateOffsets::new();
+        offsets.expect_last_acknowledged().return_const(Some(10));
+        let mut sequence = mockall::Sequence::new();
+        for update_id in [11, 12, 13] {
+            offsets.expect_acknowledge()
+                .withf(move |acknowledged| *acknowledged == update_id)
+                .times(1)
+                .in_sequence(&mut sequence)
+                .returning(|_| Ok(()));
+        }
+        let shutdown = Arc::new(AtomicBool::new(true));
+        let mut receiver = LongPollingTelegramReceiver::new("http://localhost", "token", Duration::from_secs(1), Arc::new(Metrics::new()), Arc::new(Health::new(Utc::now(), Duration::from_secs(1))), Box::new(offsets), shutdown);
+        let listener = Rc::new(RecordingListener::default());
+        receiver.add_message_arrived_listener(listener.clone());
+        let (tx, rx) = mpsc::channel();
+        tx.send((11, Some(arrived("oi")))).unwrap();
+        tx.send((12, None)).unwrap();
+        tx.send((13, Some(arrived("1")))).unwrap();
+
+        receiver.receive(rx);
+
+        assert_eq!(vec!["oi".to_string(), "1".to_string()], *listener.texts.lock().unwrap());
+        assert_eq!(1, *listener.ticks.lock().unwrap());
+        assert_eq!(13, receiver.acknowledged.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn receiver_should_receive_unhandled_updates_again_before_acknowledging_them() {
+        let mut offsets = MockUpdateOffsets::new();
+        offsets.expect_last_acknowledged().return_const(Some(10));
+        let mut sequence = mockall::Sequence::new();
+        for update_id in [11, 12] {
+            offsets.expect_acknowledge()
+                .withf(move |acknowledged| *acknowledged == update_id)
+                .times(1)
+                .in_sequence(&mut sequence)
+                .returning(|_| Ok(()));
+        }
+        let shutdown = Arc::new(AtomicBool::new(true));
+        let mut receiver = LongPollingTelegramReceiver::new("http://localhost", "token", Duration::from_secs(1), Arc::new(Metrics::new()), Arc::new(Health::new(Utc::now(), Duration::from_secs(1))), Box::new(offsets), shutdown);
+        let listener = Rc::new(RecordingListener::default());
+        listener.failing.lock().unwrap().push("sim".to_string());
+        receiver.add_message_arrived_listener(listener.clone());
+        let (tx, rx) = mpsc::channel();
+        tx.send((11, Some(arrived("sim")))).unwrap();
+        tx.send((12, Some(arrived("oi")))).unwrap();
+
+        receiver.receive(rx);
+
+        assert_eq!(vec!["sim".to_string()], *listener.texts.lock().unwrap());
+        assert_eq!(10, receiver.acknowledged.load(Ordering::SeqCst));
+        assert!(receiver.redeliver.load(Ordering::SeqCst));
+
+        listener.failing.lock().unwrap().clear();
+        let (tx, rx) = mpsc::channel();
+        tx.send((11, Some(arrived("sim")))).unwrap();
+        tx.send((12, Some(arrived("oi")))).unwrap();
+
+        receiver.receive(rx);
+
+        assert_eq!(vec!["sim".to_string(), "sim".to_string(), "oi".to_string()], *listener.texts.lock().unwrap());
+        assert_eq!(12, receiver.acknowledged.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn receiver_should_skip_updates_that_keep_failing() {
+        let mut offsets = MockUpdateOffsets::new();
+        offsets.expect_last_acknowledged().return_const(Some(10));
+        offsets.expect_acknowledge()
+            .withf(|acknowledged| *acknowledged == 11)
+            .times(1)
+            .returning(|_| Ok(()));
+        let shutdown = Arc::new(AtomicBool::new(true));
+        let mut receiver = LongPollingTelegramReceiver::new("http://localhost", "token", Duration::from_secs(1), Arc::new(Metrics::new()), Arc::new(Health::new(Utc::now(), Duration::from_secs(1))), Box::new(offsets), shutdown);
+        let listener = Rc::new(RecordingListener::default());
+        listener.failing.lock().unwrap().push("sim".to_string());
+        receiver.add_message_arrived_listener(listener.clone());
+        let (tx, rx) = mpsc::channel();
+        for _ in 0..MAX_DELIVERY_ATTEMPTS {
+            tx.send((11, Some(arrived("sim")))).unwrap();
+        }
+
+        receiver.receive(rx);
+
+        assert_eq!(MAX_DELIVERY_ATTEMPTS as usize, listener.texts.lock().unwrap().len());
+        assert_eq!(11, receiver.acknowledged.load(Ordering::SeqCst));
     }
 }
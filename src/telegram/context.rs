@@ -1,26 +1,46 @@
-use std::{sync::Arc, env};
+use std::{sync::Arc, time::Duration};
 
-use super::*;
+use crate::config::{Config, ConfigError};
+
+use super::{*, offsets::{UpdateOffsetsFile, UpdateOffsetsInMemory}};
 
 pub struct TelegramContext {    
     pub telegram_sender: Arc<dyn TelegramSender>,
+    api_base_url: String,
+    token: String,
+    poll_timeout: Duration,
+    offset_file: Option<String>,
+    metrics: Arc<Metrics>,
+    health: Arc<Health>,
 }
 impl TelegramContext {
-    pub fn build() -> Self {
-        let telegram_sender = Arc::new(Self::build_telegram_sender());
+    /// The token isn't checked here, so commands that don't talk to Telegram run without one.
+    pub fn build(config: &Config, metrics: Arc<Metrics>, health: Arc<Health>) -> Self {
+        let api_base_url = config.telegram.api_base_url.clone();
+        let token = config.telegram.token.clone().unwrap_or_default();
+        let telegram_sender = Arc::new(TelegramSenderImpl::new(&api_base_url, &token, metrics.clone()));
 
         Self {
             telegram_sender,
+            api_base_url,
+            token,
+            poll_timeout: Duration::from_secs(config.telegram.poll_timeout_secs),
+            offset_file: config.telegram.offset_file.clone(),
+            metrics,
+            health,
         }
     }
 
-    fn build_telegram_sender() -> impl TelegramSender {                    
-        let token = env::var("TELEGRAM_BOT_TOKEN").unwrap();
-        TelegramSenderImpl::new(&token)
+    /// A receiver that stops once `shutdown` is set, e.g. by SIGTERM.
+    pub fn new_telegram_receiver(&self, shutdown: Arc<AtomicBool>) -> Result<impl TelegramReceiver, ConfigError> {
+        let offsets = self.build_update_offsets()?;
+        Ok(LongPollingTelegramReceiver::new(&self.api_base_url, &self.token, self.poll_timeout, self.metrics.clone(), self.health.clone(), offsets, shutdown))
     }
 
-    pub fn new_telegram_receiver(&self) -> impl TelegramReceiver {
-        let token = env::var("TELEGRAM_BOT_TOKEN").unwrap();
-        LongPollingTelegramReceiver::new(&token)
+    fn build_update_offsets(&self) -> Result<Box<dyn UpdateOffsets>, ConfigError> {
+        Ok(match &self.offset_file {
+            Some(path) => Box::new(UpdateOffsetsFile::new(path).map_err(|error| ConfigError::storage(path, error))?),
+            None => Box::new(UpdateOffsetsInMemory::new()),
+        })
     }
 }
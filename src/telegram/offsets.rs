@@ -0,0 +1,91 @@
+use std::{fs, io, path::PathBuf};
+
+use mockall::automock;
+
+use crate::storage::write_atomically;
+
+/// Remembers the last update handled by the listeners, so a restarted bot asks Telegram for
+/// the updates after it instead of losing or reprocessing a whole batch.
+#[automock]
+pub trait UpdateOffsets {
+    fn last_acknowledged(&self) -> Option<i64>;
+    fn acknowledge(&mut self, update_id: i64) -> io::Result<()>;
+}
+
+pub struct UpdateOffsetsInMemory {
+    last_acknowledged: Option<i64>,
+}
+impl UpdateOffsetsInMemory {
+    pub fn new() -> Self {
+        Self {
+            last_acknowledged: None,
+        }
+    }
+}
+impl UpdateOffsets for UpdateOffsetsInMemory {
+    fn last_acknowledged(&self) -> Option<i64> {
+        self.last_acknowledged
+    }
+
+    fn acknowledge(&mut self, update_id: i64) -> io::Result<()> {
+        self.last_acknowledged = Some(update_id);
+        Ok(())
+    }
+}
+
+/// Keeps the last acknowledged update id as the only content of a file.
+pub struct UpdateOffsetsFile {
+    path: PathBuf,
+    last_acknowledged: Option<i64>,
+}
+impl UpdateOffsetsFile {
+    /// Fails when the file exists but can't be read or doesn't hold an update id.
+    pub fn new(path: &str) -> io::Result<Self> {
+        let path = PathBuf::from(path);
+        let content = match fs::read_to_string(&path) {
+            Ok(content) => content,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(error) => return Err(error),
+        };
+        let last_acknowledged = match content.trim() {
+            "" => None,
+            update_id => Some(update_id.parse().map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))?),
+        };
+        Ok(Self {
+            path,
+            last_acknowledged,
+        })
+    }
+}
+impl UpdateOffsets for UpdateOffsetsFile {
+    fn last_acknowledged(&self) -> Option<i64> {
+        self.last_acknowledged
+    }
+
+    fn acknowledge(&mut self, update_id: i64) -> io::Result<()> {
+        self.last_acknowledged = Some(update_id);
+        write_atomically(&self.path, update_id.to_string().as_bytes())
+    }
+}
+
+#[cfg(test)]
+mod offsets_tests {
+    use super::*;
+
+    #[test]
+    fn update_offsets_file_should_load_acknowledged_update() {
+        let path = std::env::temp_dir().join(format!("telegram-offset-{}", uuid::Uuid::new_v4()));
+        let path = path.to_str().unwrap();
+
+        assert_eq!(None, UpdateOffsetsFile::new(path).unwrap().last_acknowledged());
+
+        UpdateOffsetsFile::new(path).unwrap().acknowledge(4200).unwrap();
+
+        assert_eq!(Some(4200), UpdateOffsetsFile::new(path).unwrap().last_acknowledged());
+
+        fs::write(path, "not an update id").unwrap();
+
+        assert!(UpdateOffsetsFile::new(path).is_err());
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,315 @@
+pub mod context;
+
+use std::{fs::{self, OpenOptions}, io::{self, Write}, path::PathBuf};
+
+use chrono::{DateTime, Utc};
+use mockall::automock;
+use regex::Regex;
+use rusqlite::{params, Connection};
+use serde::{Deserialize, Serialize};
+
+/// What happened in a conversation, recorded for support investigations.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum TranscriptEvent {
+    /// A message from the user.
+    Inbound { text: String },
+    /// The state machine moved from one state to another on `action`.
+    Transition { from: String, to: String, action: String, outputs: Vec<String> },
+    /// A message sent to the chat, as an answer or scheduled.
+    Outbound { text: String },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TranscriptEntry {
+    pub chat_id: String,
+    /// The user whose session this is, when each user has their own in a group chat.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user_id: Option<String>,
+    pub recorded_on: DateTime<Utc>,
+    pub event: TranscriptEvent,
+}
+impl TranscriptEntry {
+    pub fn new(chat_id: &str, event: TranscriptEvent) -> Self {
+        Self {
+            chat_id: chat_id.to_string(),
+            user_id: None,
+            recorded_on: Utc::now(),
+            event,
+        }
+    }
+
+    pub fn with_user_id(mut self, user_id: &str) -> Self {
+        self.user_id = Some(user_id.to_string());
+        self
+    }
+}
+
+#[derive(Debug)]
+pub enum TranscriptError {
+    Serialize(serde_json::Error),
+    Io(io::Error),
+    Sqlite(rusqlite::Error),
+}
+impl From<serde_json::Error> for TranscriptError {
+    fn from(error: serde_json::Error) -> Self {
+        TranscriptError::Serialize(error)
+    }
+}
+impl From<io::Error> for TranscriptError {
+    fn from(error: io::Error) -> Self {
+        TranscriptError::Io(error)
+    }
+}
+impl From<rusqlite::Error> for TranscriptError {
+    fn from(error: rusqlite::Error) -> Self {
+        TranscriptError::Sqlite(error)
+    }
+}
+
+#[automock]
+pub trait Transcripts {
+    fn record(&mut self, entry: TranscriptEntry) -> Result<(), TranscriptError>;
+    /// Entries of a chat, oldest first. Entries that can't be parsed are skipped.
+    fn chat_transcript(&self, chat_id: &str) -> Result<Vec<TranscriptEntry>, TranscriptError>;
+}
+
+pub struct TranscriptsInMemory {
+    entries: Vec<TranscriptEntry>,
+}
+impl TranscriptsInMemory {
+    pub fn new() -> Self {
+        Self {
+            entries: Vec::new(),
+        }
+    }
+}
+impl Transcripts for TranscriptsInMemory {
+    fn record(&mut self, entry: TranscriptEntry) -> Result<(), TranscriptError> {
+        self.entries.push(entry);
+        Ok(())
+    }
+
+    fn chat_transcript(&self, chat_id: &str) -> Result<Vec<TranscriptEntry>, TranscriptError> {
+        Ok(self.entries.iter().filter(|entry| entry.chat_id == chat_id).cloned().collect())
+    }
+}
+
+/// Appends one JSON entry per line, so the file can be followed and grepped.
+pub struct TranscriptsJsonLines {
+    path: PathBuf,
+}
+impl TranscriptsJsonLines {
+    pub fn new(path: &str) -> Self {
+        Self {
+            path: PathBuf::from(path),
+        }
+    }
+}
+impl Transcripts for TranscriptsJsonLines {
+    fn record(&mut self, entry: TranscriptEntry) -> Result<(), TranscriptError> {
+        let line = serde_json::to_string(&entry)?;
+        let mut file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        writeln!(file, "{}", line)?;
+        Ok(())
+    }
+
+    fn chat_transcript(&self, chat_id: &str) -> Result<Vec<TranscriptEntry>, TranscriptError> {
+        let content = match fs::read_to_string(&self.path) {
+            Ok(content) => content,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(error) => return Err(error.into()),
+        };
+        Ok(content.lines()
+            .filter_map(|line| serde_json::from_str::<TranscriptEntry>(line).ok())
+            .filter(|entry| entry.chat_id == chat_id)
+            .collect())
+    }
+}
+
+/// Keeps entries in a SQLite table indexed by chat, for larger deployments.
+pub struct TranscriptsSqlite {
+    connection: Connection,
+}
+impl TranscriptsSqlite {
+    pub fn new(path: &str) -> rusqlite::Result<Self> {
+        let connection = Connection::open(path)?;
+        connection.execute_batch(
+            "CREATE TABLE IF NOT EXISTS transcript (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                chat_id TEXT NOT NULL,
+                user_id TEXT,
+                recorded_on TEXT NOT NULL,
+                event TEXT NOT NULL
+            );
+            CREATE INDEX IF NOT EXISTS transcript_chat_id ON transcript (chat_id);"
+        )?;
+        // Tables created before users were recorded lack the column.
+        if connection.prepare("SELECT user_id FROM transcript LIMIT 0").is_err() {
+            connection.execute("ALTER TABLE transcript ADD COLUMN user_id TEXT", [])?;
+        }
+        Ok(Self {
+            connection,
+        })
+    }
+}
+impl Transcripts for TranscriptsSqlite {
+    fn record(&mut self, entry: TranscriptEntry) -> Result<(), TranscriptError> {
+        self.connection.execute(
+            "INSERT INTO transcript (chat_id, user_id, recorded_on, event) VALUES (?1, ?2, ?3, ?4)",
+            params![entry.chat_id, entry.user_id, entry.recorded_on.to_rfc3339(), serde_json::to_string(&entry.event)?],
+        )?;
+        Ok(())
+    }
+
+    fn chat_transcript(&self, chat_id: &str) -> Result<Vec<TranscriptEntry>, TranscriptError> {
+        let mut statement = self.connection
+            .prepare("SELECT chat_id, user_id, recorded_on, event FROM transcript WHERE chat_id = ?1 ORDER BY id")?;
+        let rows = statement.query_map(params![chat_id], |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, Option<String>>(1)?, row.get::<_, String>(2)?, row.get::<_, String>(3)?))
+        })?;
+        Ok(rows.filter_map(Result::ok)
+            .filter_map(|(chat_id, user_id, recorded_on, event)| Some(TranscriptEntry {
+                chat_id,
+                user_id,
+                recorded_on: DateTime::parse_from_rfc3339(&recorded_on).ok()?.with_timezone(&Utc),
+                event: serde_json::from_str(&event).ok()?,
+            }))
+            .collect())
+    }
+}
+
+/// Phone-like digit sequences: at least eight characters of digits, spaces, dots, dashes or
+/// parentheses, optionally starting with `+` or `(`.
+const PHONE_PATTERN: &str = r"\+?\(?\d[\d\s().-]{6,}\d";
+const PHONE_REPLACEMENT: &str = "[phone]";
+
+/// Records into `transcripts` with phone numbers replaced by `[phone]`.
+pub struct RedactedTranscripts {
+    transcripts: Box<dyn Transcripts>,
+    phone: Regex,
+}
+impl RedactedTranscripts {
+    pub fn new(transcripts: Box<dyn Transcripts>) -> Self {
+        Self {
+            transcripts,
+            phone: Regex::new(PHONE_PATTERN).unwrap(),
+        }
+    }
+
+    fn redact(&self, text: &str) -> String {
+        self.phone.replace_all(text, PHONE_REPLACEMENT).into_owned()
+    }
+}
+impl Transcripts for RedactedTranscripts {
+    fn record(&mut self, mut entry: TranscriptEntry) -> Result<(), TranscriptError> {
+        entry.event = match entry.event {
+            TranscriptEvent::Inbound { text } => TranscriptEvent::Inbound { text: self.redact(&text) },
+            TranscriptEvent::Outbound { text } => TranscriptEvent::Outbound { text: self.redact(&text) },
+            TranscriptEvent::Transition { from, to, action, outputs } => TranscriptEvent::Transition {
+                from,
+                to,
+                action: self.redact(&action),
+                outputs: outputs.iter().map(|output| self.redact(output)).collect(),
+            },
+        };
+        self.transcripts.record(entry)
+    }
+
+    fn chat_transcript(&self, chat_id: &str) -> Result<Vec<TranscriptEntry>, TranscriptError> {
+        self.transcripts.chat_transcript(chat_id)
+    }
+}
+
+#[cfg(test)]
+mod transcript_tests {
+    use super::*;
+
+    fn inbound(text: &str) -> TranscriptEvent {
+        TranscriptEvent::Inbound { text: text.to_string() }
+    }
+
+    #[test]
+    fn transcripts_in_memory_should_query_per_chat() {
+        let mut transcripts = TranscriptsInMemory::new();
+        transcripts.record(TranscriptEntry::new("1", inbound("oi"))).unwrap();
+        transcripts.record(TranscriptEntry::new("2", inbound("olá"))).unwrap();
+        transcripts.record(TranscriptEntry::new("1", TranscriptEvent::Outbound { text: "1: Novo registro".to_string() })).unwrap();
+
+        let transcript = transcripts.chat_transcript("1").unwrap();
+
+        assert_eq!(vec![inbound("oi"), TranscriptEvent::Outbound { text: "1: Novo registro".to_string() }],
+            transcript.into_iter().map(|entry| entry.event).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn transcripts_json_lines_should_append_entries() {
+        let path = std::env::temp_dir().join(format!("chat-transcript-{}.jsonl", uuid::Uuid::new_v4()));
+        let path = path.to_str().unwrap();
+        TranscriptsJsonLines::new(path).record(TranscriptEntry::new("1", inbound("oi"))).unwrap();
+        TranscriptsJsonLines::new(path).record(TranscriptEntry::new("2", inbound("olá"))).unwrap();
+
+        let transcript = TranscriptsJsonLines::new(path).chat_transcript("1").unwrap();
+
+        assert_eq!(2, fs::read_to_string(path).unwrap().lines().count());
+        assert_eq!(vec![inbound("oi")], transcript.into_iter().map(|entry| entry.event).collect::<Vec<_>>());
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn transcripts_sqlite_should_keep_entries_in_order() {
+        let mut transcripts = TranscriptsSqlite::new(":memory:").unwrap();
+        let transition = TranscriptEvent::Transition {
+            from: "menu".to_string(),
+            to: "register-name".to_string(),
+            action: "1".to_string(),
+            outputs: vec!["Qual o nome?".to_string()],
+        };
+        transcripts.record(TranscriptEntry::new("1", inbound("1"))).unwrap();
+        transcripts.record(TranscriptEntry::new("2", inbound("2"))).unwrap();
+        transcripts.record(TranscriptEntry::new("1", transition.clone())).unwrap();
+
+        let transcript = transcripts.chat_transcript("1").unwrap();
+
+        assert_eq!(vec![inbound("1"), transition], transcript.into_iter().map(|entry| entry.event).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn transcripts_sqlite_should_keep_users_and_skip_malformed_entries() {
+        let mut transcripts = TranscriptsSqlite::new(":memory:").unwrap();
+        transcripts.record(TranscriptEntry::new("-100123", inbound("oi")).with_user_id("222000")).unwrap();
+        transcripts.connection.execute(
+            "INSERT INTO transcript (chat_id, recorded_on, event) VALUES ('-100123', ?1, 'not json')",
+            params![Utc::now().to_rfc3339()],
+        ).unwrap();
+
+        let transcript = transcripts.chat_transcript("-100123").unwrap();
+
+        assert_eq!(1, transcript.len());
+        assert_eq!(Some("222000".to_string()), transcript[0].user_id);
+    }
+
+    #[test]
+    fn redacted_transcripts_should_hide_phone_numbers() {
+        let mut transcripts = RedactedTranscripts::new(Box::new(TranscriptsInMemory::new()));
+        transcripts.record(TranscriptEntry::new("1", inbound("+55 41 99999-1234"))).unwrap();
+        transcripts.record(TranscriptEntry::new("1", TranscriptEvent::Transition {
+            from: "register-phone".to_string(),
+            to: "register-finished".to_string(),
+            action: "(41) 3333.1234".to_string(),
+            outputs: vec!["Nome: Ana\nTelefone: 41999991234".to_string()],
+        })).unwrap();
+        transcripts.record(TranscriptEntry::new("1", inbound("1"))).unwrap();
+
+        let events: Vec<TranscriptEvent> = transcripts.chat_transcript("1").unwrap().into_iter().map(|entry| entry.event).collect();
+
+        assert_eq!(inbound("[phone]"), events[0]);
+        assert_eq!(TranscriptEvent::Transition {
+            from: "register-phone".to_string(),
+            to: "register-finished".to_string(),
+            action: "[phone]".to_string(),
+            outputs: vec!["Nome: Ana\nTelefone: [phone]".to_string()],
+        }, events[1]);
+        assert_eq!(inbound("1"), events[2]);
+    }
+}
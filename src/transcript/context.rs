@@ -0,0 +1,38 @@
+use std::{rc::Rc, cell::RefCell};
+
+use crate::config::{Config, ConfigError, TranscriptConfig};
+
+use super::*;
+
+pub struct TranscriptContext {
+    pub transcripts: Option<Rc<RefCell<dyn Transcripts>>>,
+}
+impl TranscriptContext {
+    pub fn build(config: &Config) -> Result<Self, ConfigError> {
+        let transcripts = Self::build_transcripts(&config.transcript)?;
+
+        Ok(Self {
+            transcripts,
+        })
+    }
+
+    /// Transcripts are only kept when a file is configured, and phones are redacted unless
+    /// `redact_phones` is off.
+    fn build_transcripts(config: &TranscriptConfig) -> Result<Option<Rc<RefCell<dyn Transcripts>>>, ConfigError> {
+        if let Some(path) = &config.sqlite_file {
+            let transcripts = TranscriptsSqlite::new(path).map_err(|error| ConfigError::storage(path, error))?;
+            Ok(Some(Self::shared(transcripts, config.redact_phones)))
+        } else {
+            Ok(config.jsonl_file.as_ref()
+                .map(|path| Self::shared(TranscriptsJsonLines::new(path), config.redact_phones)))
+        }
+    }
+
+    fn shared<T: Transcripts + 'static>(transcripts: T, redact_phones: bool) -> Rc<RefCell<dyn Transcripts>> {
+        if redact_phones {
+            Rc::new(RefCell::new(RedactedTranscripts::new(Box::new(transcripts))))
+        } else {
+            Rc::new(RefCell::new(transcripts))
+        }
+    }
+}